@@ -1,9 +1,11 @@
+#[allow(clippy::single_component_path_imports)]
 use actix_web;
 use actix_session::{
     Session
 };
 use crate::UserKey;
 
+#[allow(clippy::manual_unwrap_or_default)]
 pub fn get_auth_user(sess: &Session) -> Option<UserKey>
 {
     match sess.get("auth-user")
@@ -1,412 +1,4362 @@
 use std::cmp::Eq;
+use std::convert::{ TryFrom, TryInto, };
 use std::hash::Hash;
-use std::fmt::Display;
+use std::marker::PhantomData;
+use std::fmt::{ Debug, Display, };
+use std::str::FromStr;
 use std::error::Error;
-use std::io::Write;
-use std::path::PathBuf;
-use std::fs::{ self, File, };
-use std::collections::{ HashMap, HashSet, };
+use std::ffi::OsString;
+use std::io::{ self, Write, };
+use std::path::{ Path, PathBuf, };
+use std::fs::{ self, File, OpenOptions, TryLockError, };
+use std::collections::{ BTreeMap, HashMap, HashSet, };
+use std::sync::{
+    Arc, Mutex, MutexGuard, PoisonError,
+    RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+use std::sync::atomic::{ AtomicU64, Ordering, };
+use std::time::Instant;
 
-use bincode;
 #[allow(unused_imports)]
 use serde::{ Deserialize, Serialize };
 use serde::de::DeserializeOwned;
+use sha2::{ Digest, Sha256, };
+
+mod append_log;
+mod batch;
+mod blob;
+mod codec;
+mod compress;
+mod crypt;
+mod index;
+#[cfg(test)]
+#[macro_use]
+mod conformance;
+mod record;
+mod scrub;
+mod sqlite;
+mod stats;
+pub use append_log::LogTable;
+pub use batch::{ Batch, BatchOp, };
+pub use blob::{ BlobId, BlobStore, };
+pub use codec::{ Bincode, Cbor, Codec, CodecError, Json, MessagePack, };
+pub use compress::Compression;
+pub use crypt::{ KeyId, Keyring, };
+pub use index::{ Index, IndexedTable, };
+use crypt::Unsealed;
+pub use record::Migrations;
+#[allow(unused_imports)]
+pub use scrub::{ Damage, Finding, Remedy, ScrubReport, };
+pub use sqlite::SqliteTable;
+pub use stats::TableStats;
+use stats::Counters;
+
+/// Why a `Table` operation failed.
+#[derive(Debug)]
+pub enum DatabaseError
+{
+    /// There is no record for the key
+    NotFound,
+    Io(io::Error),
+    /// A record (or the journal) is on disk, but can't be decoded
+    Corrupt(CodecError),
+    /// A value couldn't be encoded for storage
+    Encoding(CodecError),
+    Sqlite(rusqlite::Error),
+    /// The store's manifest says it's encoded with `store`, not `opened`
+    WrongCodec { store: String, opened: &'static str },
+    /// A record (or the journal) was written with a schema version there's
+    /// no way to upgrade to the `current` one from
+    UnsupportedSchema { found: u32, current: u32 },
+    /// The store is open for writing elsewhere, by the process `pid` if
+    /// it could be told, as the lock file at `path` says
+    Locked { path: PathBuf, pid: Option<u32> },
+    /// The store was opened read-only
+    ReadOnly,
+    /// A record (or the store) is encrypted, with the key `id` if it's
+    /// known, but that key wasn't given
+    MissingKey { id: Option<KeyId> },
+    /// An `IndexedTable` has no index of that name
+    NoSuchIndex(String),
+}
+
+impl Display for DatabaseError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            DatabaseError::NotFound =>
+                write!(f, "Record not found"),
+            DatabaseError::Io(e) =>
+                write!(f, "I/O error: {}", e),
+            DatabaseError::Corrupt(e) =>
+                write!(f, "Corrupt record: {}", e),
+            DatabaseError::Encoding(e) =>
+                write!(f, "Unable to encode record: {}", e),
+            DatabaseError::Sqlite(e) =>
+                write!(f, "SQLite error: {}", e),
+            DatabaseError::WrongCodec { store, opened } =>
+                write!(f, "Store is encoded with {}, but was opened with {}", store, opened),
+            DatabaseError::UnsupportedSchema { found, current } =>
+                write!(f, "Written with schema version {}, which can't be upgraded to {}", found, current),
+            DatabaseError::Locked { path, pid: Some(pid) } =>
+                write!(f, "Store is already open for writing by process {} (see {:?})", pid, path),
+            DatabaseError::Locked { path, pid: None } =>
+                write!(f, "Store is already open for writing by another process (see {:?})", path),
+            DatabaseError::ReadOnly =>
+                write!(f, "Store is open read-only"),
+            DatabaseError::MissingKey { id: Some(id) } =>
+                write!(f, "Encrypted with key {}, which wasn't given", id),
+            DatabaseError::MissingKey { id: None } =>
+                write!(f, "Store is encrypted, but no keys were given"),
+            DatabaseError::NoSuchIndex(name) =>
+                write!(f, "No index named {:?}", name),
+        }
+    }
+}
+
+impl Error for DatabaseError
+{
+    fn source(&self) -> Option<&(dyn Error + 'static)>
+    {
+        match self
+        {
+            DatabaseError::NotFound |
+            DatabaseError::WrongCodec { .. } |
+            DatabaseError::UnsupportedSchema { .. } |
+            DatabaseError::Locked { .. } |
+            DatabaseError::ReadOnly |
+            DatabaseError::MissingKey { .. } |
+            DatabaseError::NoSuchIndex(_) =>
+                None,
+            DatabaseError::Io(e) =>
+                Some(e),
+            DatabaseError::Corrupt(e) | DatabaseError::Encoding(e) =>
+                Some(e.as_ref()),
+            DatabaseError::Sqlite(e) =>
+                Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for DatabaseError
+{
+    fn from(e: io::Error) -> Self
+    {
+        DatabaseError::Io(e)
+    }
+}
+
+impl DatabaseError
+{
+    /// For an error reading a record: a missing file is a missing record.
+    fn from_read(e: io::Error) -> Self
+    {
+        match e.kind()
+        {
+            io::ErrorKind::NotFound =>
+                DatabaseError::NotFound,
+            _ =>
+                DatabaseError::Io(e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DatabaseError
+{
+    fn from(e: rusqlite::Error) -> Self
+    {
+        DatabaseError::Sqlite(e)
+    }
+}
 
 /// This is the primary API for the module.
+///
+/// Tables are shared between threads, so every method takes `&self`, and
+/// values are handed out behind an `Arc` rather than borrowed.
+///
+/// The `try_` methods report why an operation failed. The others treat any
+/// failure like a missing record.
 pub trait Table<K,V>
 {
-    fn set(&mut self, k: K, v: V) -> Option<V>;
-    fn get(&mut self, k: &K) -> Option<&V>;
-    fn contains_key(&self, k: &K) -> bool;
-    fn remove(&mut self, k: &K) -> Option<V>;
+    fn try_set(&self, k: K, v: V) -> Result<Option<Arc<V>>, DatabaseError>;
+    /// `Ok(None)` only when there is no record for `k`.
+    fn try_get(&self, k: &K) -> Result<Option<Arc<V>>, DatabaseError>;
+    fn try_contains_key(&self, k: &K) -> Result<bool, DatabaseError>;
+    fn try_remove(&self, k: &K) -> Result<Option<Arc<V>>, DatabaseError>;
+
+    fn set(&self, k: K, v: V) -> Option<Arc<V>>
+    {
+        self.try_set(k, v).unwrap_or(None)
+    }
+
+    fn get(&self, k: &K) -> Option<Arc<V>>
+    {
+        self.try_get(k).unwrap_or(None)
+    }
+
+    #[allow(dead_code)]
+    fn contains_key(&self, k: &K) -> bool
+    {
+        self.try_contains_key(k).unwrap_or(false)
+    }
+
+    #[allow(dead_code)]
+    fn remove(&self, k: &K) -> Option<Arc<V>>
+    {
+        self.try_remove(k).unwrap_or(None)
+    }
+
+    /// An empty batch, to stage changes in for `commit`.
+    fn begin(&self) -> Batch<K,V>
+    {
+        Batch::new()
+    }
+
+    /// Make every change staged in `batch`, in order, or, if that fails,
+    /// none of them.
+    fn commit(&self, batch: Batch<K,V>) -> Result<(), DatabaseError>;
+
+    /// Every key in the table, in no particular order.
+    fn keys(&self) -> Vec<K>
+        where K: Clone;
+
+    /// Every record in the table, in no particular order.
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, Arc<V>)> + '_>
+        where K: Clone;
+
+    /// The keys whose string form starts with `prefix`.
+    #[allow(dead_code)]
+    fn scan_prefix(&self, prefix: &str) -> Vec<K>
+        where K: Clone + Display
+    {
+        self.keys()
+            .into_iter()
+            .filter(|k| k.to_string().starts_with(prefix))
+            .collect()
+    }
+
+    /// Write any changes held in memory to storage, returning how many were
+    /// written. Tables which write straight through have nothing to do.
+    fn persist(&self) -> Result<usize, PersistError<K>>
+    {
+        Ok(0)
+    }
+
+    /// Check storage for damage, as `DiskCache::scrub` does. Tables with
+    /// nothing of their own to check report nothing.
+    fn scrub(&self, _quarantine: bool) -> io::Result<ScrubReport>
+    {
+        Ok(ScrubReport::default())
+    }
+
+    /// Re-encrypt what's stored under the current key, as
+    /// `DiskCache::reencrypt` does. Tables which don't encrypt have nothing
+    /// to do.
+    fn reencrypt(&self) -> Result<(usize, usize), DatabaseError>
+    {
+        Ok((0, 0))
+    }
+
+    /// What the table has done since it was opened, and what it holds in
+    /// memory. Tables which don't count anything report nothing.
+    fn stats(&self) -> TableStats
+    {
+        TableStats::default()
+    }
+}
+
+/// So the backend can be chosen at runtime.
+impl<K,V,T> Table<K,V> for Box<T>
+    where T: Table<K,V> + ?Sized
+{
+    fn try_set(&self, k: K, v: V) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        (**self).try_set(k, v)
+    }
+
+    fn try_get(&self, k: &K) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        (**self).try_get(k)
+    }
+
+    fn try_contains_key(&self, k: &K) -> Result<bool, DatabaseError>
+    {
+        (**self).try_contains_key(k)
+    }
+
+    fn try_remove(&self, k: &K) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        (**self).try_remove(k)
+    }
+
+    fn set(&self, k: K, v: V) -> Option<Arc<V>>
+    {
+        (**self).set(k, v)
+    }
+
+    fn get(&self, k: &K) -> Option<Arc<V>>
+    {
+        (**self).get(k)
+    }
+
+    fn contains_key(&self, k: &K) -> bool
+    {
+        (**self).contains_key(k)
+    }
+
+    fn remove(&self, k: &K) -> Option<Arc<V>>
+    {
+        (**self).remove(k)
+    }
+
+    fn begin(&self) -> Batch<K,V>
+    {
+        (**self).begin()
+    }
+
+    fn commit(&self, batch: Batch<K,V>) -> Result<(), DatabaseError>
+    {
+        (**self).commit(batch)
+    }
+
+    fn keys(&self) -> Vec<K>
+        where K: Clone
+    {
+        (**self).keys()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, Arc<V>)> + '_>
+        where K: Clone
+    {
+        (**self).iter()
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Vec<K>
+        where K: Clone + Display
+    {
+        (**self).scan_prefix(prefix)
+    }
+
+    fn persist(&self) -> Result<usize, PersistError<K>>
+    {
+        (**self).persist()
+    }
+
+    fn scrub(&self, quarantine: bool) -> io::Result<ScrubReport>
+    {
+        (**self).scrub(quarantine)
+    }
+
+    fn reencrypt(&self) -> Result<(usize, usize), DatabaseError>
+    {
+        (**self).reencrypt()
+    }
+
+    fn stats(&self) -> TableStats
+    {
+        (**self).stats()
+    }
+}
+
+/// Take a lock even if another thread panicked while holding it. None of
+/// the state behind these locks is left inconsistent part way through an
+/// update, so there is nothing to recover.
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T>
+{
+    lock.read().unwrap_or_else(PoisonError::into_inner)
 }
 
-/// A Hash(map)-Backed-Table with no persistant storage
-pub struct MemCache<K,V>(HashMap<K,V>);
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T>
+{
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lock<T>(lock: &Mutex<T>) -> MutexGuard<'_, T>
+{
+    lock.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A Hash(map)-Backed-Table with no persistant storage
+pub struct MemCache<K,V>
+{
+    records: RwLock<HashMap<K,Arc<V>>>,
+    counters: Counters,
+}
+
+impl<K,V> MemCache<K,V>
+    where K: Eq + Hash
+{
+    pub fn new() -> Self
+    {
+        MemCache
+        {
+            records: RwLock::new(HashMap::<K,Arc<V>>::new()),
+            counters: Counters::default(),
+        }
+    }
+}
+
+impl<K,V> Table<K,V> for MemCache<K,V>
+    where K: Eq + Hash
+{
+    fn try_set(&self, k: K, v: V) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        Ok(write(&self.records).insert(k, Arc::new(v)))
+    }
+
+    /// Every read is answered from memory, so a miss is a read of a key
+    /// which isn't there.
+    fn try_get(&self, k: &K) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        let v = read(&self.records).get(k).cloned();
+        match v
+        {
+            Some(_) =>
+                self.counters.hit(),
+            None =>
+                self.counters.miss(),
+        }
+
+        Ok(v)
+    }
+
+    fn try_contains_key(&self, k: &K) -> Result<bool, DatabaseError>
+    {
+        Ok(read(&self.records).contains_key(k))
+    }
+
+    fn try_remove(&self, k: &K) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        Ok(write(&self.records).remove(k))
+    }
+
+    /// Under one lock, so no reader sees part of the batch.
+    fn commit(&self, batch: Batch<K,V>) -> Result<(), DatabaseError>
+    {
+        let mut records = write(&self.records);
+        for op in batch.into_ops()
+        {
+            match op
+            {
+                BatchOp::Set(k, v) =>
+                {
+                    records.insert(k, Arc::new(v));
+                },
+                BatchOp::Remove(k) =>
+                {
+                    records.remove(&k);
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn keys(&self) -> Vec<K>
+        where K: Clone
+    {
+        read(&self.records).keys().cloned().collect()
+    }
+
+    /// Iterates over a snapshot of the table
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, Arc<V>)> + '_>
+        where K: Clone
+    {
+        let records: Vec<_> = read(&self.records)
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        Box::new(records.into_iter())
+    }
+
+    fn stats(&self) -> TableStats
+    {
+        TableStats
+        {
+            cached: read(&self.records).len() as u64,
+            ..self.counters.stats()
+        }
+    }
+}
+
+/// A Lazy-Populated Cache of items persisted by the system disk
+///
+/// Safe to share between threads. Reads of cached records only take a shared
+/// lock, and records are read from (and persisted to) disk without holding
+/// it, so a slow disk doesn't hold up requests for other records. (Only a
+/// read which keeps being overtaken by changes falls back to holding it.)
+///
+/// Records are encoded with `C`, which the store's manifest keeps track of.
+/// The journal, only ever read back by the store itself, is always bincode.
+pub struct DiskCache<K,V,C = Bincode>
+    where K: Eq + Hash + Display + DeserializeOwned + Serialize,
+          V: DeserializeOwned + Serialize
+{
+    base_path: PathBuf,
+    budget: Budget,
+    layout: Layout,
+    migrations: Migrations,
+    access: Access,
+    compression: Compression,
+    keyring: Option<Arc<Keyring>>,
+    /// Holds the store's lock file, when open for writing
+    _lock: Option<File>,
+    /// Lock order is `persisting`, then `journal`, then `state`
+    persisting: Mutex<()>,
+    journal: Mutex<Journal>,
+    state: RwLock<CacheState<K,V>>,
+    /// Bumped on every access, to order records by how recently they were used
+    clock: AtomicU64,
+    counters: Counters,
+    codec: PhantomData<fn() -> C>,
+}
+
+struct CacheState<K,V>
+{
+    cache: HashMap<K, Cached<V>>,
+    /// Keys changed since they were last persisted, each with the `seq` of
+    /// its latest change
+    disk_update_required: HashMap<K, u64>,
+    removed: HashSet<K>,
+    /// Bumped by every set and remove
+    seq: u64,
+    /// Serialized size of everything in `cache`
+    bytes: u64,
+    /// The keys in `cache`, by when they were last used, as far as it's
+    /// known. Reads only note the time in the record, so each is moved up
+    /// when it's found to have been used since.
+    lru: BTreeMap<u64, K>,
+    eviction_stats: EvictionStats,
+}
+
+/// How many times a `DiskCache` reads a record without holding its lock,
+/// before holding it to read the record.
+const READ_TRIES: usize = 3;
+
+struct Cached<V>
+{
+    value: Arc<V>,
+    size: u64,
+    last_used: AtomicU64,
+    /// Where it is in `CacheState::lru`
+    queued: u64,
+}
+
+/// How much a `DiskCache` may hold in memory before it starts evicting the
+/// least recently used records.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Budget
+{
+    #[default]
+    Unbounded,
+    #[allow(dead_code)]
+    Entries(usize),
+    /// Measured as the serialized size of the cached values
+    Bytes(u64),
+}
+
+/// Where a `DiskCache` keeps each record under its `base_path`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Layout
+{
+    /// Directly under `base_path`, named by the encoded key
+    #[default]
+    Flat,
+    /// Two directories down, named from a hash of the encoded key (as in
+    /// `ab/cd/<encoded-key>`), so that no directory holds too many files
+    Sharded,
+}
+
+/// Whether a `DiskCache` may change its store.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Access
+{
+    /// For reading and writing, by this `DiskCache` alone. Taken with an
+    /// advisory lock, so that no other process opening the store this way
+    /// overwrites its records.
+    #[default]
+    Exclusive,
+    /// For reading only, without the lock, so alongside whatever has it
+    /// open for writing. Reads see the records as last persisted, and as
+    /// journaled when opened. Changes fail with `DatabaseError::ReadOnly`.
+    ReadOnly,
+}
+
+/// How a `DiskCache` is opened. The default is an unbounded, flat store of
+/// values which have never changed schema, open for writing.
+#[derive(Clone, Debug, Default)]
+pub struct DiskCacheOptions
+{
+    pub budget: Budget,
+    pub layout: Layout,
+    /// Upgrades records written with older schemas as they're read
+    pub migrations: Migrations,
+    pub access: Access,
+    /// How records are compressed as they're written. The journal isn't.
+    pub compression: Compression,
+    /// Encrypts the records and journal, if given. Once a store has been
+    /// opened with keys, it can't be opened without them.
+    pub keyring: Option<Arc<Keyring>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct EvictionStats
+{
+    /// Records dropped from memory to stay within budget
+    pub evictions: u64,
+    /// Serialized size of the evicted records
+    pub bytes_evicted: u64,
+    /// Evicted records which were dirty, and had to be persisted first
+    pub dirty_writes: u64,
+}
+
+impl<K,V> DiskCache<K,V>
+    where K: Clone + Eq + Hash + Display + DeserializeOwned + Serialize,
+          V: DeserializeOwned + Serialize
+{
+    /// Opens the store at `base_path`, creating it if need be, and replaying
+    /// any changes journaled but not yet persisted by a previous run. Fails
+    /// if the store isn't encoded with bincode.
+    #[allow(dead_code)]
+    pub fn new(base_path: PathBuf) -> Result<Self, DatabaseError>
+    {
+        Self::with_options(base_path, DiskCacheOptions::default())
+    }
+
+    /// As `new`, but holding no more than `budget` in memory.
+    #[allow(dead_code)]
+    pub fn with_budget(base_path: PathBuf, budget: Budget) -> Result<Self, DatabaseError>
+    {
+        Self::with_options(base_path, DiskCacheOptions { budget, ..DiskCacheOptions::default() })
+    }
+
+    /// As `new`, but set up as `options` say. A store must always be opened
+    /// with the same `layout`; see `migrate_to_sharded` for changing it.
+    pub fn with_options(base_path: PathBuf, options: DiskCacheOptions) -> Result<Self, DatabaseError>
+    {
+        Self::with_codec(base_path, options, Bincode)
+    }
+}
+
+impl<K,V,C> DiskCache<K,V,C>
+    where K: Clone + Eq + Hash + Display + DeserializeOwned + Serialize,
+          V: DeserializeOwned + Serialize,
+          C: Codec
+{
+    /// As `with_options`, but with records encoded by `codec`. Fails if the
+    /// store is encoded with some other codec.
+    pub fn with_codec(base_path: PathBuf, options: DiskCacheOptions, _codec: C) -> Result<Self, DatabaseError>
+    {
+        let store_lock = match options.access
+        {
+            Access::Exclusive =>
+            {
+                fs::create_dir_all(&base_path)?;
+                Some(lock_store(&base_path)?)
+            },
+            Access::ReadOnly =>
+                None,
+        };
+
+        let journal = Journal::new(&base_path, options.keyring.clone());
+        let journaled = fs::metadata(&journal.path).is_ok_and(|m| m.len() > 0);
+        codec::check_manifest::<C, _>(&base_path, &options, journaled, || {
+            Ok(journal.path.exists() || !records_in(&base_path, options.layout)?.is_empty())
+        })?;
+
+        // Only its holder may cut a torn entry off the journal, as a reader's
+        // might just be being written. Opened without them, the entries
+        // would be lost by the next persist.
+        let entries = journal.replay(options.access == Access::Exclusive)?;
+        let dc = DiskCache
+        {
+            base_path,
+            budget: options.budget,
+            layout: options.layout,
+            migrations: options.migrations,
+            access: options.access,
+            compression: options.compression,
+            keyring: options.keyring,
+            _lock: store_lock,
+            persisting: Mutex::new(()),
+            journal: Mutex::new(journal),
+            state: RwLock::new(CacheState {
+                cache: HashMap::new(),
+                disk_update_required: HashMap::new(),
+                removed: HashSet::new(),
+                seq: 0,
+                bytes: 0,
+                lru: BTreeMap::new(),
+                eviction_stats: EvictionStats::default(),
+            }),
+            clock: AtomicU64::new(0),
+            counters: Counters::default(),
+            codec: PhantomData,
+        };
+
+        let mut state = write(&dc.state);
+        for entry in entries
+        {
+            dc.apply(&mut state, entry);
+        }
+        drop(state);
+        dc.evict(None);
+
+        Ok(dc)
+    }
+
+    #[allow(dead_code)]
+    pub fn eviction_stats(&self) -> EvictionStats
+    {
+        read(&self.state).eviction_stats
+    }
+
+    /// How well the cache is working: how many reads it answered, how much
+    /// was read and written to answer them, and how much is held in memory.
+    pub fn stats(&self) -> TableStats
+    {
+        let state = read(&self.state);
+        TableStats
+        {
+            dirty: state.disk_update_required.len() as u64,
+            cached: state.cache.len() as u64,
+            cached_bytes: state.bytes,
+            evictions: state.eviction_stats,
+            ..self.counters.stats()
+        }
+    }
+
+    /// Write the changes made since the last persist to disk. Records which
+    /// can't be written stay dirty (and journaled), to be retried by the next
+    /// persist. Returns how many dirty keys were settled.
+    pub fn persist(&self) -> Result<usize, PersistError<K>>
+    {
+        if self.access == Access::ReadOnly
+        {
+            // Only what was journaled is dirty, and that's for its writer
+            return Ok(0);
+        }
+        let _persisting = lock(&self.persisting);
+        let started = Instant::now();
+        let result = self.persist_dirty();
+        self.counters.persisted(started.elapsed());
+
+        result
+    }
+
+    /// `persist`, once the persisting lock is held.
+    fn persist_dirty(&self) -> Result<usize, PersistError<K>>
+    {
+        // Snapshot the changes, so they can be written without holding the lock
+        let dirty: Vec<(K, u64, Option<Arc<V>>)> = {
+            let state = read(&self.state);
+            state.disk_update_required
+                .iter()
+                .map(|(k, seq)| (k.clone(), *seq, state.cache.get(k).map(|c| c.value.clone())))
+                .collect()
+        };
+
+        let mut settled = Vec::new();
+        let mut failed = Vec::new();
+        for (k, seq, v) in dirty
+        {
+            match self.persist_one(&k, v.as_deref())
+            {
+                Ok(()) =>
+                    settled.push((k, seq)),
+                Err(e) =>
+                    failed.push((k, e)),
+            }
+        }
+
+        let mut journal = lock(&self.journal);
+        let mut state = write(&self.state);
+        let state = &mut *state;
+
+        let mut persisted = 0;
+        for (k, seq) in settled
+        {
+            // Unless it was changed again while being written
+            if state.disk_update_required.get(&k) == Some(&seq)
+            {
+                state.disk_update_required.remove(&k);
+                state.removed.remove(&k);
+                persisted += 1;
+            }
+        }
+
+        // Only what's still dirty needs to be journaled
+        let journal = journal.rewrite(&Self::pending(state)).err();
+
+        if failed.is_empty() && journal.is_none()
+        {
+            Ok(persisted)
+        }
+        else
+        {
+            Err(PersistError { failed, journal })
+        }
+    }
+
+    /// Re-encrypt every record (and the journal) not encrypted under the
+    /// current key, so that the keys before it can be dropped. Records
+    /// written before the store was encrypted are encrypted too. Records
+    /// which can't be read are reported and left as they are. Returns how
+    /// many records were re-encrypted, and how many couldn't be.
+    pub fn reencrypt(&self) -> Result<(usize, usize), DatabaseError>
+    {
+        self.writable()?;
+        let keyring = match &self.keyring
+        {
+            Some(keyring) =>
+                keyring,
+            None =>
+                return Ok((0, 0)),
+        };
+
+        let mut reencrypted = 0;
+        let mut failed = 0;
+        for (key, path) in records_in(&self.base_path, self.layout)?
+        {
+            // A record at a time, so that persists aren't held up for long,
+            // but none of them overwrite a record as it's re-encrypted
+            let _persisting = lock(&self.persisting);
+            let data = match fs::read(&path)
+            {
+                Ok(data) =>
+                    data,
+                Err(e) if e.kind() == io::ErrorKind::NotFound =>
+                    continue,
+                Err(e) =>
+                    return Err(DatabaseError::Io(e)),
+            };
+            if !record::needs_sealing(&data, keyring)
+            {
+                continue;
+            }
+
+            let resealed = record::unpack(&key, &data, Some(keyring))
+                .and_then(|(schema, body)| record::pack(&key, schema, &body, self.compression, Some(keyring)));
+            match resealed
+            {
+                Ok(data) =>
+                {
+                    write_atomic(&path, &data)?;
+                    reencrypted += 1;
+                },
+                Err(e) =>
+                {
+                    eprintln!("Unable to re-encrypt {:?}: {}", key, e);
+                    failed += 1;
+                },
+            }
+        }
+
+        // Its entries are sealed as they're written, so rewriting it is enough
+        let _persisting = lock(&self.persisting);
+        let mut journal = lock(&self.journal);
+        let state = read(&self.state);
+        journal.rewrite(&Self::pending(&state))?;
+
+        Ok((reencrypted, failed))
+    }
+
+    /// The changes in `state` not yet persisted, as they'd be journaled.
+    fn pending(state: &CacheState<K,V>) -> Vec<JournalEntry<&K,&V>>
+    {
+        state.disk_update_required
+            .keys()
+            .map(|k| match state.cache.get(k)
+            {
+                Some(c) =>
+                    JournalEntry::Set(k, &*c.value),
+                None =>
+                    JournalEntry::Remove(k),
+            })
+            .collect()
+    }
+
+    fn tick(&self) -> u64
+    {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Make a change in memory. Evicting to make room for it is left to the
+    /// caller, once the state lock is released.
+    fn apply(&self, state: &mut CacheState<K,V>, entry: JournalEntry<K,V>) -> Option<Arc<V>>
+    {
+        state.seq += 1;
+
+        match entry
+        {
+            JournalEntry::Set(k, v) =>
+            {
+                state.removed.remove(&k);
+                state.disk_update_required.insert(k.clone(), state.seq);
+
+                self.cache_insert(state, k, Arc::new(v))
+            },
+            JournalEntry::Remove(k) =>
+            {
+                let old = Self::cache_remove(state, &k);
+                state.removed.insert(k.clone());
+                state.disk_update_required.insert(k, state.seq);
+                old
+            },
+            // Replay takes these out, leaving the entries they mark
+            JournalEntry::Batch(_) =>
+                None,
+        }
+    }
+
+    fn writable(&self) -> Result<(), DatabaseError>
+    {
+        match self.access
+        {
+            Access::Exclusive =>
+                Ok(()),
+            Access::ReadOnly =>
+                Err(DatabaseError::ReadOnly),
+        }
+    }
+
+    /// Have the next persist write `k` as it is in memory.
+    fn mark_dirty(state: &mut CacheState<K,V>, k: K)
+    {
+        state.seq += 1;
+        let seq = state.seq;
+        state.disk_update_required.insert(k, seq);
+    }
+
+    /// `k` as it is in memory: Some(None) if it's known to be removed, and
+    /// None if it has to be read from disk.
+    fn cached(&self, state: &CacheState<K,V>, k: &K) -> Option<Option<Arc<V>>>
+    {
+        if let Some(c) = state.cache.get(k)
+        {
+            self.counters.hit();
+            c.last_used.store(self.tick(), Ordering::Relaxed);
+            Some(Some(c.value.clone()))
+        }
+        else if state.removed.contains(k)
+        {
+            self.counters.hit();
+            Some(None)
+        }
+        else
+        {
+            None
+        }
+    }
+
+    /// Cache what `get_from_disk` read for `k`.
+    fn load(&self, state: &mut CacheState<K,V>, k: &K, (v, upgraded): (Box<V>, bool)) -> Arc<V>
+    {
+        let v = Arc::new(*v);
+        if upgraded && self.access == Access::Exclusive
+        {
+            // To be written back in the current schema. Until then, it's
+            // upgraded again if read after a restart.
+            Self::mark_dirty(state, k.clone());
+        }
+        self.cache_insert(state, k.clone(), v.clone());
+
+        v
+    }
+
+    fn cache_insert(&self, state: &mut CacheState<K,V>, k: K, v: Arc<V>) -> Option<Arc<V>>
+    {
+        let tick = self.tick();
+        let queued = match state.cache.get(&k)
+        {
+            // Moved up when it's next considered for eviction
+            Some(c) =>
+                c.queued,
+            None =>
+            {
+                state.lru.insert(tick, k.clone());
+                tick
+            },
+        };
+        let cached = Cached
+        {
+            size: serialized_size(&*v),
+            value: v,
+            last_used: AtomicU64::new(tick),
+            queued,
+        };
+
+        state.bytes += cached.size;
+        let old = state.cache.insert(k, cached)?;
+        state.bytes -= old.size;
+
+        Some(old.value)
+    }
+
+    fn cache_remove(state: &mut CacheState<K,V>, k: &K) -> Option<Arc<V>>
+    {
+        let old = state.cache.remove(k)?;
+        state.lru.remove(&old.queued);
+        state.bytes -= old.size;
+
+        Some(old.value)
+    }
+
+    fn over_budget(&self, state: &CacheState<K,V>) -> bool
+    {
+        match self.budget
+        {
+            Budget::Unbounded =>
+                false,
+            Budget::Entries(max) =>
+                state.cache.len() > max,
+            Budget::Bytes(max) =>
+                state.bytes > max,
+        }
+    }
+
+    /// Drop least recently used records until back within budget. `keep`
+    /// was just used, and is never evicted. Dirty records are persisted
+    /// first, without holding the state lock, and only dropped if they
+    /// weren't changed again while being written.
+    fn evict(&self, keep: Option<&K>)
+    {
+        let mut stuck = HashSet::new();
+
+        loop
+        {
+            let (victim, seq, v) = match self.evict_clean(&mut write(&self.state), keep, &mut stuck)
+            {
+                Some(dirty) =>
+                    dirty,
+                None =>
+                    return,
+            };
+
+            // A persist writes its snapshot without holding the state lock,
+            // and mustn't overwrite this with an older value.
+            let persisting = self.persisting.try_lock();
+            let written = match persisting
+            {
+                Ok(_) =>
+                    self.persist_one(&victim, Some(&*v)),
+                Err(_) =>
+                    Err(DatabaseError::Io(io::Error::new(io::ErrorKind::WouldBlock, "persist in progress"))),
+            };
+
+            if let Err(e) = written
+            {
+                // Better over budget than losing the record
+                eprintln!("Unable to persist {} for eviction: {}", victim, e);
+                stuck.insert(victim);
+                continue;
+            }
+
+            let mut state = write(&self.state);
+            if state.disk_update_required.get(&victim) == Some(&seq)
+            {
+                state.disk_update_required.remove(&victim);
+                state.eviction_stats.dirty_writes += 1;
+                Self::drop_evicted(&mut state, &victim);
+            }
+        }
+    }
+
+    /// Drop least recently used records which are persisted as they are,
+    /// until back within budget, or until the next to go is dirty, which is
+    /// returned (with the `seq` of its latest change) to be persisted first.
+    fn evict_clean(&self, state: &mut CacheState<K,V>, keep: Option<&K>, stuck: &mut HashSet<K>)
+        -> Option<(K, u64, Arc<V>)>
+    {
+        while self.over_budget(state)
+        {
+            let (queued, victim) = state.lru
+                .iter()
+                .find(|(_, k)| Some(*k) != keep && !stuck.contains(*k))
+                .map(|(&queued, k)| (queued, k.clone()))?;
+
+            // Used since it was queued, so not necessarily the least recent
+            let c = state.cache.get_mut(&victim)?;
+            let used = c.last_used.load(Ordering::Relaxed);
+            if used != queued
+            {
+                c.queued = used;
+                state.lru.remove(&queued);
+                state.lru.insert(used, victim);
+                continue;
+            }
+
+            match state.disk_update_required.get(&victim)
+            {
+                Some(_) if self.access == Access::ReadOnly =>
+                {
+                    // Journaled by the store's writer, who'll persist it
+                    stuck.insert(victim);
+                },
+                Some(&seq) =>
+                {
+                    let v = state.cache[&victim].value.clone();
+                    return Some((victim, seq, v));
+                },
+                None =>
+                    Self::drop_evicted(state, &victim),
+            }
+        }
+
+        None
+    }
+
+    fn drop_evicted(state: &mut CacheState<K,V>, k: &K)
+    {
+        if let Some(c) = state.cache.remove(k)
+        {
+            state.lru.remove(&c.queued);
+            state.bytes -= c.size;
+            state.eviction_stats.evictions += 1;
+            state.eviction_stats.bytes_evicted += c.size;
+        }
+    }
+
+    /// Write `v` as the record for `k`, or remove the record if `v` is None.
+    fn persist_one(&self, k: &K, v: Option<&V>) -> Result<(), DatabaseError>
+    {
+        match v
+        {
+            Some(v) =>
+            {
+                // Update record
+                let body = C::encode(v).map_err(DatabaseError::Encoding)?;
+                let fdata = record::pack(&k.to_string(), self.migrations.current(), &body, self.compression, self.keyring.as_deref())?;
+                let path = self.make_path(k);
+                if self.layout == Layout::Sharded
+                {
+                    create_shard(&path)?;
+                }
+                write_atomic(&path, &fdata)?;
+                self.counters.wrote(fdata.len());
+            },
+            None if self.is_on_disk(k) =>
+                // Remove record
+                fs::remove_file(self.make_path(k))?,
+            None =>
+            {
+                // A double remove perhaps?
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Keys of the records in `base_path`, as of the last persist
+    fn keys_on_disk(&self) -> io::Result<Vec<K>>
+        where K: FromStr
+    {
+        let keys = records_in(&self.base_path, self.layout)?
+            .into_iter()
+            .filter_map(|(key, _)| key.parse().ok())
+            .collect();
+
+        Ok(keys)
+    }
+
+    fn make_path(&self, k: &K) -> PathBuf
+    {
+        record_path(&self.base_path, self.layout, &encode_key(&k.to_string()))
+    }
+
+    fn is_on_disk(&self, k: &K) -> bool
+    {
+        self.make_path(k).exists()
+    }
+
+    fn try_is_on_disk(&self, k: &K) -> Result<bool, DatabaseError>
+    {
+        match fs::metadata(self.make_path(k))
+        {
+            Ok(_) =>
+                Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound =>
+                Ok(false),
+            Err(e) =>
+                Err(DatabaseError::Io(e)),
+        }
+    }
+
+    /// The record for `k`, and whether it had to be upgraded from an older
+    /// schema to be read.
+    fn get_from_disk(&self, k: &K) -> Result<(Box<V>, bool), DatabaseError>
+    {
+        let path = self.make_path(k);
+
+        // Decoding from a slice (rather than the file) bounds allocations
+        // by the record's size, however corrupt its length fields are
+        let fdata = fs::read(path).map_err(DatabaseError::from_read)?;
+        self.counters.loaded(fdata.len());
+        let key = k.to_string();
+        let (schema, body) = record::unpack(&key, &fdata, self.keyring.as_deref())?;
+        let data: V = if schema == self.migrations.current()
+        {
+            C::decode(&body).map_err(DatabaseError::Corrupt)?
+        }
+        else
+        {
+            let body = self.migrations.upgrade(&key, schema, &body)?;
+            C::decode(&body).map_err(DatabaseError::Corrupt)?
+        };
+
+        Ok((Box::new(data), schema != self.migrations.current()))
+    }
+}
+
+/// A change to a `DiskCache`, as recorded in its journal.
+#[derive(Deserialize, Serialize)]
+enum JournalEntry<K,V>
+{
+    Set(K, V),
+    Remove(K),
+    /// The next so many entries were committed together, and are only
+    /// replayed if they're all there
+    Batch(u64),
+}
+
+impl<K,V> JournalEntry<K,V>
+{
+    /// How `op` is journaled.
+    fn staged(op: &BatchOp<K,V>) -> JournalEntry<&K,&V>
+    {
+        match op
+        {
+            BatchOp::Set(k, v) =>
+                JournalEntry::Set(k, v),
+            BatchOp::Remove(k) =>
+                JournalEntry::Remove(k),
+        }
+    }
+}
+
+impl<K,V> From<BatchOp<K,V>> for JournalEntry<K,V>
+{
+    fn from(op: BatchOp<K,V>) -> Self
+    {
+        match op
+        {
+            BatchOp::Set(k, v) =>
+                JournalEntry::Set(k, v),
+            BatchOp::Remove(k) =>
+                JournalEntry::Remove(k),
+        }
+    }
+}
+
+/// A journal entry, and the length of its frame.
+type Framed<K,V> = (JournalEntry<K,V>, usize);
+
+/// Write-ahead log of the changes made to a `DiskCache` since it was last
+/// persisted. Entries are length-prefixed bincode (sealed, if there's a
+/// keyring), so a torn write at the tail is detected and dropped on replay.
+struct Journal
+{
+    path: PathBuf,
+    file: Option<File>,
+    keyring: Option<Arc<Keyring>>,
+}
+
+impl Journal
+{
+    const FILE_NAME: &'static str = ".journal";
+
+    fn new(base_path: &Path, keyring: Option<Arc<Keyring>>) -> Self
+    {
+        Journal
+        {
+            path: base_path.join(Self::FILE_NAME),
+            file: None,
+            keyring,
+        }
+    }
+
+    /// Read back every complete entry, discarding a partial one (or batch)
+    /// at the end (from the file too, if `repair`). Fails, discarding
+    /// nothing, if a complete entry can't be decoded, or was sealed with a
+    /// key that wasn't given.
+    fn replay<K,V>(&self, repair: bool) -> Result<Vec<JournalEntry<K,V>>, DatabaseError>
+        where K: DeserializeOwned,
+              V: DeserializeOwned
+    {
+        let data = match fs::read(&self.path)
+        {
+            Ok(data) =>
+                data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound =>
+                return Ok(Vec::new()),
+            Err(e) =>
+                return Err(DatabaseError::Io(e)),
+        };
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while let Some((entry, len)) = self.read_entry(&data[offset..])?
+        {
+            let n = match entry
+            {
+                JournalEntry::Batch(n) =>
+                    n,
+                entry =>
+                {
+                    entries.push(entry);
+                    offset += len;
+                    continue;
+                },
+            };
+
+            // A batch torn part way through is dropped whole
+            let mut batch = Vec::new();
+            let mut end = offset + len;
+            while (batch.len() as u64) < n
+            {
+                match self.read_entry(&data[end..])?
+                {
+                    Some((JournalEntry::Batch(_), _)) =>
+                        return Err(DatabaseError::Corrupt("Journal has a batch within a batch".into())),
+                    Some((entry, len)) =>
+                    {
+                        batch.push(entry);
+                        end += len;
+                    },
+                    None =>
+                        break,
+                }
+            }
+            if (batch.len() as u64) < n
+            {
+                break;
+            }
+
+            entries.extend(batch);
+            offset = end;
+        }
+
+        if offset < data.len() && repair
+        {
+            // Crashed mid-append. Cut the torn entry off so new entries
+            // aren't written after it.
+            OpenOptions::new()
+                .write(true)
+                .open(&self.path)?
+                .set_len(offset as u64)?;
+        }
+
+        Ok(entries)
+    }
+
+    /// The entry at the start of `data` and its length, or None if it's
+    /// torn (cut short).
+    fn read_entry<K,V>(&self, data: &[u8]) -> Result<Option<Framed<K,V>>, DatabaseError>
+        where K: DeserializeOwned,
+              V: DeserializeOwned
+    {
+        const HEADER: usize = std::mem::size_of::<u64>();
+
+        let body = data
+            .get(..HEADER)
+            .and_then(|len| usize::try_from(u64::from_le_bytes(len.try_into().unwrap())).ok())
+            .and_then(|len| data.get(HEADER..HEADER.checked_add(len)?));
+        let body = match body
+        {
+            Some(body) =>
+                body,
+            None =>
+                return Ok(None),
+        };
+
+        // Those from before there were keys aren't sealed
+        let opened = match self.keyring.as_ref().map(|keyring| keyring.open(Self::FILE_NAME.as_bytes(), body))
+        {
+            Some(Ok(opened)) =>
+                Some(opened),
+            Some(Err(Unsealed::MissingKey(id))) if Bincode::decode::<JournalEntry<K,V>>(body).is_err() =>
+                return Err(DatabaseError::MissingKey { id: Some(id) }),
+            _ =>
+                None,
+        };
+
+        let entry = Bincode::decode(opened.as_deref().unwrap_or(body)).map_err(DatabaseError::Corrupt)?;
+        Ok(Some((entry, HEADER + body.len())))
+    }
+
+    /// `entry` as it's written, sealed if there's a `keyring`.
+    fn frame<K,V>(entry: &JournalEntry<&K,&V>, keyring: Option<&Keyring>) -> Result<Vec<u8>, DatabaseError>
+        where K: Serialize,
+              V: Serialize
+    {
+        let mut body = Bincode::encode(entry).map_err(DatabaseError::Encoding)?;
+        if let Some(keyring) = keyring
+        {
+            body = keyring.seal(Self::FILE_NAME.as_bytes(), &body).map_err(DatabaseError::Encoding)?;
+        }
+        let mut data = Vec::with_capacity(body.len() + 8);
+        data.extend_from_slice(&(body.len() as u64).to_le_bytes());
+        data.extend_from_slice(&body);
+
+        Ok(data)
+    }
+
+    /// Durably record `entry`. Returns only once it has reached the disk.
+    fn append<K,V>(&mut self, entry: &JournalEntry<&K,&V>) -> Result<(), DatabaseError>
+        where K: Serialize,
+              V: Serialize
+    {
+        let data = Self::frame(entry, self.keyring.as_deref())?;
+        self.write(&data)
+    }
+
+    /// Durably record `entries` as a batch, which is replayed whole or not
+    /// at all.
+    fn append_batch<K,V>(&mut self, entries: &[JournalEntry<&K,&V>]) -> Result<(), DatabaseError>
+        where K: Serialize,
+              V: Serialize
+    {
+        let keyring = self.keyring.as_deref();
+        let mut data = Self::frame(&JournalEntry::<&K,&V>::Batch(entries.len() as u64), keyring)?;
+        for entry in entries
+        {
+            data.extend(Self::frame(entry, keyring)?);
+        }
+
+        self.write(&data)
+    }
+
+    /// Append `data` and sync it. If that fails, whatever of it was written
+    /// is cut off again, so that the entries after it aren't mistaken for
+    /// part of it.
+    fn write(&mut self, data: &[u8]) -> Result<(), DatabaseError>
+    {
+        let f = match &mut self.file
+        {
+            Some(f) =>
+                f,
+            None =>
+                self.file.insert(
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&self.path)?),
+        };
+
+        let len = f.metadata()?.len();
+        if let Err(e) = f.write_all(data).and_then(|()| f.sync_data())
+        {
+            let _ = f.set_len(len);
+            return Err(DatabaseError::Io(e));
+        }
+
+        Ok(())
+    }
+
+    /// Replace the journal with just `entries`, e.g. those which still
+    /// weren't persisted.
+    fn rewrite<K,V>(&mut self, entries: &[JournalEntry<&K,&V>]) -> Result<(), DatabaseError>
+        where K: Serialize,
+              V: Serialize
+    {
+        if entries.is_empty() && self.file.is_none() && !self.path.exists()
+        {
+            return Ok(());
+        }
+
+        let mut data = Vec::new();
+        for entry in entries
+        {
+            data.extend(Self::frame(entry, self.keyring.as_deref())?);
+        }
+
+        // The open handle refers to the replaced file
+        self.file = None;
+        write_atomic(&self.path, &data)?;
+
+        Ok(())
+    }
+}
+
+/// The records `DiskCache::persist` couldn't write, and why. They are still
+/// dirty, and will be retried by the next persist.
+#[derive(Debug)]
+pub struct PersistError<K>
+{
+    pub failed: Vec<(K, DatabaseError)>,
+    /// Set if the journal couldn't be trimmed to just the failed records,
+    /// or another table's bookkeeping (such as a hint file) couldn't be saved
+    pub journal: Option<DatabaseError>,
+}
+
+impl<K: Display> Display for PersistError<K>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        write!(f, "Unable to persist {} record(s)", self.failed.len())?;
+        for (k, e) in &self.failed
+        {
+            write!(f, "; {}: {}", k, e)?;
+        }
+
+        if let Some(e) = &self.journal
+        {
+            write!(f, "; journal: {}", e)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<K: Debug + Display> Error for PersistError<K> {}
+
+/// Names in a store's directory starting with this belong to the store
+/// itself (journal, staged writes, ...) rather than to a record.
+const RESERVED_PREFIX: char = '.';
+
+/// Turn a key into a file name which is safe on any filesystem: no path
+/// separators or `..`, and no two keys which differ only by case collide.
+/// Everything but lowercase ascii, digits, `-` and `_` is percent encoded
+/// using uppercase hex, so each key has exactly one encoding.
+pub fn encode_key(key: &str) -> String
+{
+    if key.is_empty()
+    {
+        // Otherwise the record would be the directory itself
+        return String::from("%");
+    }
+
+    let mut name = String::with_capacity(key.len());
+    for b in key.bytes()
+    {
+        match b
+        {
+            b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' =>
+                name.push(b as char),
+            _ =>
+                name.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    name
+}
+
+/// The inverse of `encode_key`. Names which `encode_key` could not have
+/// produced are rejected.
+pub fn decode_key(name: &str) -> Option<String>
+{
+    if name == "%"
+    {
+        return Some(String::new());
+    }
+
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut iter = name.bytes();
+    while let Some(b) = iter.next()
+    {
+        match b
+        {
+            b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' =>
+                bytes.push(b),
+            b'%' =>
+            {
+                let hex = [iter.next()?, iter.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            },
+            _ =>
+                return None,
+        }
+    }
+
+    let key = String::from_utf8(bytes).ok()?;
+    if key.is_empty() || encode_key(&key) != name
+    {
+        // Not canonical, e.g. "%61" for "a" or "%2f" for "/"
+        return None;
+    }
+
+    Some(key)
+}
+
+/// Rename records written before keys were encoded (when the file name was
+/// the key itself) to their encoded names. Names which are already valid
+/// encodings, or are reserved, are left alone. Returns how many were renamed.
+pub fn migrate_key_encoding(base_path: &Path) -> io::Result<usize>
+{
+    let mut renamed = 0;
+
+    for entry in fs::read_dir(base_path)?
+    {
+        let entry = entry?;
+        let name = match entry.file_name().into_string()
+        {
+            Ok(name) =>
+                name,
+            Err(name) =>
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Record {:?} is not valid unicode", name))),
+        };
+
+        if !entry.file_type()?.is_file() ||
+           name.starts_with(RESERVED_PREFIX) ||
+           decode_key(&name).is_some()
+        {
+            continue;
+        }
+
+        let new_path = base_path.join(encode_key(&name));
+        if new_path.exists()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Can't migrate {:?}, {:?} already exists", name, new_path)));
+        }
+
+        fs::rename(entry.path(), new_path)?;
+        renamed += 1;
+    }
+
+    if renamed > 0
+    {
+        sync_dir(Some(base_path))?;
+    }
+
+    Ok(renamed)
+}
+
+/// Atomically replace each record under `base_path` for which `upgrade`,
+/// given the record's key and contents, returns new contents. For changing
+/// the format of a store before it's opened, as this doesn't go through the
+/// journal. Returns how many records were replaced.
+pub fn rewrite_records<F>(base_path: &Path, mut upgrade: F) -> io::Result<usize>
+    where F: FnMut(&str, &[u8]) -> io::Result<Option<Vec<u8>>>
+{
+    let mut rewritten = 0;
+
+    let mut records = records_in(base_path, Layout::Flat)?;
+    records.extend(records_in(base_path, Layout::Sharded)?);
+    for (key, path) in records
+    {
+        if let Some(data) = upgrade(&key, &fs::read(&path)?)?
+        {
+            write_atomic(&path, &data)?;
+            rewritten += 1;
+        }
+    }
+
+    Ok(rewritten)
+}
+
+/// Upgrade every record under `base_path` written with an older schema
+/// than `options.migrations.current()`, as `DiskCache` would on reading it,
+/// for upgrading a whole store at once. Records are written as `options`
+/// say, and opened with their keyring if the store is encrypted. Records
+/// which can't be upgraded are reported and left as they are. Returns how
+/// many records were upgraded, and how many couldn't be.
+pub fn upgrade_records(base_path: &Path, options: &DiskCacheOptions) -> io::Result<(usize, usize)>
+{
+    let (migrations, keyring) = (&options.migrations, options.keyring.as_deref());
+    let mut failed = 0;
+    let upgraded = rewrite_records(base_path, |key, data| {
+        let upgraded = record::unpack(key, data, keyring)
+            .and_then(|(schema, body)| if schema == migrations.current()
+            {
+                Ok(None)
+            }
+            else
+            {
+                migrations.upgrade(key, schema, &body)
+                    .and_then(|body| record::pack(key, migrations.current(), &body, options.compression, keyring))
+                    .map(Some)
+            });
+
+        match upgraded
+        {
+            Ok(upgraded) =>
+                Ok(upgraded),
+            Err(e) =>
+            {
+                eprintln!("Unable to upgrade {:?}: {}", key, e);
+                failed += 1;
+                Ok(None)
+            },
+        }
+    })?;
+
+    Ok((upgraded, failed))
+}
+
+/// Held, by whoever has a store open for writing
+const LOCK_FILE: &str = ".lock";
+
+/// Take the lock on the store at `base_path`, failing if another process
+/// has it, and note who holds it. The lock is released when the returned
+/// file is closed, even if the process dies.
+fn lock_store(base_path: &Path) -> Result<File, DatabaseError>
+{
+    let path = base_path.join(LOCK_FILE);
+    let mut f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+
+    match f.try_lock()
+    {
+        Ok(()) =>
+            {},
+        Err(TryLockError::WouldBlock) =>
+        {
+            let pid = fs::read_to_string(&path).ok().and_then(|pid| pid.trim().parse().ok());
+            return Err(DatabaseError::Locked { path, pid });
+        },
+        Err(TryLockError::Error(e)) =>
+            return Err(DatabaseError::Io(e)),
+    }
+
+    f.set_len(0)?;
+    write!(f, "{}", std::process::id())?;
+
+    Ok(f)
+}
+
+/// Prefixed to records moved aside by `migrate_to_sharded`
+const UNSHARDED_PREFIX: &str = ".unsharded-";
+
+/// Move the records of a store kept in the `Flat` layout to where the
+/// `Sharded` layout keeps them. Picks up where it left off if interrupted,
+/// and does nothing to a store that's already sharded. Returns how many
+/// records were moved.
+pub fn migrate_to_sharded(base_path: &Path) -> io::Result<usize>
+{
+    // Records named like shard directories would be in the way of them
+    for (key, path) in records_in(base_path, Layout::Flat)?
+    {
+        let name = encode_key(&key);
+        if is_shard_name(&name)
+        {
+            fs::rename(path, base_path.join(format!("{}{}", UNSHARDED_PREFIX, name)))?;
+        }
+    }
+
+    let mut moved = 0;
+    for entry in fs::read_dir(base_path)?
+    {
+        let entry = entry?;
+        let name = match entry.file_name().into_string()
+        {
+            Ok(name) =>
+                name,
+            Err(_) =>
+                continue,
+        };
+        let name = name.strip_prefix(UNSHARDED_PREFIX).unwrap_or(&name);
+
+        if !entry.file_type()?.is_file() || decode_key(name).is_none()
+        {
+            continue;
+        }
+
+        let path = record_path(base_path, Layout::Sharded, name);
+        create_shard(&path)?;
+        fs::rename(entry.path(), &path)?;
+        sync_dir(path.parent())?;
+        moved += 1;
+    }
+
+    if moved > 0
+    {
+        sync_dir(Some(base_path))?;
+    }
+
+    Ok(moved)
+}
+
+/// Whether `name` could be one of the directories of the `Sharded` layout
+fn is_shard_name(name: &str) -> bool
+{
+    name.len() == 2 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Where `layout` keeps the record `name` (an encoded key) under `base_path`
+fn record_path(base_path: &Path, layout: Layout, name: &str) -> PathBuf
+{
+    let mut path = base_path.to_path_buf();
+    if layout == Layout::Sharded
+    {
+        let hash = Sha256::digest(name.as_bytes());
+        path.push(format!("{:02x}", hash[0]));
+        path.push(format!("{:02x}", hash[1]));
+    }
+    path.push(name);
+
+    path
+}
+
+/// The keys, and paths, of the records kept under `base_path` in `layout`.
+/// Anything else found there is skipped.
+fn records_in(base_path: &Path, layout: Layout) -> io::Result<Vec<(String, PathBuf)>>
+{
+    let mut dirs = vec![base_path.to_path_buf()];
+    if layout == Layout::Sharded
+    {
+        for _ in 0..2
+        {
+            let mut shards = Vec::new();
+            for dir in dirs
+            {
+                for entry in fs::read_dir(dir)?
+                {
+                    let entry = entry?;
+                    if entry.file_type()?.is_dir() && entry.file_name().to_str().is_some_and(is_shard_name)
+                    {
+                        shards.push(entry.path());
+                    }
+                }
+            }
+            dirs = shards;
+        }
+    }
+
+    let mut records = Vec::new();
+    for dir in dirs
+    {
+        for entry in fs::read_dir(dir)?
+        {
+            let entry = entry?;
+            if !entry.file_type()?.is_file()
+            {
+                continue;
+            }
+
+            // Skips reserved names too, as they are never valid encodings
+            if let Some(key) = entry.file_name().to_str().and_then(decode_key)
+            {
+                records.push((key, entry.path()));
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+/// Create the shard directories for the record at `path`, if need be,
+/// making sure they survive a crash.
+fn create_shard(path: &Path) -> io::Result<()>
+{
+    let shard = match path.parent()
+    {
+        Some(shard) if !shard.is_dir() =>
+            shard,
+        _ =>
+            return Ok(()),
+    };
+
+    fs::create_dir_all(shard)?;
+    let outer = shard.parent();
+    sync_dir(outer)?;
+    sync_dir(outer.and_then(Path::parent))
+}
+
+/// The path a record is staged at before being renamed over `path`.
+fn temp_path(path: &Path) -> PathBuf
+{
+    let mut name = OsString::from(RESERVED_PREFIX.to_string());
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tmp");
+
+    path.with_file_name(name)
+}
+
+/// Write `data` next to `path` and fsync it. Nothing at `path` is touched yet.
+fn write_temp(path: &Path, data: &[u8]) -> io::Result<PathBuf>
+{
+    let tmp = temp_path(path);
+    let mut f = File::create(&tmp)?;
+
+    f.write_all(data)?;
+    f.sync_all()?;
+
+    Ok(tmp)
+}
+
+/// Rename a staged record into place and fsync the directory so the rename
+/// itself survives a crash.
+fn commit_temp(tmp: &Path, path: &Path) -> io::Result<()>
+{
+    fs::rename(tmp, path)?;
+    sync_dir(path.parent())
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: Option<&Path>) -> io::Result<()>
+{
+    match dir
+    {
+        Some(dir) if dir != Path::new("") =>
+            File::open(dir)?.sync_all(),
+        _ =>
+            File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: Option<&Path>) -> io::Result<()>
+{
+    // Directories can't be opened (or synced) this way off of unix
+    Ok(())
+}
+
+/// Replace the contents of `path` such that a crash at any point leaves
+/// either the old record or the new one on disk, never a partial write.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()>
+{
+    let tmp = write_temp(path, data)?;
+    commit_temp(&tmp, path)
+}
+
+fn serialized_size<V: Serialize>(v: &V) -> u64
+{
+    bincode::serialized_size(v).unwrap_or(0)
+}
+
+impl<K,V,C> Table<K,V> for DiskCache<K,V,C>
+    where K: Clone + Display + Eq + FromStr + Hash + DeserializeOwned + Serialize,
+          V: DeserializeOwned + Serialize,
+          C: Codec
+{
+    fn persist(&self) -> Result<usize, PersistError<K>>
+    {
+        DiskCache::persist(self)
+    }
+
+    fn scrub(&self, quarantine: bool) -> io::Result<ScrubReport>
+    {
+        DiskCache::scrub(self, quarantine)
+    }
+
+    fn reencrypt(&self) -> Result<(usize, usize), DatabaseError>
+    {
+        DiskCache::reencrypt(self)
+    }
+
+    fn stats(&self) -> TableStats
+    {
+        DiskCache::stats(self)
+    }
+
+    /// Only applied once the change has been journaled.
+    fn try_set(&self, k: K, v: V) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        self.writable()?;
+        let mut journal = lock(&self.journal);
+        journal.append(&JournalEntry::<&K,&V>::Set(&k, &v))?;
+        let old = self.apply(&mut write(&self.state), JournalEntry::Set(k.clone(), v));
+        drop(journal);
+        self.evict(Some(&k));
+
+        Ok(old)
+    }
+
+    /// Records are read from disk without holding the lock, unless changes
+    /// keep being made while they're read, when the last try holds it.
+    fn try_get(&self, k: &K) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        for _ in 0..READ_TRIES
+        {
+            let seq = {
+                let state = read(&self.state);
+                if let Some(v) = self.cached(&state, k)
+                {
+                    return Ok(v);
+                }
+
+                state.seq
+            };
+            self.counters.miss();
+
+            // Without holding the lock
+            let read = match self.get_from_disk(k)
+            {
+                Ok(read) =>
+                    read,
+                Err(DatabaseError::NotFound) =>
+                    return Ok(None),
+                Err(e) =>
+                    return Err(e),
+            };
+
+            let mut state = write(&self.state);
+            if let Some(c) = state.cache.get(k)
+            {
+                // Another reader beat us to it
+                return Ok(Some(c.value.clone()));
+            }
+            else if state.seq == seq
+            {
+                let v = self.load(&mut state, k, read);
+                drop(state);
+                self.evict(Some(k));
+
+                return Ok(Some(v));
+            }
+            // Otherwise something changed while reading, so what was read
+            // may already be out of date
+        }
+
+        // Nothing can change the record on disk while this is held, as it's
+        // neither cached nor dirty
+        let mut state = write(&self.state);
+        if let Some(v) = self.cached(&state, k)
+        {
+            return Ok(v);
+        }
+        self.counters.miss();
+        let read = match self.get_from_disk(k)
+        {
+            Ok(read) =>
+                read,
+            Err(DatabaseError::NotFound) =>
+                return Ok(None),
+            Err(e) =>
+                return Err(e),
+        };
+        let v = self.load(&mut state, k, read);
+        drop(state);
+        self.evict(Some(k));
+
+        Ok(Some(v))
+    }
+
+    fn try_contains_key(&self, k: &K) -> Result<bool, DatabaseError>
+    {
+        {
+            let state = read(&self.state);
+            if state.cache.contains_key(k)
+            {
+                return Ok(true);
+            }
+            else if state.removed.contains(k)
+            {
+                return Ok(false);
+            }
+        }
+
+        self.try_is_on_disk(k)
+    }
+
+    /// Only applied once the change has been journaled.
+    fn try_remove(&self, k: &K) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        self.writable()?;
+        let mut journal = lock(&self.journal);
+        journal.append(&JournalEntry::<&K,&V>::Remove(k))?;
+
+        Ok(self.apply(&mut write(&self.state), JournalEntry::Remove((*k).clone())))
+    }
+
+    /// Journaled as one batch, then applied under one lock, so neither a
+    /// reader nor a persist sees part of it. Until the persist after it
+    /// finishes, the journal holds the whole batch, so a crash part way
+    /// through persisting it is recovered from by replaying all of it.
+    fn commit(&self, batch: Batch<K,V>) -> Result<(), DatabaseError>
+    {
+        self.writable()?;
+        if batch.is_empty()
+        {
+            return Ok(());
+        }
+        let mut journal = lock(&self.journal);
+        let entries: Vec<_> = batch.ops().iter().map(JournalEntry::staged).collect();
+        journal.append_batch(&entries)?;
+
+        let mut state = write(&self.state);
+        for op in batch.into_ops()
+        {
+            self.apply(&mut state, op.into());
+        }
+        drop(state);
+        drop(journal);
+        self.evict(None);
+
+        Ok(())
+    }
+
+    /// Merges what's on disk with the changes which haven't been persisted.
+    fn keys(&self) -> Vec<K>
+    {
+        let on_disk = match self.keys_on_disk()
+        {
+            Ok(keys) =>
+                keys,
+            Err(e) =>
+            {
+                eprintln!("Unable to list {:?}: {}", self.base_path, e);
+                Vec::new()
+            },
+        };
+
+        let state = read(&self.state);
+        let mut keys: HashSet<K> = on_disk
+            .into_iter()
+            .filter(|k| !state.removed.contains(k))
+            .collect();
+        keys.extend(state.cache.keys().cloned());
+
+        keys.into_iter().collect()
+    }
+
+    /// Records which aren't cached are read from disk as they're reached,
+    /// without being cached, so a scan doesn't flush the cache.
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, Arc<V>)> + '_>
+    {
+        Box::new(
+            self.keys()
+                .into_iter()
+                .filter_map(move |k| {
+                    let cached = read(&self.state).cache.get(&k).map(|c| c.value.clone());
+                    let v = match cached
+                    {
+                        Some(v) =>
+                            v,
+                        None =>
+                            Arc::new(*self.get_from_disk(&k).ok()?.0),
+                    };
+
+                    Some((k, v))
+                }))
+    }
+}
+
+/// Runs a `Table`'s operations on the blocking thread pool, so async
+/// callers aren't held up by disk I/O.
+pub struct AsyncTable<T>(Arc<T>);
+
+impl<T> AsyncTable<T>
+    where T: Send + Sync + 'static
+{
+    pub fn new(table: T) -> Self
+    {
+        AsyncTable(Arc::new(table))
+    }
+
+    /// Wraps a table which is also used elsewhere.
+    pub fn shared(table: Arc<T>) -> Self
+    {
+        AsyncTable(table)
+    }
+
+    /// The wrapped table, for callers which may block.
+    pub fn inner(&self) -> &T
+    {
+        &self.0
+    }
+
+    /// Run `f` against the table on the blocking thread pool.
+    pub async fn run<F, R>(&self, f: F) -> Result<R, DatabaseError>
+        where F: FnOnce(&T) -> R + Send + 'static,
+              R: Send + 'static
+    {
+        let table = self.0.clone();
+
+        actix_web::web::block(move || Ok::<R, ()>(f(&table)))
+            .await
+            .map_err(|_| DatabaseError::Io(io::Error::other("Blocking operation was cancelled")))
+    }
+
+    pub async fn try_set<K,V>(&self, k: K, v: V) -> Result<Option<Arc<V>>, DatabaseError>
+        where T: Table<K,V>,
+              K: Send + 'static,
+              V: Send + Sync + 'static
+    {
+        self.run(move |t| t.try_set(k, v)).await?
+    }
+
+    pub async fn try_get<K,V>(&self, k: K) -> Result<Option<Arc<V>>, DatabaseError>
+        where T: Table<K,V>,
+              K: Send + 'static,
+              V: Send + Sync + 'static
+    {
+        self.run(move |t| t.try_get(&k)).await?
+    }
+
+    pub async fn try_contains_key<K,V>(&self, k: K) -> Result<bool, DatabaseError>
+        where T: Table<K,V>,
+              K: Send + 'static
+    {
+        self.run(move |t| t.try_contains_key(&k)).await?
+    }
+
+    pub async fn try_remove<K,V>(&self, k: K) -> Result<Option<Arc<V>>, DatabaseError>
+        where T: Table<K,V>,
+              K: Send + 'static,
+              V: Send + Sync + 'static
+    {
+        self.run(move |t| t.try_remove(&k)).await?
+    }
+
+    pub async fn commit<K,V>(&self, batch: Batch<K,V>) -> Result<(), DatabaseError>
+        where T: Table<K,V>,
+              K: Send + 'static,
+              V: Send + 'static
+    {
+        self.run(move |t| t.commit(batch)).await?
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+
+    #[derive(Clone, Deserialize, Hash, Eq, PartialEq, Serialize)]
+    struct TestKey { k: String }
+
+    impl Display for TestKey
+    {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+        {
+            write!(f, "{}", self.k)
+        }
+    }
+
+    impl FromStr for TestKey
+    {
+        type Err = std::convert::Infallible;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err>
+        {
+            Ok(TestKey { k: String::from(s) })
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+    struct TestVal { v: String }
+
+    impl<K,V> DiskCache<K,V>
+        where K: Clone + Eq + Hash + Display + DeserializeOwned + Serialize,
+              V: DeserializeOwned + Serialize
+    {
+        fn state(&self) -> RwLockReadGuard<'_, CacheState<K,V>>
+        {
+            read(&self.state)
+        }
+
+        /// Forget everything cached, as though the server had restarted
+        fn clear_cache(&self)
+        {
+            let mut state = write(&self.state);
+            state.cache.clear();
+            state.lru.clear();
+            state.bytes = 0;
+        }
+    }
+
+    const TEST_STR: &str = "~~~~😸 + 🦀 = 🎇~~~~";
+
+   // #[test]
+   // fn create_test_records()
+   // {
+   //     // For creating any needed test resources uncomment this fn
+   //     let mut base_path = std::env::current_dir().unwrap();
+   //     base_path.push("database-test-db");
+
+   //     let dc = DiskCache::<TestKey, TestVal>::new(base_path).unwrap();
+   //     for n in 0..8
+   //     {
+   //         let key = TestKey { k: String::from(format!("multiple-record-{}", n)) };
+   //         let val = TestVal { v: String::from(TEST_STR)};
+   //         dc.set(key, val);
+   //     }
+
+   //     dc.persist().unwrap();
+
+   //     panic!()
+   // }
+   //
+
+    #[allow(clippy::single_match)]
+    fn scrub_a_dub(record: &PathBuf)
+    {
+        // Clean up created resources if present
+        match fs::remove_file(record)
+        {
+            Ok(_) => {},
+            Err(_) => {},
+        };
+
+       assert!(!record.exists());
+    }
+
+    /// The records committed under `database-test-db`, which tests share, and
+    /// so only read.
+    fn fixture_db(budget: Budget) -> DiskCache<TestKey, TestVal>
+    {
+        let mut base_path = std::env::current_dir().unwrap();
+        base_path.push("database-test-db");
+
+        let options = DiskCacheOptions { budget, access: Access::ReadOnly, ..DiskCacheOptions::default() };
+        DiskCache::with_options(base_path, options).unwrap()
+    }
+
+    /// A fresh, empty directory for tests which write to their store, so
+    /// that they don't trip over each other's records or journals.
+    fn scratch_db(name: &str) -> PathBuf
+    {
+        let mut path = std::env::temp_dir();
+        path.push("img-forest-test");
+        path.push(name);
+
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn add_contains_rm_dc_sanity()
+    {
+        let foo = String::from("this-record-not-persisted");
+        let bar = String::from("Hello World!");
+        let dc = DiskCache::new(scratch_db("add_contains_rm_dc_sanity")).unwrap();
+
+        assert!(!dc.contains_key(&foo));
+        assert_eq!(dc.set(foo.clone(), bar.clone()), None);
+        assert!(dc.contains_key(&foo));
+        assert_eq!(dc.remove(&foo), Some(Arc::new(bar)));
+        assert!(!dc.contains_key(&foo));
+    }
+
+    #[test]
+    fn dc_contains_key_finds_records_on_disk()
+    {
+        let dc = fixture_db(Budget::Unbounded);
+
+        let key = TestKey { k: String::from("this-record-exists") };
+
+        assert!(!dc.state().cache.contains_key(&key));
+        assert!(dc.contains_key(&key));
+    }
+
+    #[test]
+    fn dc_adds_extant_record()
+    {
+        let dc = fixture_db(Budget::Unbounded);
+
+        let key = TestKey { k: String::from("this-record-exists") };
+
+        assert!(!dc.state().cache.contains_key(&key));
+        assert_eq!(
+            &TestVal { v: String::from(TEST_STR) },
+            &*dc.get(&key).unwrap()
+        );
+
+        assert_eq!(1, dc.state().cache.len());
+        assert!(dc.state().cache.contains_key(&key));
+    }
+
+    #[test]
+    #[allow(clippy::useless_conversion, clippy::needless_borrow)]
+    fn dc_adds_multiple_extant_record()
+    {
+        let dc = fixture_db(Budget::Unbounded);
+
+        let mut keys = Vec::with_capacity(8);
+        for n in 0..8
+        {
+            let key = TestKey { k: String::from(format!("multiple-record-{}", n)) };
+            keys.push(key);
+        }
+
+        for key in &keys
+        {
+            dc.get(key);
+        }
+
+        assert_eq!(8, dc.state().cache.len());
+
+        for key in &keys
+        {
+            assert!(dc.contains_key(&key));
+            assert_eq!(
+                &TestVal { v: String::from(TEST_STR) },
+                &*dc.get(&key).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn dc_doesnt_add_non_extant_record()
+    {
+        let key = TestKey { k: String::from("this-record-does-not-exist") };
+        let dc = fixture_db(Budget::Unbounded);
+        dc.get(&key);
+
+        assert!(!dc.contains_key(&key));
+    }
+
+    #[test]
+    fn dc_persists_new_records_to_disk()
+    {
+        let key = String::from("a-new-record");
+        let val = String::from("bar");
+        let test_db = scratch_db("dc_persists_new_records_to_disk");
+
+        let mut record = test_db.clone();
+        let dc = DiskCache::new(test_db).unwrap();
+        record.push(&key);
+
+        // Records should not be present before starting
+        // or else this test is meaningless
+        scrub_a_dub(&record);
+
+        dc.set(key.clone(), val.clone());
+        dc.persist().unwrap();
+
+        // Records should be present after persist operation
+        assert!(record.exists());
+
+        // Clean Up
+        scrub_a_dub(&record);
+    }
+
+    #[test]
+    fn dc_persist_removes_from_disk()
+    {
+        let key = String::from("a-record-on-disk");
+        let val = String::from("bar");
+        let test_db = scratch_db("dc_persist_removes_from_disk");
+
+        let mut record = test_db.clone();
+        let dc = DiskCache::new(test_db).unwrap();
+        record.push(&key);
+
+        // Set initial value on disk
+        scrub_a_dub(&record);
+        dc.set(key.clone(), val.clone());
+        dc.persist().unwrap();
+
+        dc.clear_cache();
+
+        // Record should not be present on disk
+        // after remove & persist
+        dc.remove(&key);
+        dc.persist().unwrap();
+        assert!(!record.exists());
+
+        // Clean Up
+        scrub_a_dub(&record);
+    }
+
+    #[test]
+    fn dc_persist_updates_existing_record()
+    {
+        let key = String::from("a-record-to-update");
+        let val0 = String::from("bar");
+        let val1 = String::from("baz");
+        let test_db = scratch_db("dc_persist_updates_existing_record");
+
+        let mut record = test_db.clone();
+        let dc = DiskCache::new(test_db).unwrap();
+        record.push(&key);
+
+        // Records should not be present before starting
+        scrub_a_dub(&record);
+
+        // Set initial value on disk
+        dc.set(key.clone(), val0.clone());
+        dc.persist().unwrap();
+        assert!(record.exists());
+
+        dc.clear_cache();
+
+        // Set updated value on disk
+        dc.remove(&key);
+        dc.set(key.clone(), val1.clone());
+        dc.persist().unwrap();
+
+        dc.clear_cache();
+
+        assert!(record.exists());
+        assert_eq!(
+            &val1,
+            &*dc.get(&key).unwrap()
+        );
+
+        // Clean Up
+        scrub_a_dub(&record);
+    }
+
+    #[test]
+    fn dc_interrupted_write_keeps_old_record()
+    {
+        let key = String::from("a-record-interrupted");
+        let val0 = String::from("bar");
+        let val1 = String::from("baz");
+        let test_db = scratch_db("dc_interrupted_write_keeps_old_record");
+
+        let mut record = test_db.clone();
+        let dc = DiskCache::new(test_db).unwrap();
+        record.push(&key);
+        let tmp = temp_path(&record);
+
+        scrub_a_dub(&record);
+        scrub_a_dub(&tmp);
+
+        dc.set(key.clone(), val0.clone());
+        dc.persist().unwrap();
+
+        // Crash after staging the new record, but before it was renamed
+        let staged = write_temp(&record, &bincode::serialize(&val1).unwrap()).unwrap();
+        assert_eq!(tmp, staged);
+
+        dc.clear_cache();
+        assert_eq!(
+            &val0,
+            &*dc.get(&key).unwrap()
+        );
+
+        // Clean Up
+        scrub_a_dub(&record);
+        scrub_a_dub(&tmp);
+    }
+
+    #[test]
+    fn dc_persist_replaces_truncated_temp_file()
+    {
+        let key = String::from("a-record-half-written");
+        let val = String::from("bar");
+        let test_db = scratch_db("dc_persist_replaces_truncated_temp_file");
+
+        let mut record = test_db.clone();
+        let dc = DiskCache::new(test_db).unwrap();
+        record.push(&key);
+        let tmp = temp_path(&record);
+
+        scrub_a_dub(&record);
+        scrub_a_dub(&tmp);
+
+        // Crash part way through writing the staged record
+        let fdata = bincode::serialize(&val).unwrap();
+        File::create(&tmp).unwrap().write_all(&fdata[..fdata.len() / 2]).unwrap();
+
+        // The half written record is never visible
+        assert!(!dc.contains_key(&key));
+        assert_eq!(None, dc.get(&key));
+
+        dc.set(key.clone(), val.clone());
+        dc.persist().unwrap();
+
+        assert!(record.exists());
+        assert!(!tmp.exists());
+
+        dc.clear_cache();
+        assert_eq!(
+            &val,
+            &*dc.get(&key).unwrap()
+        );
+
+        // Clean Up
+        scrub_a_dub(&record);
+        scrub_a_dub(&tmp);
+    }
+
+    #[test]
+    fn dc_journal_replays_unpersisted_set()
+    {
+        let key = String::from("a-record-never-persisted");
+        let val = String::from("bar");
+        let test_db = scratch_db("dc_journal_replays_unpersisted_set");
+        let record = test_db.join(&key);
+
+        let dc = DiskCache::new(test_db.clone()).unwrap();
+        dc.try_set(key.clone(), val.clone()).unwrap();
+
+        // Crash before persisting
+        drop(dc);
+        assert!(!record.exists());
+
+        let dc = DiskCache::<String, String>::new(test_db).unwrap();
+        assert_eq!(
+            &val,
+            &*dc.get(&key).unwrap()
+        );
+
+        dc.persist().unwrap();
+        assert!(record.exists());
+    }
+
+    #[test]
+    fn dc_journal_replays_unpersisted_remove()
+    {
+        let key = String::from("a-record-removed");
+        let val = String::from("bar");
+        let test_db = scratch_db("dc_journal_replays_unpersisted_remove");
+        let record = test_db.join(&key);
+
+        let dc = DiskCache::new(test_db.clone()).unwrap();
+        dc.set(key.clone(), val.clone());
+        dc.persist().unwrap();
+        drop(dc);
+
+        let dc = DiskCache::<String, String>::new(test_db.clone()).unwrap();
+        dc.try_remove(&key).unwrap();
+        assert!(!dc.contains_key(&key));
+
+        // Crash before persisting
+        drop(dc);
+        assert!(record.exists());
+
+        let dc = DiskCache::<String, String>::new(test_db).unwrap();
+        assert!(!dc.contains_key(&key));
+        assert_eq!(None, dc.get(&key));
+
+        dc.persist().unwrap();
+        assert!(!record.exists());
+    }
+
+    #[test]
+    fn dc_persist_truncates_journal()
+    {
+        let key = String::from("a-record-persisted");
+        let val = String::from("bar");
+        let test_db = scratch_db("dc_persist_truncates_journal");
+        let journal = test_db.join(Journal::FILE_NAME);
+
+        let dc = DiskCache::new(test_db.clone()).unwrap();
+        dc.set(key.clone(), val.clone());
+        assert!(fs::metadata(&journal).unwrap().len() > 0);
+
+        dc.persist().unwrap();
+        assert_eq!(0, fs::metadata(&journal).unwrap().len());
+        drop(dc);
+
+        let dc = DiskCache::<String, String>::new(test_db).unwrap();
+        assert!(dc.state().disk_update_required.is_empty());
+        assert!(dc.state().cache.is_empty());
+    }
+
+    #[test]
+    fn dc_journal_drops_torn_entry()
+    {
+        let key0 = String::from("a-record-journaled");
+        let key1 = String::from("a-record-half-journaled");
+        let val = String::from("bar");
+        let test_db = scratch_db("dc_journal_drops_torn_entry");
+        let journal = test_db.join(Journal::FILE_NAME);
+
+        let dc = DiskCache::new(test_db.clone()).unwrap();
+        dc.set(key0.clone(), val.clone());
+        drop(dc);
+        let intact_len = fs::metadata(&journal).unwrap().len();
+
+        // Crash part way through appending a second entry
+        let dc = DiskCache::new(test_db.clone()).unwrap();
+        dc.set(key1.clone(), val.clone());
+        drop(dc);
+        let torn_len = (intact_len + fs::metadata(&journal).unwrap().len()) / 2;
+        OpenOptions::new().write(true).open(&journal).unwrap().set_len(torn_len).unwrap();
+
+        let dc = DiskCache::<String, String>::new(test_db.clone()).unwrap();
+        assert_eq!(intact_len, fs::metadata(&journal).unwrap().len());
+        assert_eq!(Some(&val), dc.get(&key0).as_deref());
+        assert!(!dc.contains_key(&key1));
+
+        // New entries land after the intact ones
+        dc.set(key1.clone(), val.clone());
+        drop(dc);
+
+        let dc = DiskCache::<String, String>::new(test_db).unwrap();
+        assert_eq!(Some(&val), dc.get(&key0).as_deref());
+        assert_eq!(Some(&val), dc.get(&key1).as_deref());
+    }
+
+    #[test]
+    fn dc_journal_refuses_a_corrupt_entry()
+    {
+        let test_db = scratch_db("dc_journal_refuses_a_corrupt_entry");
+        let journal = test_db.join(Journal::FILE_NAME);
+
+        let dc = DiskCache::new(test_db.clone()).unwrap();
+        dc.set(String::from("a"), String::from("bar"));
+        dc.set(String::from("b"), String::from("bar"));
+        drop(dc);
+
+        // Whole, but not an entry, so neither it nor what follows is torn
+        let mut data = fs::read(&journal).unwrap();
+        data[8..12].copy_from_slice(&[0xff; 4]);
+        fs::write(&journal, &data).unwrap();
+
+        assert!(matches!(DiskCache::<String, String>::new(test_db), Err(DatabaseError::Corrupt(_))));
+        assert_eq!(data, fs::read(&journal).unwrap());
+    }
+
+    #[test]
+    fn dc_journal_drops_torn_batch_whole()
+    {
+        let test_db = scratch_db("dc_journal_drops_torn_batch_whole");
+        let journal = test_db.join(Journal::FILE_NAME);
+
+        let dc = DiskCache::new(test_db.clone()).unwrap();
+        dc.set(String::from("before"), String::from("a"));
+        let intact_len = fs::metadata(&journal).unwrap().len();
+        let mut batch = dc.begin();
+        batch.set(String::from("first"), String::from("b")).remove(String::from("before"));
+        dc.commit(batch).unwrap();
+        drop(dc);
+
+        // Whole, the batch is replayed
+        let dc = DiskCache::<String, String>::new(test_db.clone()).unwrap();
+        assert_eq!(vec!["first"], dc.keys());
+        drop(dc);
+
+        // Crash part way through appending the batch's last entry
+        let len = fs::metadata(&journal).unwrap().len();
+        OpenOptions::new().write(true).open(&journal).unwrap().set_len(len - 2).unwrap();
+
+        let dc = DiskCache::<String, String>::new(test_db).unwrap();
+        assert_eq!(intact_len, fs::metadata(&journal).unwrap().len());
+        assert_eq!(vec!["before"], dc.keys());
+    }
+
+    #[test]
+    fn dc_entry_budget_evicts_least_recently_used()
+    {
+        let (a, b, c) = (String::from("a"), String::from("b"), String::from("c"));
+        let val = String::from("bar");
+        let dc = DiskCache::with_budget(
+            scratch_db("dc_entry_budget_evicts_least_recently_used"),
+            Budget::Entries(2)).unwrap();
+
+        dc.set(a.clone(), val.clone());
+        dc.set(b.clone(), val.clone());
+        dc.persist().unwrap();
+
+        // a is now more recently used than b
+        dc.get(&a);
+        dc.set(c.clone(), val.clone());
+
+        assert_eq!(2, dc.state().cache.len());
+        assert!(dc.state().cache.contains_key(&a));
+        assert!(!dc.state().cache.contains_key(&b));
+        assert!(dc.state().cache.contains_key(&c));
+        assert_eq!(1, dc.eviction_stats().evictions);
+        assert_eq!(0, dc.eviction_stats().dirty_writes);
+
+        // Evicted, not lost
+        assert!(dc.contains_key(&b));
+        assert_eq!(Some(&val), dc.get(&b).as_deref());
+        assert!(!dc.state().cache.contains_key(&a));
+
+        dc.remove(&c);
+        let mut queued: Vec<_> = dc.state().lru.values().cloned().collect();
+        queued.sort();
+        assert_eq!(vec![b], queued);
+    }
+
+    #[test]
+    fn dc_eviction_persists_dirty_records()
+    {
+        let (a, b) = (String::from("a"), String::from("b"));
+        let val = String::from("bar");
+        let test_db = scratch_db("dc_eviction_persists_dirty_records");
+        let dc = DiskCache::with_budget(test_db.clone(), Budget::Entries(1)).unwrap();
+
+        dc.set(a.clone(), val.clone());
+        assert!(!test_db.join(&a).exists());
+
+        dc.set(b.clone(), val.clone());
+        assert!(test_db.join(&a).exists());
+        assert!(!test_db.join(&b).exists());
+        assert!(!dc.state().cache.contains_key(&a));
+        assert_eq!(1, dc.eviction_stats().dirty_writes);
+
+        assert_eq!(Some(&val), dc.get(&a).as_deref());
+    }
+
+    #[test]
+    fn dc_eviction_keeps_dirty_records_during_a_persist()
+    {
+        let (a, b) = (String::from("a"), String::from("b"));
+        let val = String::from("bar");
+        let test_db = scratch_db("dc_eviction_keeps_dirty_records_during_a_persist");
+        let dc = DiskCache::with_budget(test_db.clone(), Budget::Entries(1)).unwrap();
+
+        dc.set(a.clone(), val.clone());
+        {
+            let _persisting = lock(&dc.persisting);
+            dc.set(b.clone(), val.clone());
+        }
+
+        // Over budget, rather than losing it
+        assert!(dc.state().cache.contains_key(&a));
+        assert!(dc.state().disk_update_required.contains_key(&a));
+        assert!(!test_db.join(&a).exists());
+        assert_eq!(0, dc.eviction_stats().evictions);
+
+        assert_eq!(2, dc.persist().unwrap());
+        assert!(test_db.join(&a).exists());
+    }
+
+    #[test]
+    fn dc_byte_budget_evicts_by_size()
+    {
+        let val = "x".repeat(100);
+        let size = serialized_size(&val);
+        let dc = DiskCache::with_budget(
+            scratch_db("dc_byte_budget_evicts_by_size"),
+            Budget::Bytes(size * 5 / 2)).unwrap();
+
+        for k in &["a", "b", "c"]
+        {
+            dc.set(String::from(*k), val.clone());
+        }
+
+        assert_eq!(2, dc.state().cache.len());
+        assert!(!dc.state().cache.contains_key("a"));
+        assert_eq!(size * 2, dc.state().bytes);
+        assert_eq!(
+            EvictionStats { evictions: 1, bytes_evicted: size, dirty_writes: 1 },
+            dc.eviction_stats()
+        );
+    }
+
+    #[test]
+    fn dc_budget_bounds_records_loaded_from_disk()
+    {
+        let dc = fixture_db(Budget::Entries(4));
+
+        for n in 0..8
+        {
+            let key = TestKey { k: format!("multiple-record-{}", n) };
+            assert_eq!(
+                &TestVal { v: String::from(TEST_STR) },
+                &*dc.get(&key).unwrap()
+            );
+        }
+
+        assert_eq!(4, dc.state().cache.len());
+        assert_eq!(4, dc.eviction_stats().evictions);
+    }
+
+    #[test]
+    fn key_encoding_round_trips()
+    {
+        for key in &["", "a-normal-cat", "Cat", "../../etc/foo", "a/b", ".journal", "%", "%41", "😸🦀", TEST_STR]
+        {
+            let name = encode_key(key);
+
+            assert!(!name.contains('/'));
+            assert!(!name.contains('\\'));
+            assert!(!name.starts_with(RESERVED_PREFIX));
+            assert_eq!(Some(String::from(*key)), decode_key(&name));
+        }
+
+        assert_eq!("a-normal-cat", encode_key("a-normal-cat"));
+        assert_eq!("%43at", encode_key("Cat"));
+        assert_eq!("%2E%2E%2Fetc", encode_key("../etc"));
+        assert_ne!(encode_key("cat"), encode_key("Cat"));
+    }
+
+    #[test]
+    fn key_decoding_rejects_non_canonical_names()
+    {
+        for name in &["Cat", "%61", "%2f", "%2", "%zz", "%+1", ".journal", "a.b", ""]
+        {
+            assert_eq!(None, decode_key(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn dc_keys_cant_escape_base_path()
+    {
+        let test_db = scratch_db("dc_keys_cant_escape_base_path");
+        let mut store = test_db.clone();
+        store.push("store");
+        fs::create_dir(&store).unwrap();
+
+        let key = String::from("../escaped");
+        let val = String::from("bar");
+        let dc = DiskCache::new(store.clone()).unwrap();
+        dc.set(key.clone(), val.clone());
+        dc.persist().unwrap();
+
+        assert!(!test_db.join("escaped").exists());
+        assert!(store.join("%2E%2E%2Fescaped").exists());
+        drop(dc);
+
+        let dc = DiskCache::<String, String>::new(store).unwrap();
+        assert_eq!(Some(&val), dc.get(&key).as_deref());
+    }
+
+    #[test]
+    fn migrate_key_encoding_renames_raw_names()
+    {
+        let test_db = scratch_db("migrate_key_encoding_renames_raw_names");
+        let val = String::from("bar");
+        let fdata = bincode::serialize(&val).unwrap();
+        for name in &["a-normal-cat", "Out-On-The-Town", "50%off"]
+        {
+            fs::write(test_db.join(name), &fdata).unwrap();
+        }
+        fs::write(test_db.join(".journal"), b"").unwrap();
+
+        assert_eq!(2, migrate_key_encoding(&test_db).unwrap());
+        assert_eq!(0, migrate_key_encoding(&test_db).unwrap());
+
+        let mut names: Vec<_> = fs::read_dir(&test_db).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(vec!["%4Fut-%4Fn-%54he-%54own", ".journal", "50%25off", "a-normal-cat"], names);
+
+        let dc = DiskCache::<String, String>::new(test_db).unwrap();
+        for key in &["a-normal-cat", "Out-On-The-Town", "50%off"]
+        {
+            assert_eq!(Some(&val), dc.get(&String::from(*key)).as_deref());
+        }
+    }
+
+    #[test]
+    fn dc_records_are_encoded_by_the_codec()
+    {
+        let test_db = scratch_db("dc_records_are_encoded_by_the_codec");
+        let dc = DiskCache::with_codec(test_db.clone(), DiskCacheOptions::default(), Json).unwrap();
+        dc.set(String::from("a/b"), vec![String::from("foo"), String::from("bar")]);
+        dc.persist().unwrap();
+
+        let data = fs::read(test_db.join("a%2Fb")).unwrap();
+        assert_eq!(decoded(0, br#"["foo","bar"]"#), record::decode(&data).unwrap());
+        assert_eq!(Json::NAME, serde_json::from_slice::<serde_json::Value>(&fs::read(test_db.join(".manifest")).unwrap()).unwrap()["codec"]);
+    }
+
+    #[test]
+    fn dc_opening_with_the_wrong_codec_fails()
+    {
+        let test_db = scratch_db("dc_opening_with_the_wrong_codec_fails");
+        let dc = DiskCache::with_codec(test_db.clone(), DiskCacheOptions::default(), MessagePack).unwrap();
+        dc.set(String::from("foo"), String::from("bar"));
+        dc.persist().unwrap();
+        drop(dc);
+
+        match DiskCache::<String, String>::new(test_db.clone())
+        {
+            Err(DatabaseError::WrongCodec { store, opened }) =>
+                assert_eq!((MessagePack::NAME, Bincode::NAME), (&store[..], opened)),
+            other =>
+                panic!("Opened a msgpack store as bincode: {:?}", other.err()),
+        }
+
+        let dc = DiskCache::<String, String, _>::with_codec(test_db, DiskCacheOptions::default(), MessagePack).unwrap();
+        assert_eq!(Some(&String::from("bar")), dc.get(&String::from("foo")).as_deref());
+    }
+
+    #[test]
+    fn dc_stores_from_before_manifests_are_bincode()
+    {
+        let test_db = scratch_db("dc_stores_from_before_manifests_are_bincode");
+        fs::write(test_db.join("foo"), bincode::serialize("bar").unwrap()).unwrap();
+
+        let json = DiskCache::<String, String, _>::with_codec(test_db.clone(), DiskCacheOptions::default(), Json);
+        assert!(matches!(json, Err(DatabaseError::WrongCodec { .. })));
+        assert!(!test_db.join(".manifest").exists());
+
+        let dc = DiskCache::<String, String>::new(test_db.clone()).unwrap();
+        assert_eq!(Some(&String::from("bar")), dc.get(&String::from("foo")).as_deref());
+        assert!(test_db.join(".manifest").exists());
+
+        // Whereas an empty one is whatever it's first opened as
+        let empty = scratch_db("dc_stores_from_before_manifests_are_bincode-empty");
+        assert!(DiskCache::<String, String, _>::with_codec(empty.clone(), DiskCacheOptions::default(), Cbor).is_ok());
+        assert!(DiskCache::<String, String>::new(empty).is_err());
+    }
+
+    fn sharded(base_path: PathBuf) -> DiskCache<String, String>
+    {
+        DiskCache::with_options(base_path, DiskCacheOptions { layout: Layout::Sharded, ..DiskCacheOptions::default() }).unwrap()
+    }
+
+    #[test]
+    fn dc_sharded_layout_spreads_records()
+    {
+        let test_db = scratch_db("dc_sharded_layout_spreads_records");
+        let dc = sharded(test_db.clone());
+        for k in &["a-normal-cat", "a/b", "3f"]
+        {
+            dc.set(String::from(*k), k.to_uppercase());
+        }
+        dc.persist().unwrap();
+
+        let path = record_path(&test_db, Layout::Sharded, "a-normal-cat");
+        assert_eq!(test_db, path.ancestors().nth(3).unwrap());
+        assert!(path.is_file());
+        assert!(record_path(&test_db, Layout::Sharded, "a%2Fb").is_file());
+        assert!(!test_db.join("a-normal-cat").exists());
+        drop(dc);
+
+        let dc = sharded(test_db.clone());
+        assert_eq!(vec!["3f", "a-normal-cat", "a/b"], sorted(dc.keys()));
+        dc.remove(&String::from("3f"));
+        dc.persist().unwrap();
+        assert!(!record_path(&test_db, Layout::Sharded, "3f").exists());
+        drop(dc);
+
+        // Nothing to see, laid out flat
+        assert!(DiskCache::<String, String>::new(test_db).unwrap().keys().is_empty());
+    }
+
+    #[test]
+    fn migrate_to_sharded_moves_flat_records()
+    {
+        let test_db = scratch_db("migrate_to_sharded_moves_flat_records");
+        let dc = DiskCache::new(test_db.clone()).unwrap();
+        // Including records named like the shard directories they'll go in
+        let keys = ["a-normal-cat", "a/b", "3f", "00", "ff"];
+        for k in &keys
+        {
+            dc.set(String::from(*k), k.to_uppercase());
+        }
+        dc.persist().unwrap();
+        dc.set(String::from("journaled"), String::from("JOURNALED"));
+        drop(dc);
+
+        // As if interrupted after moving a record aside
+        fs::rename(test_db.join("ff"), test_db.join(".unsharded-ff")).unwrap();
+
+        assert_eq!(5, migrate_to_sharded(&test_db).unwrap());
+        assert_eq!(0, migrate_to_sharded(&test_db).unwrap());
+
+        let mut names: Vec<_> = fs::read_dir(&test_db).unwrap()
+            .map(|e| e.unwrap())
+            .filter(|e| e.file_type().unwrap().is_file())
+            .map(|e| e.file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(vec![".journal", ".lock", ".manifest"], names);
+
+        let dc = sharded(test_db);
+        for k in &keys
+        {
+            assert_eq!(Some(k.to_uppercase()), dc.get(&String::from(*k)).map(|v| (*v).clone()));
+        }
+        assert_eq!(Some(&String::from("JOURNALED")), dc.get(&String::from("journaled")).as_deref());
+    }
+
+    #[test]
+    fn rewrite_records_finds_sharded_records()
+    {
+        let test_db = scratch_db("rewrite_records_finds_sharded_records");
+        let dc = sharded(test_db.clone());
+        dc.set(String::from("a/b"), String::from("old"));
+        dc.persist().unwrap();
+        drop(dc);
+
+        let rewritten = rewrite_records(&test_db, |_, _| Ok(Some(bincode::serialize("new").unwrap())));
+        assert_eq!(1, rewritten.unwrap());
+        assert_eq!(Some(&String::from("new")), sharded(test_db).get(&String::from("a/b")).as_deref());
+    }
+
+    fn sorted<T: Ord>(mut v: Vec<T>) -> Vec<T>
+    {
+        v.sort();
+        v
+    }
+
+    #[test]
+    fn mc_keys_iter_and_scan_prefix()
+    {
+        let mc = MemCache::new();
+        for k in &["nutty", "nutmeg", "chipper"]
+        {
+            mc.set(String::from(*k), k.len());
+        }
+        mc.remove(&String::from("nutmeg"));
+
+        assert_eq!(vec!["chipper", "nutty"], sorted(mc.keys()));
+        assert_eq!(vec![(String::from("chipper"), Arc::new(7)), (String::from("nutty"), Arc::new(5))], sorted(mc.iter().collect()));
+        assert_eq!(vec!["nutty"], mc.scan_prefix("nut"));
+        assert!(mc.scan_prefix("blitz").is_empty());
+    }
+
+    #[test]
+    fn dc_keys_finds_records_on_disk()
+    {
+        let dc = fixture_db(Budget::Unbounded);
+
+        let keys: Vec<_> = sorted(dc.keys().into_iter().map(|k| k.k).collect());
+        let mut expected: Vec<_> = (0..8).map(|n| format!("multiple-record-{}", n)).collect();
+        expected.push(String::from("this-record-changes"));
+        expected.push(String::from("this-record-exists"));
+        assert_eq!(expected, keys);
+
+        assert_eq!(8, dc.scan_prefix("multiple-").len());
+        for (_, v) in dc.iter()
+        {
+            assert_eq!(TestVal { v: String::from(TEST_STR) }, *v);
+        }
+
+        // Listing doesn't pull records into the cache
+        assert!(dc.state().cache.is_empty());
+    }
+
+    #[test]
+    fn dc_keys_merges_unpersisted_changes()
+    {
+        let test_db = scratch_db("dc_keys_merges_unpersisted_changes");
+        let val = String::from("bar");
+        let dc = DiskCache::with_budget(test_db.clone(), Budget::Entries(1)).unwrap();
+
+        for k in &["persisted", "Removed", "evicted", "cached"]
+        {
+            dc.set(String::from(*k), val.clone());
+        }
+        dc.persist().unwrap();
+        dc.remove(&String::from("Removed"));
+        dc.set(String::from("new/record"), val.clone());
+
+        // Not a record
+        fs::create_dir(test_db.join("a-directory")).unwrap();
+
+        let expected = vec!["cached", "evicted", "new/record", "persisted"];
+        assert_eq!(expected, sorted(dc.keys()));
+        assert_eq!(
+            expected.iter().map(|k| (String::from(*k), Arc::new(val.clone()))).collect::<Vec<_>>(),
+            sorted(dc.iter().collect())
+        );
+        assert_eq!(vec!["new/record"], dc.scan_prefix("new/"));
+
+        dc.persist().unwrap();
+        drop(dc);
+        let dc = DiskCache::<String, String>::new(test_db).unwrap();
+        assert_eq!(expected, sorted(dc.keys()));
+    }
+
+    #[test]
+    fn dc_persist_clears_dirty_set()
+    {
+        let val = String::from("bar");
+        let dc = DiskCache::new(scratch_db("dc_persist_clears_dirty_set")).unwrap();
+
+        dc.set(String::from("a"), val.clone());
+        dc.set(String::from("b"), val.clone());
+        dc.remove(&String::from("b"));
+        dc.remove(&String::from("never-added"));
+
+        assert_eq!(3, dc.persist().unwrap());
+        assert!(dc.state().disk_update_required.is_empty());
+        assert!(dc.state().removed.is_empty());
+
+        // Nothing left to rewrite
+        assert_eq!(0, dc.persist().unwrap());
+    }
+
+    #[test]
+    fn dc_persist_reports_and_retries_failures()
+    {
+        let (a, b, c) = (String::from("a"), String::from("b"), String::from("c"));
+        let val = String::from("bar");
+        let test_db = scratch_db("dc_persist_reports_and_retries_failures");
+        let dc = DiskCache::new(test_db.clone()).unwrap();
+
+        dc.set(c.clone(), val.clone());
+        dc.persist().unwrap();
+
+        // Neither a file can be renamed over, nor removed, a non-empty directory
+        fs::create_dir_all(test_db.join(&b).join("in-the-way")).unwrap();
+        fs::remove_file(test_db.join(&c)).unwrap();
+        fs::create_dir_all(test_db.join(&c).join("in-the-way")).unwrap();
+
+        dc.set(a.clone(), val.clone());
+        dc.set(b.clone(), val.clone());
+        dc.remove(&c);
+
+        let err = dc.persist().unwrap_err();
+        assert!(err.journal.is_none());
+        let failed: Vec<String> = sorted(err.failed.into_iter().map(|(k, _)| k).collect());
+        assert_eq!(vec![b.clone(), c.clone()], failed);
+
+        assert!(test_db.join(&a).is_file());
+        assert_eq!(
+            vec![b.clone(), c.clone()],
+            sorted(dc.state().disk_update_required.keys().cloned().collect())
+        );
+
+        // Only the failures are still journaled
+        let options = DiskCacheOptions { access: Access::ReadOnly, ..DiskCacheOptions::default() };
+        let reopened = DiskCache::<String, String>::with_options(test_db.clone(), options).unwrap();
+        assert_eq!(
+            vec![b.clone(), c.clone()],
+            sorted(reopened.state().disk_update_required.keys().cloned().collect())
+        );
+        assert!(reopened.state().removed.contains(&c));
+        drop(reopened);
+
+        fs::remove_dir_all(test_db.join(&b)).unwrap();
+        fs::remove_dir_all(test_db.join(&c)).unwrap();
+
+        assert_eq!(2, dc.persist().unwrap());
+        assert!(test_db.join(&b).is_file());
+        assert!(!test_db.join(&c).exists());
+        assert!(dc.state().disk_update_required.is_empty());
+    }
+
+    #[test]
+    fn dc_try_get_distinguishes_missing_from_corrupt()
+    {
+        let test_db = scratch_db("dc_try_get_distinguishes_missing_from_corrupt");
+        let (missing, corrupt) = (String::from("missing"), String::from("corrupt"));
+        fs::write(test_db.join(&corrupt), b"\xff\xff\xff\xff\xff\xff\xff\xff").unwrap();
+
+        let dc = DiskCache::<String, String>::new(test_db).unwrap();
+
+        assert!(matches!(dc.try_get(&missing), Ok(None)));
+        assert!(matches!(dc.try_get(&corrupt), Err(DatabaseError::Corrupt(_))));
+        assert!(!dc.try_contains_key(&missing).unwrap());
+        assert!(dc.try_contains_key(&corrupt).unwrap());
+
+        // The infallible API can't tell them apart
+        assert_eq!(None, dc.get(&missing));
+        assert_eq!(None, dc.get(&corrupt));
+    }
+
+    /// Flip the lowest bit of the last byte of the file at `path`
+    fn rot(path: &Path)
+    {
+        let mut data = fs::read(path).unwrap();
+        *data.last_mut().unwrap() ^= 0x01;
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn dc_detects_records_which_dont_match_their_checksums()
+    {
+        let test_db = scratch_db("dc_detects_records_which_dont_match_their_checksums");
+        let (flipped, truncated) = (String::from("flipped"), String::from("truncated"));
+        let dc = DiskCache::<String, String>::new(test_db.clone()).unwrap();
+        dc.set(flipped.clone(), String::from(TEST_STR));
+        dc.set(truncated.clone(), String::from(TEST_STR));
+        dc.persist().unwrap();
+        drop(dc);
+
+        // Still a valid string, so only the checksum gives it away
+        rot(&test_db.join(&flipped));
+        let data = fs::read(test_db.join(&truncated)).unwrap();
+        fs::write(test_db.join(&truncated), &data[..data.len() - 3]).unwrap();
+
+        let dc = DiskCache::<String, String>::new(test_db).unwrap();
+        assert!(matches!(dc.try_get(&flipped), Err(DatabaseError::Corrupt(_))));
+        assert!(matches!(dc.try_get(&truncated), Err(DatabaseError::Corrupt(_))));
+    }
+
+    /// The name of each file found by a scrub, with what was wrong and done
+    fn findings(report: &ScrubReport) -> Vec<(String, &'static str, &'static str)>
+    {
+        sorted(report.findings
+            .iter()
+            .map(|finding| (
+                finding.path.file_name().unwrap().to_string_lossy().into_owned(),
+                match finding.damage
+                {
+                    Damage::Corrupt(_) =>
+                        "corrupt",
+                    Damage::Orphaned =>
+                        "orphaned",
+                    Damage::Unreadable(_) =>
+                        "unreadable",
+                },
+                match finding.remedy
+                {
+                    Remedy::None =>
+                        "none",
+                    Remedy::Persist =>
+                        "persist",
+                    Remedy::Quarantined(_) =>
+                        "quarantined",
+                }))
+            .collect())
+    }
+
+    #[test]
+    fn dc_scrub_finds_and_quarantines_damage()
+    {
+        let test_db = scratch_db("dc_scrub_finds_and_quarantines_damage");
+        let dc = sharded(test_db.clone());
+        for k in &["fine", "rotten", "cached"]
+        {
+            dc.set(String::from(*k), String::from(TEST_STR));
+        }
+        dc.persist().unwrap();
+        drop(dc);
+
+        let dc = sharded(test_db.clone());
+        let (rotten, cached) = (String::from("rotten"), String::from("cached"));
+        assert!(dc.get(&cached).is_some());
+        rot(&dc.make_path(&rotten));
+        rot(&dc.make_path(&cached));
+        // Written by something newer
+        let newer = dc.make_path(&String::from("newer"));
+        create_shard(&newer).unwrap();
+        fs::write(&newer, plain(1, &bincode::serialize(TEST_STR).unwrap())).unwrap();
+        // Left by an interrupted write, and by a store that wasn't sharded
+        fs::write(temp_path(&dc.make_path(&String::from("fine"))), b"partial").unwrap();
+        fs::write(test_db.join("flat"), plain(0, &bincode::serialize(TEST_STR).unwrap())).unwrap();
+
+        let report = dc.scrub(false).unwrap();
+        assert_eq!(4, report.checked);
+        assert_eq!(vec![
+            (String::from(".fine.tmp"), "orphaned", "none"),
+            (String::from("cached"), "corrupt", "none"),
+            (String::from("flat"), "orphaned", "none"),
+            (String::from("newer"), "unreadable", "none"),
+            (String::from("rotten"), "corrupt", "none"),
+        ], findings(&report));
+        assert!(dc.make_path(&rotten).exists());
+
+        let report = dc.scrub(true).unwrap();
+        assert_eq!(vec![
+            (String::from(".fine.tmp"), "orphaned", "quarantined"),
+            (String::from("cached"), "corrupt", "persist"),
+            (String::from("flat"), "orphaned", "quarantined"),
+            (String::from("newer"), "unreadable", "none"),
+            (String::from("rotten"), "corrupt", "quarantined"),
+        ], findings(&report));
+        for finding in &report.findings
+        {
+            if let Remedy::Quarantined(to) = &finding.remedy
+            {
+                assert!(to.starts_with(test_db.join(".quarantine")));
+                assert!(to.exists());
+                assert!(!finding.path.exists());
+            }
+        }
+        assert!(!dc.contains_key(&rotten));
+
+        // What was cached is rewritten as it was
+        assert_eq!(1, dc.persist().unwrap());
+        drop(dc);
+        let dc = sharded(test_db);
+        assert_eq!(Some(&String::from(TEST_STR)), dc.get(&cached).as_deref());
+        assert_eq!(vec![(String::from("newer"), "unreadable", "none")], findings(&dc.scrub(true).unwrap()));
+    }
+
+    #[test]
+    fn dc_is_only_open_for_writing_once()
+    {
+        let test_db = scratch_db("dc_is_only_open_for_writing_once");
+        let dc = DiskCache::<String, String>::new(test_db.clone()).unwrap();
+
+        match DiskCache::<String, String>::new(test_db.clone())
+        {
+            Err(DatabaseError::Locked { path, pid }) =>
+            {
+                assert_eq!(test_db.join(LOCK_FILE), path);
+                assert_eq!(Some(std::process::id()), pid);
+            },
+            other =>
+                panic!("Opened a store in use: {:?}", other.map(|_| ())),
+        }
+
+        // Released when closed
+        drop(dc);
+        assert!(DiskCache::<String, String>::new(test_db).is_ok());
+    }
+
+    #[test]
+    fn dc_read_only_reads_alongside_a_writer_and_changes_nothing()
+    {
+        let test_db = scratch_db("dc_read_only_reads_alongside_a_writer_and_changes_nothing");
+        let (persisted, journaled) = (String::from("persisted"), String::from("journaled"));
+        let dc = DiskCache::<String, String>::new(test_db.clone()).unwrap();
+        dc.set(persisted.clone(), String::from(TEST_STR));
+        dc.persist().unwrap();
+        dc.set(journaled.clone(), String::from(TEST_STR));
+        // Part way through appending another
+        OpenOptions::new().append(true).open(test_db.join(Journal::FILE_NAME)).unwrap().write_all(&[0xff; 3]).unwrap();
+        let journal = fs::read(test_db.join(Journal::FILE_NAME)).unwrap();
+
+        let options = DiskCacheOptions { access: Access::ReadOnly, ..DiskCacheOptions::default() };
+        let ro = DiskCache::<String, String>::with_options(test_db.clone(), options).unwrap();
+        assert_eq!(Some(&String::from(TEST_STR)), ro.get(&persisted).as_deref());
+        assert_eq!(Some(&String::from(TEST_STR)), ro.get(&journaled).as_deref());
+
+        assert!(matches!(ro.try_set(String::from("new"), String::new()), Err(DatabaseError::ReadOnly)));
+        assert!(matches!(ro.try_remove(&persisted), Err(DatabaseError::ReadOnly)));
+        assert_eq!(None, ro.remove(&persisted));
+        assert!(ro.contains_key(&persisted));
+        assert_eq!(0, ro.persist().unwrap());
+        assert!(ro.scrub(true).is_err());
+
+        assert_eq!(journal, fs::read(test_db.join(Journal::FILE_NAME)).unwrap());
+        assert!(!test_db.join(&journaled).exists());
+        assert_eq!(Some(&String::from(TEST_STR)), dc.get(&journaled).as_deref());
+    }
+
+    #[test]
+    fn dc_read_only_doesnt_create_the_store()
+    {
+        let test_db = scratch_db("dc_read_only_doesnt_create_the_store").join("missing");
+        let options = DiskCacheOptions { access: Access::ReadOnly, ..DiskCacheOptions::default() };
+
+        assert!(DiskCache::<String, String>::with_options(test_db.clone(), options).is_err());
+        assert!(!test_db.exists());
+    }
+
+    #[test]
+    fn dc_try_set_fails_when_journal_cant_be_written()
+    {
+        let test_db = scratch_db("dc_try_set_fails_when_journal_cant_be_written");
+        let key = String::from("a");
+        let val = String::from("bar");
+        let dc = DiskCache::new(test_db.clone()).unwrap();
+        fs::create_dir(test_db.join(Journal::FILE_NAME)).unwrap();
+
+        assert!(matches!(dc.try_set(key.clone(), val.clone()), Err(DatabaseError::Io(_))));
+        assert!(!dc.contains_key(&key));
+
+        // Nor does the infallible API make a change it couldn't journal
+        assert_eq!(None, dc.set(key.clone(), val.clone()));
+        assert!(!dc.contains_key(&key));
+    }
+
+    #[test]
+    fn dc_missing_store_is_an_io_error_not_a_missing_record()
+    {
+        let test_db = scratch_db("dc_missing_store_is_an_io_error_not_a_missing_record");
+        let dc = DiskCache::<String, String>::new(test_db.clone()).unwrap();
+        fs::remove_dir_all(&test_db).unwrap();
+
+        assert!(matches!(dc.try_set(String::from("a"), String::from("b")), Err(DatabaseError::Io(_))));
+        assert!(matches!(dc.try_get(&String::from("c")), Ok(None)));
+    }
+
+    #[test]
+    fn mc_try_methods_never_fail()
+    {
+        let mc = MemCache::new();
+        let key = String::from("a");
+
+        assert!(!mc.try_contains_key(&key).unwrap());
+        assert_eq!(None, mc.try_set(key.clone(), 1).unwrap());
+        assert_eq!(Some(&1), mc.try_get(&key).unwrap().as_deref());
+        assert_eq!(Some(Arc::new(1)), mc.try_remove(&key).unwrap());
+        assert_eq!(None, mc.try_get(&key).unwrap());
+    }
+
+    #[test]
+    fn mc_counts_hits_and_misses()
+    {
+        let mc = MemCache::new();
+        mc.set(String::from("a"), 1);
+        mc.get(&String::from("a"));
+        mc.get(&String::from("b"));
+
+        let stats = mc.stats();
+        assert_eq!((1, 1, 1), (stats.hits, stats.misses, stats.cached));
+        assert_eq!(Some(0.5), stats.hit_rate());
+    }
+
+    #[test]
+    fn dc_counts_hits_misses_and_io()
+    {
+        let test_db = scratch_db("dc_counts_hits_misses_and_io");
+        let dc = DiskCache::<String, String>::new(test_db.clone()).unwrap();
+        assert_eq!(None, dc.stats().hit_rate());
+
+        dc.set(String::from("a"), String::from("acorn"));
+        dc.set(String::from("b"), String::from("beech"));
+        assert_eq!(2, dc.stats().dirty);
+        dc.persist().unwrap();
+
+        let written: u64 = ["a", "b"].iter()
+            .map(|k| fs::metadata(test_db.join(k)).unwrap().len())
+            .sum();
+        let stats = dc.stats();
+        assert_eq!((0, 1, written), (stats.dirty, stats.persists, stats.bytes_written));
+        assert!(stats.max_persist_micros <= stats.persist_micros);
+
+        dc.clear_cache();
+        dc.get(&String::from("a"));
+        dc.get(&String::from("a"));
+        dc.get(&String::from("missing"));
+        dc.remove(&String::from("b"));
+        dc.get(&String::from("b"));
+
+        let stats = dc.stats();
+        assert_eq!((2, 2, 1), (stats.hits, stats.misses, stats.loads));
+        assert_eq!(fs::metadata(test_db.join("a")).unwrap().len(), stats.bytes_read);
+        assert_eq!((1, 1), (stats.cached, stats.dirty));
+        assert_eq!(serialized_size(&String::from("acorn")), stats.cached_bytes);
+    }
+
+    #[test]
+    fn dc_concurrent_readers_and_writers()
+    {
+        let test_db = scratch_db("dc_concurrent_readers_and_writers");
+        let dc = DiskCache::<String, String>::with_budget(test_db.clone(), Budget::Entries(8)).unwrap();
+
+        std::thread::scope(|scope| {
+            for t in 0..4
+            {
+                let dc = &dc;
+                scope.spawn(move || {
+                    for n in 0..50
+                    {
+                        dc.set(format!("writer-{}-{}", t, n % 10), format!("{}", n));
+                        if n % 7 == 0
+                        {
+                            dc.persist().unwrap();
+                        }
+                    }
+                });
+                scope.spawn(move || {
+                    for n in 0..200
+                    {
+                        if let Some(v) = dc.get(&format!("writer-{}-{}", t, n % 10))
+                        {
+                            assert!(v.parse::<u32>().unwrap() < 50);
+                        }
+                    }
+                });
+            }
+        });
+
+        dc.persist().unwrap();
+        drop(dc);
+
+        let dc = DiskCache::<String, String>::new(test_db).unwrap();
+        assert_eq!(40, dc.keys().len());
+        for t in 0..4
+        {
+            for n in 40..50
+            {
+                assert_eq!(
+                    Some(&format!("{}", n)),
+                    dc.get(&format!("writer-{}-{}", t, n % 10)).as_deref()
+                );
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn async_table_runs_operations_on_the_inner_table()
+    {
+        let test_db = scratch_db("async_table_runs_operations_on_the_inner_table");
+        let at = AsyncTable::new(DiskCache::<String, String>::new(test_db.clone()).unwrap());
+        let key = String::from("key");
+
+        assert_eq!(None, at.try_set(key.clone(), String::from("value")).await.unwrap());
+        assert!(at.try_contains_key(key.clone()).await.unwrap());
+        assert_eq!(Some(&String::from("value")), at.try_get(key.clone()).await.unwrap().as_deref());
+
+        assert_eq!(1, at.run(|dc| dc.persist()).await.unwrap().unwrap());
+        assert!(test_db.join("key").exists());
+
+        assert_eq!(Some(&String::from("value")), at.try_remove(key.clone()).await.unwrap().as_deref());
+        assert_eq!(None, at.try_get(key.clone()).await.unwrap());
+        assert_eq!(None, at.inner().get(&key));
+    }
+
+    table_conformance!(mem_cache, |_: &Path| MemCache::new(), durable: false);
+    table_conformance!(disk_cache, |path: &Path| DiskCache::new(path.to_path_buf()).unwrap(), durable: true);
+    table_conformance!(disk_cache_bounded, |path: &Path| DiskCache::with_budget(path.to_path_buf(), Budget::Entries(2)).unwrap(), durable: true);
+    table_conformance!(disk_cache_json, |path: &Path| DiskCache::with_codec(path.to_path_buf(), DiskCacheOptions::default(), Json).unwrap(), durable: true);
+    table_conformance!(disk_cache_cbor, |path: &Path| DiskCache::with_codec(path.to_path_buf(), DiskCacheOptions::default(), Cbor).unwrap(), durable: true);
+    table_conformance!(disk_cache_msgpack, |path: &Path| DiskCache::with_codec(path.to_path_buf(), DiskCacheOptions::default(), MessagePack).unwrap(), durable: true);
+    table_conformance!(disk_cache_sharded, |path: &Path| DiskCache::with_options(path.to_path_buf(), DiskCacheOptions { budget: Budget::Entries(2), layout: Layout::Sharded, ..Default::default() }).unwrap(), durable: true);
+    table_conformance!(sqlite_table, |path: &Path| SqliteTable::open(&path.join("db.sqlite3")).unwrap(), durable: true);
+    table_conformance!(log_table, |path: &Path| LogTable::open(path).unwrap(), durable: true);
+
+    #[test]
+    fn sqlite_try_get_distinguishes_missing_from_corrupt()
+    {
+        let test_db = scratch_db("sqlite_try_get_distinguishes_missing_from_corrupt").join("db.sqlite3");
+        let t = SqliteTable::<String, String>::open(&test_db).unwrap();
+        let (missing, corrupt) = (String::from("missing"), String::from("corrupt"));
+        rusqlite::Connection::open(&test_db).unwrap()
+            .execute("INSERT INTO records (key, value) VALUES ('corrupt', x'ffffffffffffffff')", [])
+            .unwrap();
+
+        assert!(matches!(t.try_get(&missing), Ok(None)));
+        assert!(matches!(t.try_get(&corrupt), Err(DatabaseError::Corrupt(_))));
+        assert!(t.try_contains_key(&corrupt).unwrap());
+        assert_eq!(vec!["corrupt"], t.keys());
+        assert_eq!(0, t.iter().count());
+
+        // Still replaced, or removed
+        assert!(matches!(t.try_set(corrupt.clone(), String::from("mended")), Ok(None)));
+        assert!(t.try_remove(&corrupt).unwrap().is_some());
+    }
+
+    #[test]
+    fn sqlite_try_methods_report_sqlite_errors()
+    {
+        let test_db = scratch_db("sqlite_try_methods_report_sqlite_errors").join("db.sqlite3");
+        let t = SqliteTable::<String, String>::open(&test_db).unwrap();
+        rusqlite::Connection::open(&test_db).unwrap()
+            .execute("DROP TABLE records", [])
+            .unwrap();
+
+        assert!(matches!(t.try_set(String::from("a"), String::from("b")), Err(DatabaseError::Sqlite(_))));
+        assert!(matches!(t.try_remove(&String::from("a")), Err(DatabaseError::Sqlite(_))));
+        assert!(matches!(t.try_keys(), Err(DatabaseError::Sqlite(_))));
+        assert!(matches!(t.try_scan_prefix("a"), Err(DatabaseError::Sqlite(_))));
+        assert!(matches!(t.try_iter(), Err(DatabaseError::Sqlite(_))));
+        assert!(t.keys().is_empty());
+    }
+
+    fn data_files(dir: &Path) -> Vec<String>
+    {
+        sorted(
+            fs::read_dir(dir).unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name.ends_with(".data"))
+                .collect())
+    }
+
+    #[test]
+    fn log_table_compaction_reclaims_dead_records()
+    {
+        let test_db = scratch_db("log_table_compaction_reclaims_dead_records");
+        let lt = LogTable::<String, String>::open(&test_db).unwrap();
+        for n in 0..10
+        {
+            lt.set(format!("record-{}", n % 4), n.to_string());
+        }
+        lt.remove(&String::from("record-3"));
+        assert_eq!(vec!["0.data"], data_files(&test_db));
+
+        let before = fs::metadata(test_db.join("0.data")).unwrap().len();
+        let reclaimed = lt.compact().unwrap();
+        assert!(reclaimed > 0);
+        assert_eq!(0, lt.dead_bytes());
+        assert_eq!(vec!["1.data"], data_files(&test_db));
+        assert_eq!(before - reclaimed, fs::metadata(test_db.join("1.data")).unwrap().len());
+
+        let expected = vec![
+            (String::from("record-0"), Arc::new(String::from("8"))),
+            (String::from("record-1"), Arc::new(String::from("9"))),
+            (String::from("record-2"), Arc::new(String::from("6"))),
+        ];
+        assert_eq!(expected, sorted(lt.iter().collect()));
+
+        // Still writable, and the compacted log is what's opened next time
+        lt.set(String::from("record-3"), String::from("10"));
+        drop(lt);
+        let lt = LogTable::<String, String>::open(&test_db).unwrap();
+        assert_eq!(4, lt.keys().len());
+        assert_eq!(Some(&String::from("10")), lt.get(&String::from("record-3")).as_deref());
+    }
+
+    #[test]
+    fn log_table_compacts_while_being_written()
+    {
+        let test_db = scratch_db("log_table_compacts_while_being_written");
+        let lt = LogTable::<String, String>::open(&test_db).unwrap();
+
+        std::thread::scope(|scope| {
+            let lt = &lt;
+            scope.spawn(move || {
+                for n in 0..200
+                {
+                    lt.set(format!("record-{}", n % 20), n.to_string());
+                }
+            });
+            for _ in 0..5
+            {
+                lt.compact().unwrap();
+            }
+        });
+
+        for n in 180..200
+        {
+            assert_eq!(Some(&n.to_string()), lt.get(&format!("record-{}", n % 20)).as_deref());
+        }
+        drop(lt);
+
+        let lt = LogTable::<String, String>::open(&test_db).unwrap();
+        assert_eq!(Some(&String::from("199")), lt.get(&String::from("record-19")).as_deref());
+        assert_eq!(1, data_files(&test_db).len());
+    }
+
+    #[test]
+    fn log_table_opens_from_hint_and_later_appends()
+    {
+        let test_db = scratch_db("log_table_opens_from_hint_and_later_appends");
+        let lt = LogTable::<String, String>::open(&test_db).unwrap();
+        lt.set(String::from("hinted"), String::from("a"));
+        lt.set(String::from("removed"), String::from("b"));
+        lt.persist().unwrap();
+        assert!(test_db.join("0.hint").exists());
+
+        lt.set(String::from("appended"), String::from("c"));
+        lt.remove(&String::from("removed"));
+        let dead = lt.dead_bytes();
+        drop(lt);
+
+        let lt = LogTable::<String, String>::open(&test_db).unwrap();
+        assert_eq!(vec!["appended", "hinted"], sorted(lt.keys()));
+        assert_eq!(dead, lt.dead_bytes());
+
+        // A hint which doesn't match the log is ignored
+        fs::write(test_db.join("0.hint"), b"nonsense").unwrap();
+        let lt = LogTable::<String, String>::open(&test_db).unwrap();
+        assert_eq!(vec!["appended", "hinted"], sorted(lt.keys()));
+    }
+
+    #[test]
+    fn log_table_drops_torn_append()
+    {
+        let test_db = scratch_db("log_table_drops_torn_append");
+        let lt = LogTable::<String, String>::open(&test_db).unwrap();
+        lt.set(String::from("whole"), String::from("a"));
+        drop(lt);
+
+        let data = test_db.join("0.data");
+        let len = fs::metadata(&data).unwrap().len();
+        let mut f = OpenOptions::new().append(true).open(&data).unwrap();
+        f.write_all(&100u64.to_le_bytes()).unwrap();
+        f.write_all(b"torn").unwrap();
+        drop(f);
+
+        let lt = LogTable::<String, String>::open(&test_db).unwrap();
+        assert_eq!(len, fs::metadata(&data).unwrap().len());
+        assert_eq!(vec!["whole"], lt.keys());
+
+        lt.set(String::from("after"), String::from("b"));
+        drop(lt);
+        let lt = LogTable::<String, String>::open(&test_db).unwrap();
+        assert_eq!(vec!["after", "whole"], sorted(lt.keys()));
+    }
+
+    #[test]
+    fn log_table_drops_garbled_tail()
+    {
+        let test_db = scratch_db("log_table_drops_garbled_tail");
+        let data = test_db.join("0.data");
+        let lt = LogTable::<String, String>::open(&test_db).unwrap();
+        lt.set(String::from("whole"), String::from("a"));
+        drop(lt);
+        let len = fs::metadata(&data).unwrap().len();
+
+        // Whole frames by their length, but not as written
+        let mut garbled = 4u64.to_le_bytes().to_vec();
+        garbled.extend_from_slice(b"\0\0\0\0junk");
+        for tail in &[garbled, vec![0; 64]]
+        {
+            OpenOptions::new().append(true).open(&data).unwrap().write_all(tail).unwrap();
+
+            let lt = LogTable::<String, String>::open(&test_db).unwrap();
+            assert_eq!(len, fs::metadata(&data).unwrap().len());
+            assert_eq!(vec!["whole"], lt.keys());
+        }
+    }
+
+    #[test]
+    fn log_table_drops_torn_batch_whole()
+    {
+        let test_db = scratch_db("log_table_drops_torn_batch_whole");
+        let data = test_db.join("0.data");
+        let lt = LogTable::<String, String>::open(&test_db).unwrap();
+        lt.set(String::from("before"), String::from("a"));
+        let len = fs::metadata(&data).unwrap().len();
+        let mut batch = lt.begin();
+        batch.set(String::from("first"), String::from("b")).remove(String::from("before"));
+        lt.commit(batch).unwrap();
+        let dead = lt.dead_bytes();
+        drop(lt);
+
+        let lt = LogTable::<String, String>::open(&test_db).unwrap();
+        assert_eq!(vec!["first"], lt.keys());
+        assert_eq!(dead, lt.dead_bytes());
+        drop(lt);
 
-impl<K,V> MemCache<K,V>
-    where K: Eq + Hash
-{
-    pub fn new() -> Self
+        let batch_len = fs::metadata(&data).unwrap().len();
+        OpenOptions::new().write(true).open(&data).unwrap().set_len(batch_len - 2).unwrap();
+
+        let lt = LogTable::<String, String>::open(&test_db).unwrap();
+        assert_eq!(len, fs::metadata(&data).unwrap().len());
+        assert_eq!(vec!["before"], lt.keys());
+    }
+
+    fn indexed(base_path: &Path) -> IndexedTable<String, String, DiskCache<String, String>>
     {
-        Self(HashMap::<K,V>::new())
+        let indexes = vec![
+            Index::new("len", |v: &String| v.len().to_string()),
+            Index::new("first", |v: &String| v.chars().take(1).collect()),
+        ];
+
+        IndexedTable::open(DiskCache::new(base_path.to_path_buf()).unwrap(), base_path.join(".indexes"), indexes, &DiskCacheOptions::default())
+            .unwrap()
     }
-}
 
-impl<K,V> Table<K,V> for MemCache<K,V>
-    where K: Eq + Hash
-{
-    fn set(&mut self, k: K, v: V) -> Option<V>
+    fn queried(it: &IndexedTable<String, String, DiskCache<String, String>>, index: &str, term: &str) -> Vec<String>
     {
-        self.0.insert(k,v)
+        sorted(it.query(index, term).unwrap())
     }
 
-    fn get(&mut self, k: &K) -> Option<&V>
+    #[test]
+    fn indexes_follow_changes()
     {
-        self.0.get(k)
+        let test_db = scratch_db("indexes_follow_changes");
+        let it = indexed(&test_db);
+        it.set(String::from("nutty"), String::from("acorn"));
+        it.set(String::from("chipper"), String::from("almond"));
+        it.set(String::from("blitz"), String::from("pecan"));
+        assert_eq!(vec!["chipper", "nutty"], queried(&it, "first", "a"));
+        assert_eq!(vec!["blitz", "nutty"], queried(&it, "len", "5"));
+
+        it.set(String::from("nutty"), String::from("walnut"));
+        it.remove(&String::from("blitz"));
+        let mut batch = it.begin();
+        batch.set(String::from("nutmeg"), String::from("pine")).remove(String::from("chipper"));
+        it.commit(batch).unwrap();
+
+        assert!(queried(&it, "first", "a").is_empty());
+        assert_eq!(vec!["nutty"], queried(&it, "len", "6"));
+        assert_eq!(vec!["nutmeg"], queried(&it, "first", "p"));
+        assert!(matches!(it.query("owner", "nutty"), Err(DatabaseError::NoSuchIndex(_))));
     }
 
-    fn contains_key(&self, k: &K) -> bool
+    #[test]
+    fn indexes_are_saved_until_changed_and_rebuilt_otherwise()
     {
-        self.0.contains_key(k)
+        let test_db = scratch_db("indexes_are_saved_until_changed_and_rebuilt_otherwise");
+        let it = indexed(&test_db);
+        it.set(String::from("nutty"), String::from("acorn"));
+        it.persist().unwrap();
+        assert!(test_db.join(".indexes").exists());
+
+        // Not indexed, so only there if the indexes are rebuilt
+        it.inner().set(String::from("chipper"), String::from("almond"));
+        it.inner().persist().unwrap();
+        drop(it);
+
+        let it = indexed(&test_db);
+        assert_eq!(vec!["nutty"], queried(&it, "first", "a"));
+
+        // A change removes the saved indexes, which are rebuilt if it isn't
+        // persisted
+        it.set(String::from("blitz"), String::from("apple"));
+        assert!(!test_db.join(".indexes").exists());
+        drop(it);
+
+        let it = indexed(&test_db);
+        assert_eq!(vec!["blitz", "chipper", "nutty"], queried(&it, "first", "a"));
+        assert_eq!(3, it.rebuild().unwrap());
     }
 
-    fn remove(&mut self, k: &K) -> Option<V>
+    #[test]
+    fn indexes_which_cant_be_loaded_are_rebuilt()
     {
-        self.0.remove(k)
+        let test_db = scratch_db("indexes_which_cant_be_loaded_are_rebuilt");
+        let it = indexed(&test_db);
+        it.set(String::from("nutty"), String::from("acorn"));
+        it.persist().unwrap();
+        drop(it);
+
+        fs::write(test_db.join(".indexes"), b"nonsense").unwrap();
+        let it = indexed(&test_db);
+        assert_eq!(vec!["nutty"], queried(&it, "first", "a"));
     }
-}
 
-/// A Lazy-Populated Cache of items persisted by the system disk
-pub struct DiskCache<K,V>
-    where K: Eq + Hash + Display,
-          V: DeserializeOwned + Serialize
-{
-    base_path: PathBuf,
-    disk_update_required: HashSet<K>,
-    cache: HashMap<K,V>,
-}
+    #[test]
+    fn blob_store_shares_blobs_by_contents()
+    {
+        let test_db = scratch_db("blob_store_shares_blobs_by_contents");
+        let blobs = BlobStore::new(test_db.join("blobs")).unwrap();
 
-impl<K,V> DiskCache<K,V>
-    where K: Eq + Hash + Display,
-          V: DeserializeOwned + Serialize
-{
-    pub fn new(base_path: PathBuf) -> Self
+        let id = blobs.put(b"data").unwrap();
+        assert_eq!(id, blobs.put(b"data").unwrap());
+        assert_ne!(id, blobs.put(b"other data").unwrap());
+        assert_eq!(2, blobs.refs(&id).unwrap());
+        assert_eq!(Ok(id), id.to_string().parse());
+
+        let (mut f, len) = blobs.open(&id).unwrap();
+        let mut data = Vec::new();
+        io::Read::read_to_end(&mut f, &mut data).unwrap();
+        assert_eq!((4, &b"data"[..]), (len, &data[..]));
+
+        // Counts are kept like any other record
+        blobs.persist().unwrap();
+        drop(blobs);
+        let blobs = BlobStore::new(test_db.join("blobs")).unwrap();
+        assert!(!blobs.release(&id).unwrap());
+        assert!(blobs.contains(&id));
+        assert!(blobs.release(&id).unwrap());
+        assert!(!blobs.contains(&id));
+        assert!(matches!(blobs.open(&id), Err(DatabaseError::NotFound)));
+
+        // Never counted, so left alone
+        assert!(!blobs.release(&id).unwrap());
+    }
+
+    #[test]
+    fn blob_store_gc_recounts_and_removes_orphans()
     {
-        DiskCache
-        {
-            base_path,
-            disk_update_required: HashSet::<K>::new(),
-            cache: HashMap::<K,V>::new(),
-        }
+        let test_db = scratch_db("blob_store_gc_recounts_and_removes_orphans");
+        let blobs = BlobStore::new(test_db.join("blobs")).unwrap();
+
+        let kept = blobs.put(b"kept").unwrap();
+        let orphan = blobs.put(b"orphan").unwrap();
+        let undercounted = BlobId::of(b"undercounted");
+        fs::write(test_db.join("blobs").join(".sha256").join(undercounted.to_string()), b"undercounted").unwrap();
+        fs::write(test_db.join("blobs").join(".sha256").join(".interrupted.tmp"), b"").unwrap();
+
+        assert_eq!(2, blobs.gc(vec![kept, kept, undercounted]).unwrap());
+        assert_eq!(2, blobs.refs(&kept).unwrap());
+        assert_eq!(1, blobs.refs(&undercounted).unwrap());
+        assert_eq!(0, blobs.refs(&orphan).unwrap());
+        assert!(blobs.contains(&undercounted));
+        assert!(!blobs.contains(&orphan));
+        assert_eq!(0, blobs.gc(vec![kept, kept, undercounted]).unwrap());
     }
 
-    pub fn persist(&self) -> Result<(), Box<dyn Error>>
+    #[test]
+    fn rewrite_records_replaces_only_what_it_is_given()
     {
-        for k in self.disk_update_required.iter()
+        let test_db = scratch_db("rewrite_records_replaces_only_what_it_is_given");
+        let dc = DiskCache::new(test_db.clone()).unwrap();
+        for k in &["old", "new", "a/b"]
         {
-            if let Some(v) = self.cache.get(k)
-            {
-                // Update record
-                let fpath = self.make_path(k);
-                let mut f = File::create(fpath)?;
+            dc.set(String::from(*k), String::from("old"));
+        }
+        dc.persist().unwrap();
+        drop(dc);
 
-                let fdata = bincode::serialize(v)?;
-                f.write(&fdata)?;
-            }
-            else if self.is_on_disk(k)
-            {
-                // Remove record
-                fs::remove_file(self.make_path(k))?;
-            }
-            else
+        let mut seen = Vec::new();
+        let rewritten = rewrite_records(&test_db, |key, record| {
+            seen.push(String::from(key));
+            let v: String = bincode::deserialize(record::decode(record).unwrap().1).unwrap();
+            match key
             {
-                // A double remove perhaps?
+                "new" =>
+                    Ok(None),
+                _ =>
+                    Ok(Some(bincode::serialize(&format!("{}-{}", key, v)).unwrap())),
             }
-        }
+        });
+        assert_eq!(2, rewritten.unwrap());
+        assert_eq!(vec!["a/b", "new", "old"], sorted(seen));
 
-        Ok(())
+        let dc = DiskCache::<String, String>::new(test_db).unwrap();
+        assert_eq!(Some(&String::from("a/b-old")), dc.get(&String::from("a/b")).as_deref());
+        assert_eq!(Some(&String::from("old")), dc.get(&String::from("new")).as_deref());
+        assert_eq!(Some(&String::from("old-old")), dc.get(&String::from("old")).as_deref());
     }
 
-    fn make_path(&self, k: &K) -> PathBuf
+    /// `body` behind a header saying it's in `schema`, and nothing else.
+    fn plain(schema: u32, body: &[u8]) -> Vec<u8>
     {
-        let mut path = self.base_path.clone();
-        path.push(k.to_string());
-
-        path
+        record::encode(record::Header { schema, flags: 0 }, body)
     }
 
-    fn is_on_disk(&self, k: &K) -> bool
+    /// What `record::decode` gives for `body`, in `schema`, without flags.
+    fn decoded(schema: u32, body: &[u8]) -> (record::Header, &[u8])
     {
-        self.make_path(k).exists()
+        (record::Header { schema, flags: 0 }, body)
     }
 
-    fn get_from_disk(&self, k: &K) -> Result<Box<V>, Box<dyn Error>>
+    #[test]
+    fn records_have_a_header_unless_written_before_there_were_any()
     {
-        let path = self.make_path(k);
-
-        let file = File::open(path)?;
-        let data: V = bincode::deserialize_from(file)?;
+        let data = plain(3, b"body");
+        assert_eq!(b"IFR\x03\x03\0\0\0\0\0\0\0\xb2\x0b\xa8\xdbbody", &data[..]);
+        assert_eq!(decoded(3, b"body"), record::decode(&data).unwrap());
 
-        Ok(Box::new(data))
+        // Before records had flags
+        assert_eq!(decoded(3, b"body"), record::decode(b"IFR\x02\x03\0\0\0\xb2\x0b\xa8\xdbbody").unwrap());
+        // Before records had checksums
+        assert_eq!(decoded(3, b"body"), record::decode(b"IFR\x01\x03\0\0\0body").unwrap());
+        // Before records had headers at all
+        assert_eq!(decoded(0, b"body"), record::decode(b"body").unwrap());
+        assert_eq!(decoded(0, b"IFR\x01\x03"), record::decode(b"IFR\x01\x03").unwrap());
     }
-}
 
-impl<K,V> Table<K,V> for DiskCache<K,V>
-    where K: Clone + Display + Eq + Hash,
-          V: DeserializeOwned + Serialize
-{
-    fn set(&mut self, k: K, v: V) -> Option<V>
+    #[test]
+    fn records_which_dont_match_their_checksums_are_corrupt()
     {
-        self.disk_update_required.insert(k.clone());
-        self.cache.insert(k,v)
+        let mut data = plain(0, b"body");
+        data[16] ^= 0x20;
+        assert!(record::decode(&data).is_err());
+        assert!(record::decode(&plain(0, b"body")[..16 + 2]).is_err());
+        assert!(record::decode(&plain(0, b"body")[..6]).is_err());
+        assert!(record::decode(b"IFR\x02\x03\0\0\0\xb2\x0b\xa8\xdbBody").is_err());
     }
 
-    fn get(&mut self, k: &K) -> Option<&V>
+    #[test]
+    fn records_with_unknown_flags_are_refused()
     {
-        if self.cache.contains_key(k)
-        {
-            self.cache.get(k)
-        }
-        else if let Ok(boxed_v) = self.get_from_disk(k)
-        {
-            self.cache.insert((*k).clone(), *boxed_v);
-            self.cache.get(k)
-        }
-        else
-        {
-            None
-        }
+        let data = record::encode(record::Header { schema: 0, flags: 0x80 }, b"body");
+        assert!(record::decode(&data).is_err());
     }
 
-    fn contains_key(&self, k: &K) -> bool
+    /// Records of `String`s, once stored as their lengths
+    fn string_migrations() -> Migrations
     {
-        self.cache.contains_key(k) ||
-        self.is_on_disk(k)
+        Migrations::new(2)
+            .step(0, |_, body| Ok(bincode::serialize(&bincode::deserialize::<u64>(body)?.to_string())?))
+            .step(1, |key, body| Ok(bincode::serialize(&format!("{}:{}", key, bincode::deserialize::<String>(body)?))?))
     }
 
-    fn remove(&mut self, k: &K) -> Option<V>
+    #[test]
+    fn migrations_upgrade_a_step_at_a_time()
     {
-        self.disk_update_required.insert((*k).clone());
-        self.cache.remove(k)
-    }
-}
+        let migrations = string_migrations();
+        let upgraded = migrations.upgrade("k", 0, &bincode::serialize(&3u64).unwrap()).unwrap();
+        assert_eq!("k:3", bincode::deserialize::<String>(&upgraded).unwrap());
 
-#[cfg(test)]
-mod test
-{
-    use super::*;
+        let current = bincode::serialize("k:3").unwrap();
+        assert_eq!(current, migrations.upgrade("k", 2, &current).unwrap());
 
-    #[derive(Clone, Hash, Eq, PartialEq)]
-    struct TestKey { k: String }
+        assert!(matches!(migrations.upgrade("k", 3, &current),
+                         Err(DatabaseError::UnsupportedSchema { found: 3, current: 2 })));
+        assert!(matches!(Migrations::new(2).upgrade("k", 0, &current),
+                         Err(DatabaseError::UnsupportedSchema { found: 0, current: 2 })));
+        assert!(matches!(migrations.upgrade("k", 1, b"\xff"), Err(DatabaseError::Corrupt(_))));
+    }
 
-    impl Display for TestKey
+    #[test]
+    fn dc_upgrades_old_records_as_they_are_read()
     {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
-        {
-            write!(f, "{}", self.k)
-        }
+        let test_db = scratch_db("dc_upgrades_old_records_as_they_are_read");
+        fs::write(test_db.join("old"), bincode::serialize(&3u64).unwrap()).unwrap();
+        fs::write(test_db.join("newer"), plain(3, &bincode::serialize("?").unwrap())).unwrap();
+        let options = DiskCacheOptions { migrations: string_migrations(), ..Default::default() };
+
+        let dc = DiskCache::<String, String>::with_options(test_db.clone(), options.clone()).unwrap();
+        assert_eq!(Some(&String::from("old:3")), dc.get(&String::from("old")).as_deref());
+        assert!(matches!(dc.try_get(&String::from("newer")),
+                         Err(DatabaseError::UnsupportedSchema { found: 3, current: 2 })));
+
+        // Written back as it now is
+        assert_eq!(1, dc.persist().unwrap());
+        assert_eq!(plain(2, &bincode::serialize("old:3").unwrap()), fs::read(test_db.join("old")).unwrap());
     }
 
-    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
-    struct TestVal { v: String }
+    #[test]
+    fn upgrade_records_upgrades_what_it_can()
+    {
+        let test_db = scratch_db("upgrade_records_upgrades_what_it_can");
+        fs::write(test_db.join("old"), bincode::serialize(&3u64).unwrap()).unwrap();
+        fs::write(test_db.join("bad"), plain(1, b"\xff")).unwrap();
+        let current = plain(2, &bincode::serialize("current").unwrap());
+        fs::write(test_db.join("current"), &current).unwrap();
 
-    const TEST_STR: &str = "~~~~😸 + 🦀 = 🎇~~~~";
+        let options = DiskCacheOptions { migrations: string_migrations(), ..Default::default() };
+        assert_eq!((1, 1), upgrade_records(&test_db, &options).unwrap());
+        assert_eq!(plain(2, &bincode::serialize("old:3").unwrap()), fs::read(test_db.join("old")).unwrap());
+        assert_eq!(plain(1, b"\xff"), fs::read(test_db.join("bad")).unwrap());
+        assert_eq!(current, fs::read(test_db.join("current")).unwrap());
+    }
 
-   // #[test]
-   // fn create_test_records()
-   // {
-   //     // For creating any needed test resources uncomment this fn
-   //     let mut base_path = std::env::current_dir().unwrap();
-   //     base_path.push("database-test-db");
+    #[test]
+    fn dc_refuses_a_journal_of_another_schema()
+    {
+        let test_db = scratch_db("dc_refuses_a_journal_of_another_schema");
+        let dc = DiskCache::<String, u64>::new(test_db.clone()).unwrap();
+        dc.set(String::from("k"), 3);
+        drop(dc);
 
-   //     let mut dc = DiskCache::<TestKey, TestVal>::new(base_path);
-   //     for n in 0..8
-   //     {
-   //         let key = TestKey { k: String::from(format!("multiple-record-{}", n)) };
-   //         let val = TestVal { v: String::from(TEST_STR)};
-   //         dc.set(key, val);
-   //     }
+        let options = DiskCacheOptions { migrations: string_migrations(), ..Default::default() };
+        assert!(matches!(DiskCache::<String, String>::with_options(test_db.clone(), options.clone()),
+                         Err(DatabaseError::UnsupportedSchema { found: 0, current: 2 })));
 
-   //     dc.persist().unwrap();
+        // Once it's persisted, there's nothing to misread
+        DiskCache::<String, u64>::new(test_db.clone()).unwrap().persist().unwrap();
+        let dc = DiskCache::<String, String>::with_options(test_db, options).unwrap();
+        assert_eq!(Some(&String::from("k:3")), dc.get(&String::from("k")).as_deref());
+    }
 
-   //     panic!()
-   // }
-   //
+    const KEY_A: [u8; 32] = [0xaa; 32];
+    const KEY_B: [u8; 32] = [0xbb; 32];
 
-    fn scrub_a_dub(record: &PathBuf)
+    fn keyring(keys: &[[u8; 32]]) -> Option<Arc<Keyring>>
     {
-        // Clean up created resources if present
-        match fs::remove_file(record)
-        {
-            Ok(_) => {},
-            Err(_) => {},
-        };
+        Some(Arc::new(Keyring::new(keys.to_vec()).unwrap()))
+    }
 
-       assert!(!record.exists());
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool
+    {
+        haystack.windows(needle.len()).any(|window| window == needle)
     }
 
     #[test]
-    fn add_contains_rm_dc_sanity()
+    fn indexes_are_encrypted_with_the_store()
     {
-        let foo = String::from("this-record-not-persisted");
-        let bar = String::from("Hello World!");
-        let mut dc = DiskCache::new(PathBuf::new());
+        let test_db = scratch_db("indexes_are_encrypted_with_the_store");
+        let options = DiskCacheOptions { keyring: keyring(&[KEY_A]), ..Default::default() };
+        let open = |options: &DiskCacheOptions| {
+            let dc = DiskCache::<String, String>::with_options(test_db.clone(), options.clone()).unwrap();
+            IndexedTable::open(dc, test_db.join(".indexes"), vec![Index::new("v", String::clone)], options)
+        };
 
-        assert!(!dc.contains_key(&foo));
-        assert_eq!(dc.set(foo.clone(), bar.clone()), None);
-        assert!(dc.contains_key(&foo));
-        assert_eq!(dc.remove(&foo), Some(bar));
-        assert!(!dc.contains_key(&foo));
+        let it = open(&options).unwrap();
+        it.set(String::from("nutty"), String::from("acorn"));
+        it.persist().unwrap();
+        drop(it);
+        assert!(!contains(&fs::read(test_db.join(".indexes")).unwrap(), b"acorn"));
+
+        let it = open(&options).unwrap();
+        assert_eq!(vec![String::from("nutty")], it.query("v", "acorn").unwrap());
     }
 
     #[test]
-    fn dc_contains_key_finds_records_on_disk()
+    fn keyring_loads_keys_from_a_file()
     {
-        let mut base_path = std::env::current_dir().unwrap();
-        base_path.push("database-test-db");
-        let dc = DiskCache::<TestKey, TestVal>::new(base_path);
+        let test_db = scratch_db("keyring_loads_keys_from_a_file");
+        let path = test_db.join("keys");
+        let lines = format!("# Old\n{}\n\n  {}  \n", base64::encode(KEY_A), base64::encode(KEY_B));
+        fs::write(&path, lines).unwrap();
 
-        let key = TestKey { k: String::from("this-record-exists") };
+        let keys = Keyring::load(&path).unwrap();
+        assert_eq!(Keyring::new(vec![KEY_B]).unwrap().current(), keys.current());
+        assert_ne!(Keyring::new(vec![KEY_A]).unwrap().current(), keys.current());
+        assert!(!format!("{:?}", keys).contains(&base64::encode(KEY_B)));
 
-        assert!(!dc.cache.contains_key(&key));
-        assert!(dc.contains_key(&key));
+        fs::write(&path, base64::encode(&KEY_A[..16])).unwrap();
+        assert!(Keyring::load(&path).is_err());
+        fs::write(&path, "# None yet\n").unwrap();
+        assert!(Keyring::load(&path).is_err());
     }
 
     #[test]
-    fn dc_adds_extant_record()
+    fn dc_encrypts_records_and_journal()
     {
-        let mut base_path = std::env::current_dir().unwrap();
-        base_path.push("database-test-db");
-        let mut dc = DiskCache::<TestKey, TestVal>::new(base_path);
+        let test_db = scratch_db("dc_encrypts_records_and_journal");
+        let options = DiskCacheOptions { keyring: keyring(&[KEY_A]), ..Default::default() };
+        let dc = DiskCache::<String, String>::with_options(test_db.clone(), options.clone()).unwrap();
+        dc.set(String::from("k"), String::from(TEST_STR));
+        dc.set(String::from("other"), String::from("other"));
+        assert!(!contains(&fs::read(test_db.join(".journal")).unwrap(), TEST_STR.as_bytes()));
+        drop(dc);
 
-        let key = TestKey { k: String::from("this-record-exists") };
+        // Replayed from the journal
+        let dc = DiskCache::<String, String>::with_options(test_db.clone(), options.clone()).unwrap();
+        assert_eq!(Some(&String::from(TEST_STR)), dc.get(&String::from("k")).as_deref());
+        assert_eq!(2, dc.persist().unwrap());
+        let data = fs::read(test_db.join("k")).unwrap();
+        assert!(!contains(&data, TEST_STR.as_bytes()));
+        assert_eq!(record::SEALED, record::decode(&data).unwrap().0.flags);
+        drop(dc);
 
-        assert!(!dc.cache.contains_key(&key));
-        assert_eq!(
-            &TestVal { v: String::from(TEST_STR) },
-            dc.get(&key).unwrap()
-        );
+        // Once encrypted, never opened without the keys
+        assert!(matches!(DiskCache::<String, String>::new(test_db.clone()),
+                         Err(DatabaseError::MissingKey { id: None })));
 
-        assert_eq!(1, dc.cache.len());
-        assert!(dc.cache.contains_key(&key));
+        // Nor can one record be passed off as another
+        fs::copy(test_db.join("k"), test_db.join("other")).unwrap();
+        let dc = DiskCache::<String, String>::with_options(test_db, options).unwrap();
+        assert_eq!(Some(&String::from(TEST_STR)), dc.get(&String::from("k")).as_deref());
+        assert!(matches!(dc.try_get(&String::from("other")), Err(DatabaseError::Corrupt(_))));
+        assert_eq!(1, dc.scrub(false).unwrap().findings.len());
     }
 
     #[test]
-    fn dc_adds_multiple_extant_record()
+    fn dc_reencrypts_under_the_current_key()
     {
-        let mut base_path = std::env::current_dir().unwrap();
-        base_path.push("database-test-db");
-        let mut dc = DiskCache::<TestKey, TestVal>::new(base_path);
+        let test_db = scratch_db("dc_reencrypts_under_the_current_key");
+        let dc = DiskCache::<String, String>::new(test_db.clone()).unwrap();
+        dc.set(String::from("plain"), String::from("plain"));
+        dc.persist().unwrap();
+        drop(dc);
 
-        let mut keys = Vec::with_capacity(8);
-        for n in 0..8
-        {
-            let key = TestKey { k: String::from(format!("multiple-record-{}", n)) };
-            keys.push(key);
-        }
+        let with_a = DiskCacheOptions { keyring: keyring(&[KEY_A]), ..Default::default() };
+        let dc = DiskCache::<String, String>::with_options(test_db.clone(), with_a.clone()).unwrap();
+        dc.set(String::from("a"), String::from("a"));
+        dc.persist().unwrap();
+        drop(dc);
 
-        for key in &keys
-        {
-            dc.get(key);
-        }
+        let only_b = DiskCacheOptions { keyring: keyring(&[KEY_B]), ..Default::default() };
+        let id_a = Keyring::new(vec![KEY_A]).unwrap().current();
+        let dc = DiskCache::<String, String>::with_options(test_db.clone(), only_b.clone()).unwrap();
+        assert!(matches!(dc.try_get(&String::from("a")), Err(DatabaseError::MissingKey { id: Some(id) }) if id == id_a));
+        let report = dc.scrub(false).unwrap();
+        assert!(matches!(report.findings[..], [Finding { damage: Damage::Unreadable(_), .. }]));
+        drop(dc);
 
-        assert_eq!(8, dc.cache.len());
+        let dc = DiskCache::<String, String>::with_options(test_db.clone(), with_a).unwrap();
+        dc.set(String::from("journaled"), String::from("journaled"));
+        drop(dc);
 
-        for key in &keys
+        // Rather than dropping what it can't read from the journal
+        assert!(matches!(DiskCache::<String, String>::with_options(test_db.clone(), only_b.clone()),
+                         Err(DatabaseError::MissingKey { id: Some(id) }) if id == id_a));
+
+        // Rotated: B is added, and everything re-encrypted under it
+        let both = DiskCacheOptions { keyring: keyring(&[KEY_A, KEY_B]), ..Default::default() };
+        let dc = DiskCache::<String, String>::with_options(test_db.clone(), both).unwrap();
+        assert_eq!((2, 0), dc.reencrypt().unwrap());
+        assert_eq!((0, 0), dc.reencrypt().unwrap());
+        drop(dc);
+
+        let dc = DiskCache::<String, String>::with_options(test_db, only_b).unwrap();
+        for k in ["plain", "a", "journaled"]
         {
-            assert!(dc.contains_key(&key));
-            assert_eq!(
-                &TestVal { v: String::from(TEST_STR) },
-                dc.get(&key).unwrap()
-            );
+            assert_eq!(Some(&String::from(k)), dc.get(&String::from(k)).as_deref());
         }
     }
 
     #[test]
-    fn dc_doesnt_add_non_extant_record()
+    fn blob_store_encrypts_and_reencrypts_blobs()
     {
-        let mut base_path = std::env::current_dir().unwrap();
-        base_path.push("database-test-db");
-        let key = TestKey { k: String::from("this-record-does-not-exist") };
-        let mut dc = DiskCache::<TestKey, TestVal>::new(base_path);
-        dc.get(&key);
+        let test_db = scratch_db("blob_store_encrypts_and_reencrypts_blobs");
+        let base_path = test_db.join("blobs");
+        let plain = BlobStore::new(base_path.clone()).unwrap().put(b"plain data").unwrap();
 
-        assert!(!dc.contains_key(&key));
-    }
+        let blobs = BlobStore::with_options(base_path.clone(), DiskCacheOptions { keyring: keyring(&[KEY_A]), ..Default::default() }).unwrap();
+        let sealed = blobs.put(b"sealed data").unwrap();
+        // Not named by its id, which anyone could work out from the data
+        assert!(!base_path.join(".sha256").join(sealed.to_string()).exists());
+        for entry in fs::read_dir(base_path.join(".sha256")).unwrap()
+        {
+            assert!(!contains(&fs::read(entry.unwrap().path()).unwrap(), b"sealed data"));
+        }
+        assert_eq!(b"plain data", &blobs.read(&plain).unwrap()[..]);
 
-    #[test]
-    fn dc_persists_new_records_to_disk()
-    {
-        let key = String::from("a-new-record");
-        let val = String::from("bar");
-        let mut test_db = std::env::current_dir().unwrap();
-        test_db.push("database-test-db");
+        let (mut f, len) = blobs.open(&sealed).unwrap();
+        let mut data = Vec::new();
+        io::Read::read_to_end(&mut f, &mut data).unwrap();
+        assert_eq!((11, &b"sealed data"[..]), (len, &data[..]));
+        drop(blobs);
 
-        let mut record = test_db.clone();
-        let mut dc = DiskCache::new(test_db);
-        record.push(&key);
+        let blobs = BlobStore::with_options(base_path.clone(), DiskCacheOptions { keyring: keyring(&[KEY_A, KEY_B]), ..Default::default() }).unwrap();
+        assert_eq!((2, 0), blobs.reencrypt().unwrap());
+        assert_eq!((0, 0), blobs.reencrypt().unwrap());
+        assert!(!base_path.join(".sha256").join(plain.to_string()).exists());
+        assert_eq!(1, blobs.refs(&plain).unwrap());
+        drop(blobs);
 
-        // Records should not be present before starting
-        // or else this test is meaningless
-        scrub_a_dub(&record);
+        let blobs = BlobStore::with_options(base_path, DiskCacheOptions { keyring: keyring(&[KEY_B]), ..Default::default() }).unwrap();
+        assert_eq!(b"plain data", &blobs.read(&plain).unwrap()[..]);
+        assert_eq!(b"sealed data", &blobs.read(&sealed).unwrap()[..]);
+        assert_eq!(0, blobs.gc([plain, sealed]).unwrap());
+        assert!(blobs.release(&plain).unwrap());
+        assert!(!blobs.contains(&plain));
+    }
 
-        dc.set(key.clone(), val.clone());
-        dc.persist().unwrap();
+    #[test]
+    fn records_are_compressed_only_when_it_shrinks_them()
+    {
+        let body = TEST_STR.repeat(64).into_bytes();
+        for (compression, flag) in [(Compression::Lz4, record::LZ4), (Compression::Zstd(3), record::ZSTD)]
+        {
+            let data = record::pack("k", 1, &body, compression, None).unwrap();
+            assert_eq!(flag, record::decode(&data).unwrap().0.flags);
+            assert!(data.len() < body.len() / 4);
+            let (schema, unpacked) = record::unpack("k", &data, None).unwrap();
+            assert_eq!((1, &body[..]), (schema, &unpacked[..]));
 
-        // Records should be present after persist operation
-        assert!(record.exists());
+            // Left alone when it wouldn't be any smaller
+            assert_eq!(plain(1, b"tiny"), record::pack("k", 1, b"tiny", compression, None).unwrap());
+        }
 
-        // Clean Up
-        scrub_a_dub(&record);
+        let keyring = keyring(&[KEY_A]);
+        let data = record::pack("k", 1, &body, Compression::Zstd(3), keyring.as_deref()).unwrap();
+        assert_eq!(record::SEALED | record::ZSTD, record::decode(&data).unwrap().0.flags);
+        assert_eq!(&body[..], &record::unpack("k", &data, keyring.as_deref()).unwrap().1[..]);
     }
 
     #[test]
-    fn dc_persist_removes_from_disk()
+    fn dc_reads_records_however_they_were_compressed()
     {
-        let key = String::from("a-record-on-disk");
-        let val = String::from("bar");
-        let mut test_db = std::env::current_dir().unwrap();
-        test_db.push("database-test-db");
+        let test_db = scratch_db("dc_reads_records_however_they_were_compressed");
+        let value = TEST_STR.repeat(64);
+        for (n, compression) in [Compression::None, Compression::Lz4, Compression::Zstd(3)].iter().copied().enumerate()
+        {
+            let options = DiskCacheOptions { compression, ..Default::default() };
+            let dc = DiskCache::<String, String>::with_options(test_db.clone(), options).unwrap();
+            dc.set(n.to_string(), value.clone());
+            dc.persist().unwrap();
+            drop(dc);
+        }
+        assert!(fs::metadata(test_db.join("2")).unwrap().len() < fs::metadata(test_db.join("0")).unwrap().len() / 4);
 
-        let mut record = test_db.clone();
-        let mut dc = DiskCache::new(test_db);
-        record.push(&key);
+        let dc = DiskCache::<String, String>::new(test_db).unwrap();
+        for n in 0..3
+        {
+            assert_eq!(Some(&value), dc.get(&n.to_string()).as_deref());
+        }
+    }
 
-        // Set initial value on disk
-        scrub_a_dub(&record);
-        dc.set(key.clone(), val.clone());
-        dc.persist().unwrap();
+    #[test]
+    fn blob_store_compresses_blobs_which_shrink()
+    {
+        let test_db = scratch_db("blob_store_compresses_blobs_which_shrink");
+        let base_path = test_db.join("blobs");
+        let options = DiskCacheOptions { compression: Compression::Lz4, ..Default::default() };
+        let blobs = BlobStore::with_options(base_path.clone(), options).unwrap();
 
-        dc.cache.clear();
+        let bitmap = vec![0x42; 64 * 1024];
+        let compressed = blobs.put(&bitmap).unwrap();
+        let tiny = blobs.put(b"tiny").unwrap();
+        let stored = |id: &BlobId| fs::read(base_path.join(".sha256").join(id.to_string())).unwrap();
+        assert!(stored(&compressed).len() < bitmap.len() / 16);
+        assert_eq!(b"tiny", &stored(&tiny)[..]);
 
-        // Record should not be present on disk
-        // after remove & persist
-        dc.remove(&key);
-        dc.persist().unwrap();
-        assert!(!record.exists());
+        let (mut f, len) = blobs.open(&compressed).unwrap();
+        let mut data = Vec::new();
+        io::Read::read_to_end(&mut f, &mut data).unwrap();
+        assert_eq!((bitmap.len() as u64, &bitmap[..]), (len, &data[..]));
+        drop(blobs);
 
-        // Clean Up
-        scrub_a_dub(&record);
+        let blobs = BlobStore::new(base_path).unwrap();
+        assert_eq!(bitmap, blobs.read(&compressed).unwrap());
+        assert_eq!(b"tiny", &blobs.read(&tiny).unwrap()[..]);
     }
 
+    /// Compares read throughput of a store shared behind one big lock (as the
+    /// server used to) with the store shared directly. Run with:
+    /// `cargo test --release bench_concurrent_reads -- --ignored --nocapture`
+    #[ignore]
     #[test]
-    fn dc_persist_updates_existing_record()
+    fn bench_concurrent_reads()
     {
-        let key = String::from("a-record-to-update");
-        let val0 = String::from("bar");
-        let val1 = String::from("baz");
-        let mut test_db = std::env::current_dir().unwrap();
-        test_db.push("database-test-db");
-
-        let mut record = test_db.clone();
-        let mut dc = DiskCache::new(test_db);
-        record.push(&key);
-
-        // Records should not be present before starting
-        scrub_a_dub(&record);
+        const RECORDS: usize = 256;
+        const THREADS: usize = 8;
+        const READS: usize = 2000;
 
-        // Set initial value on disk
-        dc.set(key.clone(), val0.clone());
+        let test_db = scratch_db("bench_concurrent_reads");
+        let dc = DiskCache::new(test_db.clone()).unwrap();
+        for n in 0..RECORDS
+        {
+            dc.set(format!("record-{}", n), vec![n as u8; 64 * 1024]);
+        }
         dc.persist().unwrap();
-        assert!(record.exists());
+        drop(dc);
 
-        dc.cache.clear();
+        // Half the records fit in memory, so about half the reads go to disk
+        let budget = Budget::Entries(RECORDS / 2);
+        let read_all = |get: &(dyn Fn(&String) -> Option<Arc<Vec<u8>>> + Sync)| {
+            let start = std::time::Instant::now();
+            std::thread::scope(|scope| {
+                for t in 0..THREADS
+                {
+                    scope.spawn(move || {
+                        for n in 0..READS
+                        {
+                            let k = format!("record-{}", (n * 31 + t * 17) % RECORDS);
+                            assert!(get(&k).is_some());
+                        }
+                    });
+                }
+            });
 
-        // Set updated value on disk
-        dc.remove(&key);
-        dc.set(key.clone(), val1.clone());
-        dc.persist().unwrap();
+            (THREADS * READS) as f64 / start.elapsed().as_secs_f64()
+        };
 
-        dc.cache.clear();
+        let locked = Mutex::new(DiskCache::<String, Vec<u8>>::with_budget(test_db.clone(), budget).unwrap());
+        let before = read_all(&|k| lock(&locked).get(k));
 
-        assert!(record.exists());
-        assert_eq!(
-            &val1,
-            dc.get(&key).unwrap()
-        );
+        let shared = DiskCache::<String, Vec<u8>>::with_budget(test_db, budget).unwrap();
+        let after = read_all(&|k| shared.get(k));
 
-        // Clean Up
-        scrub_a_dub(&record);
+        println!("Global lock: {:>10.0} reads/s", before);
+        println!("Shared:      {:>10.0} reads/s ({:.1}x)", after, after / before);
     }
 }
@@ -0,0 +1,672 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{ self, File, OpenOptions, };
+use std::hash::Hash;
+use std::io::{ self, BufWriter, Write, };
+use std::marker::PhantomData;
+use std::path::{ Path, PathBuf, };
+use std::sync::{ Arc, Mutex, RwLock, };
+
+use serde::{ Deserialize, Serialize, };
+use serde::de::DeserializeOwned;
+
+use super::{
+    lock, read, write, sync_dir, temp_path, write_atomic,
+    Batch, BatchOp, Bincode, Codec, DatabaseError, JournalEntry, PersistError, Table,
+};
+
+const LEN_PREFIX: u64 = std::mem::size_of::<u64>() as u64;
+/// Each frame starts with the length of its body, then a CRC-32 of it.
+const HEADER: u64 = LEN_PREFIX + std::mem::size_of::<u32>() as u64;
+
+/// Space taken by overwritten and removed records before `persist`
+/// compacts the log.
+const COMPACT_MIN_DEAD: u64 = 1024 * 1024;
+
+/// A Bitcask-style table. Every change is appended to a single data file,
+/// and an in-memory index holds where the latest version of each record
+/// starts. Only the index is kept in memory.
+///
+/// The index is saved to a hint file by `persist`, so it can be loaded
+/// quickly on open, rather than rebuilt by scanning the whole log.
+/// `compact` rewrites the log without the records which have been
+/// overwritten or removed.
+pub struct LogTable<K,V>
+{
+    dir: PathBuf,
+    /// Lock order is `compacting`, then `appending`, then `state`
+    compacting: Mutex<()>,
+    appending: Mutex<()>,
+    state: RwLock<LogState<K>>,
+    // Values are only stored encoded, never held
+    types: PhantomData<fn() -> V>,
+}
+
+struct LogState<K>
+{
+    /// Bumped by each compaction, which starts a new data file
+    generation: u64,
+    file: File,
+    len: u64,
+    index: HashMap<K, Location>,
+    /// Bytes of the log a compaction would reclaim
+    dead: u64,
+}
+
+/// Where a record's frame is in the data file.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+struct Location
+{
+    offset: u64,
+    len: u64,
+}
+
+/// The index as of `data_len` bytes into the data file.
+#[derive(Deserialize, Serialize)]
+struct Hint<K>
+{
+    data_len: u64,
+    dead: u64,
+    index: Vec<(K, Location)>,
+}
+
+/// Decodes just the key of a `JournalEntry`, ignoring the value after it.
+#[derive(Deserialize)]
+enum EntryKey<K>
+{
+    Set(K),
+    Remove(K),
+    Batch(u64),
+}
+
+impl<K,V> LogTable<K,V>
+    where K: Clone + Eq + Hash + DeserializeOwned + Serialize,
+          V: DeserializeOwned + Serialize
+{
+    /// Opens the log kept in `dir`, creating it if it doesn't exist.
+    pub fn open(dir: &Path) -> Result<Self, DatabaseError>
+    {
+        fs::create_dir_all(dir)?;
+
+        let mut generations = Vec::new();
+        for entry in fs::read_dir(dir)?
+        {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if let Some(gen) = name.strip_suffix(".data").and_then(|gen| gen.parse::<u64>().ok())
+            {
+                generations.push(gen);
+            }
+            else if name.ends_with(".tmp")
+            {
+                // An unfinished compaction
+                fs::remove_file(dir.join(&*name))?;
+            }
+        }
+        generations.sort_unstable();
+
+        let generation = generations.pop().unwrap_or(0);
+        for old in generations
+        {
+            // Left behind by a compaction which finished, but didn't clean up
+            Self::remove_generation(dir, old);
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(data_path(dir, generation))?;
+
+        let hint = Self::load_hint(dir, generation, file.metadata()?.len());
+        let (start, index, dead) = match hint
+        {
+            Some(hint) =>
+                (hint.data_len, hint.index.into_iter().collect(), hint.dead),
+            None =>
+                (0, HashMap::new(), 0),
+        };
+
+        let mut state = LogState { generation, file, len: start, index, dead };
+        Self::scan(&mut state)?;
+
+        Ok(LogTable
+        {
+            dir: dir.to_path_buf(),
+            compacting: Mutex::new(()),
+            appending: Mutex::new(()),
+            state: RwLock::new(state),
+            types: PhantomData,
+        })
+    }
+
+    /// Bytes of the log taken by records which have been overwritten or
+    /// removed, which `compact` would reclaim.
+    #[allow(dead_code)]
+    pub fn dead_bytes(&self) -> u64
+    {
+        read(&self.state).dead
+    }
+
+    /// Rewrite the log with just the latest version of each record, and
+    /// return how many bytes were reclaimed. Reads and writes carry on while
+    /// the records are copied, and are only held up to switch to the new log.
+    pub fn compact(&self) -> Result<u64, DatabaseError>
+    {
+        let _compacting = lock(&self.compacting);
+
+        let (generation, copied_len, live) = {
+            let state = read(&self.state);
+            let live: Vec<(K, Location)> = state.index
+                .iter()
+                .map(|(k, loc)| (k.clone(), *loc))
+                .collect();
+
+            (state.generation, state.len, live)
+        };
+
+        let path = data_path(&self.dir, generation + 1);
+        let tmp = temp_path(&path);
+        let reclaimed = match self.compact_into(&tmp, &path, copied_len, live)
+        {
+            Ok(reclaimed) =>
+                reclaimed,
+            Err(e) =>
+            {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            },
+        };
+
+        Self::remove_generation(&self.dir, generation);
+        self.write_hint()?;
+
+        Ok(reclaimed)
+    }
+
+    /// Copy the records at `live` to `tmp`, then catch up with anything
+    /// written since `copied_len`, and switch to it as the data file `path`.
+    fn compact_into(&self, tmp: &Path, path: &Path, copied_len: u64, live: Vec<(K, Location)>)
+        -> Result<u64, DatabaseError>
+    {
+        let mut out = BufWriter::new(
+            OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create_new(true)
+                .open(tmp)?);
+        let mut index = HashMap::with_capacity(live.len());
+        let mut len = 0;
+        let mut dead = 0;
+
+        for (k, loc) in live
+        {
+            let frame = read_at(&read(&self.state).file, loc.offset, loc.len)?;
+
+            out.write_all(&frame)?;
+            index.insert(k, Location { offset: len, len: loc.len });
+            len += loc.len;
+        }
+
+        let _appending = lock(&self.appending);
+        let mut state = write(&self.state);
+
+        let mut pos = copied_len;
+        while let Some((key, frame)) = read_frame(&state.file, pos, state.len)?
+        {
+            let frame_len = frame.len() as u64;
+            out.write_all(&frame)?;
+            dead += apply(&mut index, key, Location { offset: len, len: frame_len });
+            len += frame_len;
+            pos += frame_len;
+        }
+
+        out.flush()?;
+        let file = out.into_inner().map_err(|e| DatabaseError::Io(e.into_error()))?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        sync_dir(Some(&self.dir))?;
+
+        let reclaimed = state.len.saturating_sub(len);
+        *state = LogState { generation: state.generation + 1, file, len, index, dead };
+
+        Ok(reclaimed)
+    }
+
+    /// Save the index, so the next open needn't scan the whole log.
+    fn write_hint(&self) -> Result<(), DatabaseError>
+    {
+        let (generation, data) = {
+            let state = read(&self.state);
+            let hint = Hint {
+                data_len: state.len,
+                dead: state.dead,
+                index: state.index.iter().map(|(k, loc)| (k, *loc)).collect(),
+            };
+
+            (state.generation, Bincode::encode(&hint).map_err(DatabaseError::Encoding)?)
+        };
+
+        write_atomic(&hint_path(&self.dir, generation), &data)?;
+
+        Ok(())
+    }
+
+    /// The hint for `generation`, unless it's missing, unreadable, or
+    /// describes more of the log than there is.
+    fn load_hint(dir: &Path, generation: u64, data_len: u64) -> Option<Hint<K>>
+    {
+        let data = fs::read(hint_path(dir, generation)).ok()?;
+        let hint: Hint<K> = bincode::deserialize(&data).ok()?;
+
+        if hint.data_len <= data_len
+        {
+            Some(hint)
+        }
+        else
+        {
+            None
+        }
+    }
+
+    /// Index the log from `state.len` to its end. A torn or garbled frame
+    /// at the end (from a crash mid-append) is cut off, with what follows it.
+    fn scan(state: &mut LogState<K>) -> Result<(), DatabaseError>
+    {
+        let file_len = state.file.metadata()?.len();
+
+        while let Some((key, frame)) = read_frame(&state.file, state.len, file_len)?
+        {
+            let frame_len = frame.len() as u64;
+            if let EntryKey::Batch(n) = key
+            {
+                // A batch torn part way through is cut off whole
+                if !batch_is_whole::<K>(&state.file, state.len + frame_len, file_len, n)?
+                {
+                    break;
+                }
+            }
+            state.dead += apply(&mut state.index, key, Location { offset: state.len, len: frame_len });
+            state.len += frame_len;
+        }
+
+        if state.len < file_len
+        {
+            state.file.set_len(state.len)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_generation(dir: &Path, generation: u64)
+    {
+        let _ = fs::remove_file(data_path(dir, generation));
+        let _ = fs::remove_file(hint_path(dir, generation));
+    }
+
+    /// Durably append `entry`, returning where it was written.
+    fn append(&self, entry: &JournalEntry<&K,&V>) -> Result<Location, DatabaseError>
+    {
+        let frame = frame(entry)?;
+        let offset = self.write_frames(&frame)?;
+
+        Ok(Location { offset, len: frame.len() as u64 })
+    }
+
+    /// Durably append `entries` as a batch, which is kept whole or not at
+    /// all, returning where each was written, and how long the batch is.
+    fn append_batch(&self, entries: &[JournalEntry<&K,&V>]) -> Result<(Vec<Location>, u64), DatabaseError>
+    {
+        let mut data = frame(&JournalEntry::<&K,&V>::Batch(entries.len() as u64))?;
+        let mut lens = Vec::with_capacity(entries.len());
+        for entry in entries
+        {
+            let frame = frame(entry)?;
+            lens.push(frame.len() as u64);
+            data.extend(frame);
+        }
+
+        let mut offset = self.write_frames(&data)? + (data.len() as u64 - lens.iter().sum::<u64>());
+        let locs = lens
+            .into_iter()
+            .map(|len| {
+                let loc = Location { offset, len };
+                offset += len;
+                loc
+            })
+            .collect();
+
+        Ok((locs, data.len() as u64))
+    }
+
+    /// Durably append `data`, returning where it was written.
+    fn write_frames(&self, data: &[u8]) -> Result<u64, DatabaseError>
+    {
+        let state = read(&self.state);
+        let offset = state.len;
+
+        let result = (&state.file).write_all(data)
+            .and_then(|()| state.file.sync_data());
+        if let Err(e) = result
+        {
+            // Don't leave a partial frame for the next append to follow
+            let _ = state.file.set_len(offset);
+            return Err(DatabaseError::Io(e));
+        }
+
+        Ok(offset)
+    }
+
+    fn read_value(state: &LogState<K>, k: &K) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        let loc = match state.index.get(k)
+        {
+            Some(loc) =>
+                *loc,
+            None =>
+                return Ok(None),
+        };
+
+        let frame = read_at(&state.file, loc.offset, loc.len)?;
+        let body = checked_body(&frame).ok_or_else(|| DatabaseError::Corrupt("Log frame fails its checksum".into()))?;
+        match Bincode::decode::<JournalEntry<K,V>>(body).map_err(DatabaseError::Corrupt)?
+        {
+            JournalEntry::Set(_, v) =>
+                Ok(Some(Arc::new(v))),
+            JournalEntry::Remove(_) =>
+                Err(DatabaseError::Io(io::Error::new(io::ErrorKind::InvalidData, "Index refers to a removal"))),
+            JournalEntry::Batch(_) =>
+                Err(DatabaseError::Io(io::Error::new(io::ErrorKind::InvalidData, "Index refers to the start of a batch"))),
+        }
+    }
+}
+
+impl<K,V> Table<K,V> for LogTable<K,V>
+    where K: Clone + Eq + Hash + DeserializeOwned + Serialize,
+          V: DeserializeOwned + Serialize
+{
+    fn try_set(&self, k: K, v: V) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        let _appending = lock(&self.appending);
+        // A corrupt old value is still replaced
+        let old = match Self::read_value(&read(&self.state), &k)
+        {
+            Err(DatabaseError::Corrupt(_)) =>
+                None,
+            old =>
+                old?,
+        };
+        let loc = self.append(&JournalEntry::<&K,&V>::Set(&k, &v))?;
+
+        let mut state = write(&self.state);
+        state.len += loc.len;
+        if let Some(old) = state.index.insert(k, loc)
+        {
+            state.dead += old.len;
+        }
+
+        Ok(old)
+    }
+
+    fn try_get(&self, k: &K) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        Self::read_value(&read(&self.state), k)
+    }
+
+    fn try_contains_key(&self, k: &K) -> Result<bool, DatabaseError>
+    {
+        Ok(read(&self.state).index.contains_key(k))
+    }
+
+    fn try_remove(&self, k: &K) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        let _appending = lock(&self.appending);
+        let old = {
+            let state = read(&self.state);
+            if !state.index.contains_key(k)
+            {
+                return Ok(None);
+            }
+
+            // A corrupt old value is still removed
+            match Self::read_value(&state, k)
+            {
+                Err(DatabaseError::Corrupt(_)) =>
+                    None,
+                old =>
+                    old?,
+            }
+        };
+        let loc = self.append(&JournalEntry::<&K,&V>::Remove(k))?;
+
+        let mut state = write(&self.state);
+        state.len += loc.len;
+        state.dead += apply(&mut state.index, EntryKey::Remove(k.clone()), loc);
+
+        Ok(old)
+    }
+
+    /// Appended as one batch, which is cut off whole if the log is torn
+    /// part way through it.
+    fn commit(&self, batch: Batch<K,V>) -> Result<(), DatabaseError>
+    {
+        if batch.is_empty()
+        {
+            return Ok(());
+        }
+        let _appending = lock(&self.appending);
+        let entries: Vec<_> = batch.ops().iter().map(JournalEntry::staged).collect();
+        let (locs, len) = self.append_batch(&entries)?;
+
+        let mut state = write(&self.state);
+        let state = &mut *state;
+        state.len += len;
+        // The marker before the batch's entries is dead once written
+        state.dead += len - locs.iter().map(|loc| loc.len).sum::<u64>();
+        for (op, loc) in batch.ops().iter().zip(locs)
+        {
+            let key = match op
+            {
+                BatchOp::Set(k, _) =>
+                    EntryKey::Set(k.clone()),
+                BatchOp::Remove(k) =>
+                    EntryKey::Remove(k.clone()),
+            };
+            state.dead += apply(&mut state.index, key, loc);
+        }
+
+        Ok(())
+    }
+
+    fn keys(&self) -> Vec<K>
+        where K: Clone
+    {
+        read(&self.state).index.keys().cloned().collect()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, Arc<V>)> + '_>
+        where K: Clone
+    {
+        Box::new(
+            self.keys()
+                .into_iter()
+                .filter_map(move |k| {
+                    let v = self.get(&k)?;
+                    Some((k, v))
+                }))
+    }
+
+    /// Nothing is held back from the log, so this only saves the hint file,
+    /// compacting first if most of the log is dead.
+    fn persist(&self) -> Result<usize, PersistError<K>>
+    {
+        let (len, dead) = {
+            let state = read(&self.state);
+            (state.len, state.dead)
+        };
+
+        let result = if dead >= COMPACT_MIN_DEAD && dead * 2 > len
+        {
+            self.compact().map(|_| ())
+        }
+        else
+        {
+            self.write_hint()
+        };
+
+        result
+            .map(|()| 0)
+            .map_err(|e| PersistError { failed: Vec::new(), journal: Some(e) })
+    }
+}
+
+/// Apply the change framed at `loc` to `index`, returning how many bytes
+/// of the log it made dead.
+fn apply<K>(index: &mut HashMap<K, Location>, key: EntryKey<K>, loc: Location) -> u64
+    where K: Eq + Hash
+{
+    match key
+    {
+        EntryKey::Set(k) =>
+            index.insert(k, loc).map(|old| old.len).unwrap_or(0),
+        // Once the record it removes is gone, the removal isn't needed either
+        EntryKey::Remove(k) =>
+            index.remove(&k).map(|old| old.len).unwrap_or(0) + loc.len,
+        // Only needed until the batch after it is whole
+        EntryKey::Batch(_) =>
+            loc.len,
+    }
+}
+
+/// Whether all `n` frames of a batch are between `pos` and `end`.
+fn batch_is_whole<K>(file: &File, mut pos: u64, end: u64, n: u64) -> Result<bool, DatabaseError>
+    where K: DeserializeOwned
+{
+    for _ in 0..n
+    {
+        match read_frame::<K>(file, pos, end)?
+        {
+            Some((EntryKey::Batch(_), _)) =>
+                return Err(DatabaseError::Corrupt("Log has a batch within a batch".into())),
+            Some((_, frame)) =>
+                pos += frame.len() as u64,
+            None =>
+                return Ok(false),
+        }
+    }
+
+    Ok(true)
+}
+
+/// A change as written to the log, and the key it changes.
+type Frame<K> = (EntryKey<K>, Vec<u8>);
+
+/// `entry` as it's written to the log.
+fn frame<K,V>(entry: &JournalEntry<&K,&V>) -> Result<Vec<u8>, DatabaseError>
+    where K: Serialize,
+          V: Serialize
+{
+    let body = Bincode::encode(entry).map_err(DatabaseError::Encoding)?;
+    let mut data = Vec::with_capacity(HEADER as usize + body.len());
+    data.extend_from_slice(&(body.len() as u64).to_le_bytes());
+    data.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+    data.extend_from_slice(&body);
+
+    Ok(data)
+}
+
+/// The body of the whole `frame`, unless it fails its checksum. No entry
+/// encodes to nothing, so an empty body (as in a zero-filled tail) fails.
+fn checked_body(frame: &[u8]) -> Option<&[u8]>
+{
+    let (header, body) = frame.split_at(HEADER as usize);
+    let crc = u32::from_le_bytes(header[LEN_PREFIX as usize..].try_into().ok()?);
+
+    if !body.is_empty() && crc32fast::hash(body) == crc
+    {
+        Some(body)
+    }
+    else
+    {
+        None
+    }
+}
+
+/// The frame at `pos` in `file`. `None` if there isn't a whole frame
+/// between `pos` and `end` which passes its checksum.
+fn read_frame<K>(file: &File, pos: u64, end: u64) -> Result<Option<Frame<K>>, DatabaseError>
+    where K: DeserializeOwned
+{
+    if end < pos + HEADER
+    {
+        return Ok(None);
+    }
+
+    let mut len = [0; LEN_PREFIX as usize];
+    len.copy_from_slice(&read_at(file, pos, LEN_PREFIX)?);
+    let frame_len = match u64::from_le_bytes(len).checked_add(HEADER)
+    {
+        Some(frame_len) if frame_len <= end - pos =>
+            frame_len,
+        _ =>
+            return Ok(None),
+    };
+
+    let frame = read_at(file, pos, frame_len)?;
+    let body = match checked_body(&frame)
+    {
+        Some(body) =>
+            body,
+        None =>
+            return Ok(None),
+    };
+    // Checked, so it's as it was written
+    let key = Bincode::decode(body).map_err(DatabaseError::Corrupt)?;
+
+    Ok(Some((key, frame)))
+}
+
+fn data_path(dir: &Path, generation: u64) -> PathBuf
+{
+    dir.join(format!("{}.data", generation))
+}
+
+fn hint_path(dir: &Path, generation: u64) -> PathBuf
+{
+    dir.join(format!("{}.hint", generation))
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, offset: u64, len: u64) -> io::Result<Vec<u8>>
+{
+    use std::os::unix::fs::FileExt;
+
+    let mut buf = vec![0; len as usize];
+    file.read_exact_at(&mut buf, offset)?;
+
+    Ok(buf)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut offset: u64, len: u64) -> io::Result<Vec<u8>>
+{
+    use std::os::windows::fs::FileExt;
+
+    let mut buf = vec![0; len as usize];
+    let mut filled = 0;
+    while filled < buf.len()
+    {
+        match file.seek_read(&mut buf[filled..], offset)?
+        {
+            0 =>
+                return Err(io::ErrorKind::UnexpectedEof.into()),
+            n =>
+            {
+                filled += n;
+                offset += n as u64;
+            },
+        }
+    }
+
+    Ok(buf)
+}
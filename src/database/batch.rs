@@ -0,0 +1,72 @@
+// Changes staged to be made to a table together.
+
+/// Changes to a table, staged by `set` and `remove`, to be made all together
+/// by `Table::commit`, or not at all. A batch which isn't committed (or is
+/// dropped by `abort`) changes nothing.
+pub struct Batch<K,V>
+{
+    ops: Vec<BatchOp<K,V>>,
+}
+
+/// A change staged in a `Batch`.
+pub enum BatchOp<K,V>
+{
+    Set(K, V),
+    #[allow(dead_code)]
+    Remove(K),
+}
+
+impl<K,V> Batch<K,V>
+{
+    pub fn new() -> Self
+    {
+        Batch { ops: Vec::new() }
+    }
+
+    pub fn set(&mut self, k: K, v: V) -> &mut Self
+    {
+        self.ops.push(BatchOp::Set(k, v));
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn remove(&mut self, k: K) -> &mut Self
+    {
+        self.ops.push(BatchOp::Remove(k));
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize
+    {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.ops.is_empty()
+    }
+
+    /// Drop the staged changes, without making any of them.
+    #[allow(dead_code)]
+    pub fn abort(self) {}
+
+    /// The staged changes, in the order they're made.
+    pub fn ops(&self) -> &[BatchOp<K,V>]
+    {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp<K,V>>
+    {
+        self.ops
+    }
+}
+
+impl<K,V> Default for Batch<K,V>
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
@@ -0,0 +1,573 @@
+use std::collections::{ HashMap, HashSet, };
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{ self, File, };
+use std::io::{ self, Cursor, Read, Seek, SeekFrom, };
+use std::path::{ Path, PathBuf, };
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex, };
+
+use hmac::{ Hmac, Mac, };
+use serde::{ Deserialize, Serialize, };
+use sha2::{ Digest, Sha256, };
+
+use super::{
+    crypt, lock, record, write_atomic,
+    Access, Compression, DatabaseError, DiskCache, DiskCacheOptions, PersistError, Table,
+};
+use super::crypt::{ Keyring, Unsealed, };
+
+/// Where blobs are kept, under their names
+const BLOB_DIR: &str = ".sha256";
+/// Where the reference counts are kept, as a `DiskCache`
+const REFS_DIR: &str = ".refs";
+/// Where the key encrypted blobs are named with is kept, sealed
+const NAME_KEY: &str = ".name-key";
+
+/// Names a blob by the SHA-256 hash of its contents, so that the same data
+/// is only ever stored once.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct BlobId([u8; 32]);
+
+impl BlobId
+{
+    pub fn of(data: &[u8]) -> Self
+    {
+        BlobId(Sha256::digest(data).into())
+    }
+}
+
+impl fmt::Display for BlobId
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        for b in &self.0
+        {
+            write!(f, "{:02x}", b)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for BlobId
+{
+    type Err = ();
+
+    /// Parses the form `Display` gives, and only that.
+    fn from_str(s: &str) -> Result<Self, ()>
+    {
+        let mut id = [0; 32];
+        if s.len() != 2 * id.len() || s.bytes().any(|b| !matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        {
+            return Err(());
+        }
+
+        for (i, b) in id.iter_mut().enumerate()
+        {
+            *b = u8::from_str_radix(&s[2*i..2*i + 2], 16).map_err(|_| ())?;
+        }
+
+        Ok(BlobId(id))
+    }
+}
+
+/// Large values, such as image data, kept a file apiece so they can be
+/// streamed rather than read into memory whole. Blobs are named by their
+/// contents, and counted each time they're `put`, so they're shared by
+/// everything storing the same data and only removed once all of it has
+/// `release`d them.
+///
+/// Writes are atomic, but unlike a `Table` only the counts are cached:
+/// `put` returns once the blob is on disk.
+///
+/// Blobs may be compressed or encrypted, as records are, and then have a
+/// record's header and have to be read into memory whole. Those stored as
+/// they are are told apart by their contents matching their ids.
+///
+/// Blobs (and their counts) are stored under their ids, unless the store
+/// is encrypted. Then they're named by a keyed hash of their ids instead,
+/// so that listing the store doesn't tell whether some known data is in
+/// it. Those stored before it was encrypted are renamed by `reencrypt`.
+pub struct BlobStore
+{
+    base_path: PathBuf,
+    access: Access,
+    compression: Compression,
+    keyring: Option<Arc<Keyring>>,
+    /// The key blobs are named with, if they're encrypted
+    name_key: Option<[u8; 32]>,
+    refs: DiskCache<BlobId, u64>,
+    /// Held while a count changes, so that a blob isn't removed as it's
+    /// being shared again
+    counting: Mutex<()>,
+}
+
+impl BlobStore
+{
+    /// Opens the store at `base_path` for writing, creating it if it doesn't
+    /// exist.
+    #[allow(dead_code)]
+    pub fn new(base_path: PathBuf) -> io::Result<Self>
+    {
+        Self::with_access(base_path, Access::Exclusive)
+    }
+
+    /// As `new`, but with `access` to the store, as for a `DiskCache`. Only
+    /// reads are allowed with `Access::ReadOnly`.
+    #[allow(dead_code)]
+    pub fn with_access(base_path: PathBuf, access: Access) -> io::Result<Self>
+    {
+        Self::with_options(base_path, DiskCacheOptions { access, ..DiskCacheOptions::default() })
+    }
+
+    /// As `new`, but with blobs stored as `options` say a `DiskCache` stores
+    /// its records: with its `access`, `compression` and `keyring`. The rest
+    /// don't apply, and the counts are neither compressed nor encrypted.
+    pub fn with_options(base_path: PathBuf, options: DiskCacheOptions) -> io::Result<Self>
+    {
+        let access = options.access;
+        if access == Access::Exclusive
+        {
+            fs::create_dir_all(base_path.join(BLOB_DIR))?;
+            fs::create_dir_all(base_path.join(REFS_DIR))?;
+        }
+
+        let name_key = match &options.keyring
+        {
+            Some(keyring) =>
+                load_name_key(&base_path, keyring, access)?,
+            None =>
+                None,
+        };
+
+        let refs = DiskCacheOptions { access, ..DiskCacheOptions::default() };
+        Ok(BlobStore {
+            refs: DiskCache::with_options(base_path.join(REFS_DIR), refs).map_err(io::Error::other)?,
+            base_path,
+            access,
+            compression: options.compression,
+            keyring: options.keyring,
+            name_key,
+            counting: Mutex::new(()),
+        })
+    }
+
+    fn writable(&self) -> Result<(), DatabaseError>
+    {
+        match self.access
+        {
+            Access::Exclusive =>
+                Ok(()),
+            Access::ReadOnly =>
+                Err(DatabaseError::ReadOnly),
+        }
+    }
+
+    /// What the blob `id` is stored as: its id, or a keyed hash of it if
+    /// the store is encrypted.
+    fn name(&self, id: &BlobId) -> BlobId
+    {
+        match &self.name_key
+        {
+            Some(key) =>
+            {
+                let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+                    .unwrap_or_else(|_| unreachable!("HMAC takes keys of any length"));
+                mac.update(&id.0);
+                BlobId(mac.finalize().into_bytes().into())
+            },
+            None =>
+                *id,
+        }
+    }
+
+    fn make_path(&self, name: &BlobId) -> PathBuf
+    {
+        let mut path = self.base_path.join(BLOB_DIR);
+        path.push(name.to_string());
+        path
+    }
+
+    /// The name the blob `id` is stored under, and its path: its `name`,
+    /// unless it's still under its id from before the store was encrypted.
+    fn find(&self, id: &BlobId) -> (BlobId, PathBuf)
+    {
+        let name = self.name(id);
+        let path = self.make_path(&name);
+        if name != *id && !path.is_file()
+        {
+            let unrenamed = self.make_path(id);
+            if unrenamed.is_file()
+            {
+                return (*id, unrenamed);
+            }
+        }
+
+        (name, path)
+    }
+
+    /// The references to `id`, counted under its name, or else its id.
+    fn count(&self, id: &BlobId) -> Result<u64, DatabaseError>
+    {
+        let name = self.name(id);
+        match self.refs.try_get(&name)?
+        {
+            Some(n) =>
+                Ok(*n),
+            None if name != *id =>
+                Ok(self.refs.try_get(id)?.map_or(0, |n| *n)),
+            None =>
+                Ok(0),
+        }
+    }
+
+    /// Count `n` references to `id`, under its name.
+    fn set_count(&self, id: &BlobId, n: u64) -> Result<(), DatabaseError>
+    {
+        let name = self.name(id);
+        if name != *id && self.refs.try_contains_key(id)?
+        {
+            self.refs.try_remove(id)?;
+        }
+        match n
+        {
+            0 =>
+                self.refs.try_remove(&name)?,
+            n =>
+                self.refs.try_set(name, n)?,
+        };
+
+        Ok(())
+    }
+
+    /// Durably store `data`, unless it's already stored, and count another
+    /// reference to it.
+    pub fn put(&self, data: &[u8]) -> Result<BlobId, DatabaseError>
+    {
+        self.writable()?;
+        let id = BlobId::of(data);
+
+        let _counting = lock(&self.counting);
+        // Even uncounted, a blob that's there has the right contents
+        let (name, path) = self.find(&id);
+        if !path.is_file()
+        {
+            write_atomic(&path, &self.pack(&name, data)?)?;
+        }
+        self.set_count(&id, self.count(&id)? + 1)?;
+
+        Ok(id)
+    }
+
+    /// Drop a reference to a blob, removing it if that was the last one.
+    /// Returns whether it was removed.
+    pub fn release(&self, id: &BlobId) -> Result<bool, DatabaseError>
+    {
+        self.writable()?;
+        let _counting = lock(&self.counting);
+        match self.count(id)?
+        {
+            // Never counted, so it's not known to be unused. Left for `gc`.
+            0 =>
+                Ok(false),
+            1 =>
+            {
+                // Uncounted first, so a blob is never counted but missing
+                let (_, path) = self.find(id);
+                self.set_count(id, 0)?;
+                match fs::remove_file(path)
+                {
+                    Ok(()) =>
+                        Ok(true),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound =>
+                        Ok(false),
+                    Err(e) =>
+                        Err(DatabaseError::Io(e)),
+                }
+            },
+            n =>
+            {
+                self.set_count(id, n - 1)?;
+                Ok(false)
+            },
+        }
+    }
+
+    /// How many references there are to a blob.
+    #[allow(dead_code)]
+    pub fn refs(&self, id: &BlobId) -> Result<u64, DatabaseError>
+    {
+        self.count(id)
+    }
+
+    /// The blob `id`, opened for reading, and its length. Streamed from
+    /// disk if it's stored as it is.
+    pub fn open(&self, id: &BlobId) -> Result<(Box<dyn Read + Send>, u64), DatabaseError>
+    {
+        let (name, path) = self.find(id);
+        let mut f = File::open(path).map_err(DatabaseError::from_read)?;
+        let mut magic = [0; 4];
+        let stored_as_is = match f.read_exact(&mut magic)
+        {
+            Ok(()) =>
+                !record::has_header(&magic),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof =>
+                true,
+            Err(e) =>
+                return Err(DatabaseError::Io(e)),
+        };
+
+        f.seek(SeekFrom::Start(0))?;
+        if stored_as_is && self.keyring.is_none()
+        {
+            let len = f.metadata()?.len();
+            return Ok((Box::new(f), len));
+        }
+
+        let mut data = Vec::new();
+        f.read_to_end(&mut data)?;
+        let data = self.unpack(id, &name, data)?;
+        let len = data.len() as u64;
+
+        Ok((Box::new(Cursor::new(data)), len))
+    }
+
+    #[allow(dead_code)]
+    pub fn read(&self, id: &BlobId) -> Result<Vec<u8>, DatabaseError>
+    {
+        let (name, path) = self.find(id);
+        let data = fs::read(path).map_err(DatabaseError::from_read)?;
+        self.unpack(id, &name, data)
+    }
+
+    /// `data`, as a blob is stored under `name`: as it is, unless it's to
+    /// be compressed (and that makes it smaller) or encrypted. Bound to
+    /// `name` when encrypted, so that it can't be passed off as another blob.
+    fn pack(&self, name: &BlobId, data: &[u8]) -> Result<Vec<u8>, DatabaseError>
+    {
+        let packed = record::pack(&name.to_string(), 0, data, self.compression, self.keyring.as_deref())?;
+        if self.keyring.is_none() && record::decode(&packed).is_ok_and(|(header, _)| header.flags == 0)
+        {
+            return Ok(data.to_vec());
+        }
+
+        Ok(packed)
+    }
+
+    /// The contents of `data`, the blob `id` as it's stored under `name`.
+    fn unpack(&self, id: &BlobId, name: &BlobId, data: Vec<u8>) -> Result<Vec<u8>, DatabaseError>
+    {
+        match self.unpack_named(name, data)?
+        {
+            (found, data) if found == *id =>
+                Ok(data),
+            _ =>
+                Err(DatabaseError::Corrupt("Blob doesn't match its id".into())),
+        }
+    }
+
+    /// The id and contents of `data`, a blob as it's stored under `name`.
+    fn unpack_named(&self, name: &BlobId, data: Vec<u8>) -> Result<(BlobId, Vec<u8>), DatabaseError>
+    {
+        let id = BlobId::of(&data);
+        if id == *name
+        {
+            return Ok((id, data));
+        }
+
+        let (_, unpacked) = record::unpack(&name.to_string(), &data, self.keyring.as_deref())?;
+        let id = BlobId::of(&unpacked);
+        if id != *name && self.name(&id) != *name
+        {
+            return Err(DatabaseError::Corrupt("Blob doesn't match its name".into()));
+        }
+
+        Ok((id, unpacked.into_owned()))
+    }
+
+    /// Re-encrypt every blob not encrypted under the current key, as
+    /// `DiskCache::reencrypt` does for records. Blobs which can't be read
+    /// are reported and left as they are. Returns how many blobs were
+    /// re-encrypted, and how many couldn't be.
+    pub fn reencrypt(&self) -> Result<(usize, usize), DatabaseError>
+    {
+        self.writable()?;
+        let keyring = match &self.keyring
+        {
+            Some(keyring) =>
+                keyring,
+            None =>
+                return Ok((0, 0)),
+        };
+
+        if let Some(key) = &self.name_key
+        {
+            let path = self.base_path.join(NAME_KEY);
+            if !keyring.is_current(&fs::read(&path)?)
+            {
+                write_atomic(&path, &keyring.seal(NAME_KEY.as_bytes(), key).map_err(DatabaseError::Corrupt)?)?;
+            }
+        }
+
+        let mut reencrypted = 0;
+        let mut failed = 0;
+        for entry in fs::read_dir(self.base_path.join(BLOB_DIR))?
+        {
+            let entry = entry?;
+            let name: BlobId = match entry.file_name().to_str().and_then(|name| name.parse().ok())
+            {
+                Some(name) =>
+                    name,
+                None =>
+                    continue,
+            };
+
+            // So it isn't removed as it's rewritten
+            let _counting = lock(&self.counting);
+            let data = match fs::read(entry.path())
+            {
+                Ok(data) =>
+                    data,
+                Err(e) if e.kind() == io::ErrorKind::NotFound =>
+                    continue,
+                Err(e) =>
+                    return Err(DatabaseError::Io(e)),
+            };
+            if !record::needs_sealing(&data, keyring)
+            {
+                continue;
+            }
+
+            let repacked = self.unpack_named(&name, data)
+                .and_then(|(id, data)| Ok((id, self.pack(&self.name(&id), &data)?)));
+            match repacked
+            {
+                Ok((id, data)) =>
+                {
+                    // Stored before the store was encrypted, so renamed,
+                    // and its count with it
+                    let renamed = self.name(&id);
+                    write_atomic(&self.make_path(&renamed), &data)?;
+                    if renamed != name
+                    {
+                        self.set_count(&id, self.count(&id)?)?;
+                        fs::remove_file(entry.path())?;
+                    }
+                    reencrypted += 1;
+                },
+                Err(e) =>
+                {
+                    eprintln!("Unable to re-encrypt blob {}: {}", name, e);
+                    failed += 1;
+                },
+            }
+        }
+
+        Ok((reencrypted, failed))
+    }
+
+    #[allow(dead_code)]
+    pub fn contains(&self, id: &BlobId) -> bool
+    {
+        self.find(id).1.is_file()
+    }
+
+    /// Persist the reference counts.
+    pub fn persist(&self) -> Result<usize, PersistError<BlobId>>
+    {
+        self.refs.persist()
+    }
+
+    /// Recount the references to every blob from `live`, which holds an id
+    /// for each reference, and remove the blobs it doesn't mention. These
+    /// are left behind when a crash comes between changing a blob's count
+    /// and the record referring to it.
+    ///
+    /// Anything which could `put` or `release` must wait until this is
+    /// done, from before `live` was gathered. Returns how many blobs were
+    /// removed.
+    pub fn gc<I>(&self, live: I) -> Result<usize, DatabaseError>
+        where I: IntoIterator<Item = BlobId>
+    {
+        self.writable()?;
+        let mut counts: HashMap<BlobId, u64> = HashMap::new();
+        for id in live
+        {
+            *counts.entry(id).or_insert(0) += 1;
+        }
+
+        let _counting = lock(&self.counting);
+        let names: HashMap<BlobId, BlobId> = counts.keys().map(|id| (self.name(id), *id)).collect();
+        for name in self.refs.keys()
+        {
+            if !names.contains_key(&name)
+            {
+                self.refs.try_remove(&name)?;
+            }
+        }
+        for (id, n) in &counts
+        {
+            if self.count(id)? != *n
+            {
+                self.set_count(id, *n)?;
+            }
+        }
+        self.refs.persist().map_err(|e| io::Error::other(e.to_string()))?;
+
+        // Either name a live blob may be stored under
+        let live: HashSet<&BlobId> = names.keys().chain(counts.keys()).collect();
+        let mut removed = 0;
+        for entry in fs::read_dir(self.base_path.join(BLOB_DIR))?
+        {
+            let entry = entry?;
+            // Including any temporary files left by an interrupted `put`
+            let live = entry.file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+                .is_some_and(|name: BlobId| live.contains(&name));
+
+            if !live
+            {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+/// The key blobs in the store at `base_path` are named with, sealed under
+/// `keyring`. It's made when the store is first encrypted, unless it's
+/// read only, when there are no blobs named with it yet.
+fn load_name_key(base_path: &Path, keyring: &Keyring, access: Access) -> io::Result<Option<[u8; 32]>>
+{
+    let path = base_path.join(NAME_KEY);
+    match fs::read(&path)
+    {
+        Ok(sealed) =>
+        {
+            let key = keyring.open(NAME_KEY.as_bytes(), &sealed).map_err(|e| match e
+            {
+                Unsealed::MissingKey(id) =>
+                    io::Error::other(DatabaseError::MissingKey { id: Some(id) }),
+                Unsealed::Corrupt(e) =>
+                    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)),
+            })?;
+            let key = <[u8; 32]>::try_from(key.as_slice())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("{}: Not a key", path.display())))?;
+            Ok(Some(key))
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound && access == Access::Exclusive =>
+        {
+            let key = crypt::new_key();
+            write_atomic(&path, &keyring.seal(NAME_KEY.as_bytes(), &key).map_err(io::Error::other)?)?;
+            Ok(Some(key))
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound =>
+            Ok(None),
+        Err(e) =>
+            Err(e),
+    }
+}
@@ -0,0 +1,188 @@
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{ Deserialize, Serialize, };
+use serde::de::DeserializeOwned;
+
+use super::{ write_atomic, Access, DatabaseError, DiskCacheOptions, };
+
+/// Why a value couldn't be encoded or decoded, whatever the codec.
+pub type CodecError = Box<dyn Error + Send + Sync>;
+
+/// How a `DiskCache` turns its values into records, and back.
+pub trait Codec
+{
+    /// How the codec is named in a store's manifest
+    const NAME: &'static str;
+
+    fn encode<T>(v: &T) -> Result<Vec<u8>, CodecError>
+        where T: Serialize + ?Sized;
+
+    fn decode<T>(data: &[u8]) -> Result<T, CodecError>
+        where T: DeserializeOwned;
+}
+
+/// Compact, but only readable knowing the type it was written from. The
+/// default, and what every store used before there was a choice.
+pub struct Bincode;
+
+impl Codec for Bincode
+{
+    const NAME: &'static str = "bincode";
+
+    fn encode<T>(v: &T) -> Result<Vec<u8>, CodecError>
+        where T: Serialize + ?Sized
+    {
+        Ok(bincode::serialize(v)?)
+    }
+
+    fn decode<T>(data: &[u8]) -> Result<T, CodecError>
+        where T: DeserializeOwned
+    {
+        Ok(bincode::deserialize(data)?)
+    }
+}
+
+pub struct Json;
+
+impl Codec for Json
+{
+    const NAME: &'static str = "json";
+
+    fn encode<T>(v: &T) -> Result<Vec<u8>, CodecError>
+        where T: Serialize + ?Sized
+    {
+        Ok(serde_json::to_vec(v)?)
+    }
+
+    fn decode<T>(data: &[u8]) -> Result<T, CodecError>
+        where T: DeserializeOwned
+    {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+pub struct Cbor;
+
+impl Codec for Cbor
+{
+    const NAME: &'static str = "cbor";
+
+    fn encode<T>(v: &T) -> Result<Vec<u8>, CodecError>
+        where T: Serialize + ?Sized
+    {
+        let mut data = Vec::new();
+        ciborium::ser::into_writer(v, &mut data)?;
+
+        Ok(data)
+    }
+
+    fn decode<T>(data: &[u8]) -> Result<T, CodecError>
+        where T: DeserializeOwned
+    {
+        Ok(ciborium::de::from_reader(data)?)
+    }
+}
+
+/// Structs are written as maps, with their field names, so the records
+/// can be read without knowing the type.
+pub struct MessagePack;
+
+impl Codec for MessagePack
+{
+    const NAME: &'static str = "msgpack";
+
+    fn encode<T>(v: &T) -> Result<Vec<u8>, CodecError>
+        where T: Serialize + ?Sized
+    {
+        Ok(rmp_serde::to_vec_named(v)?)
+    }
+
+    fn decode<T>(data: &[u8]) -> Result<T, CodecError>
+        where T: DeserializeOwned
+    {
+        Ok(rmp_serde::from_slice(data)?)
+    }
+}
+
+/// What a store records about how it's kept, so that it's never read
+/// as something it isn't. JSON, for the benefit of other tools.
+#[derive(Deserialize, Serialize)]
+struct Manifest
+{
+    codec: String,
+    /// The schema version of the values in the journal. Unlike records,
+    /// journal entries have no header of their own.
+    #[serde(default)]
+    schema: u32,
+    /// Whether anything has been encrypted, so that the store is never
+    /// opened without its keys, and written to in the clear
+    #[serde(default)]
+    encrypted: bool,
+}
+
+const MANIFEST: &str = ".manifest";
+
+/// Make sure the store at `base_path` is encoded with `C`, that what's
+/// `journaled` is at the schema `options` expect, and that `options` have
+/// keys if the store is encrypted, recording all three if need be and the
+/// store is writable. A store without a manifest which already
+/// `has_records` predates manifests, and so is `Bincode` at schema 0.
+pub(super) fn check_manifest<C, F>(base_path: &Path, options: &DiskCacheOptions, journaled: bool, has_records: F) -> Result<(), DatabaseError>
+    where C: Codec,
+          F: FnOnce() -> io::Result<bool>
+{
+    let schema = options.migrations.current();
+    let path = base_path.join(MANIFEST);
+    let (manifest, existed) = match fs::read(&path)
+    {
+        Ok(data) =>
+        {
+            let manifest: Manifest = serde_json::from_slice(&data)
+                .map_err(|e| DatabaseError::Corrupt(e.into()))?;
+            (manifest, true)
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound =>
+        {
+            let manifest = if has_records()?
+            {
+                Manifest { codec: String::from(Bincode::NAME), schema: 0, encrypted: false }
+            }
+            else
+            {
+                Manifest { codec: String::from(C::NAME), schema, encrypted: false }
+            };
+            (manifest, false)
+        },
+        Err(e) =>
+            return Err(DatabaseError::Io(e)),
+    };
+
+    if manifest.codec != C::NAME
+    {
+        return Err(DatabaseError::WrongCodec { store: manifest.codec, opened: C::NAME });
+    }
+    if manifest.schema != schema && journaled
+    {
+        // Entries which won't decode would be taken for a torn write, and
+        // dropped
+        return Err(DatabaseError::UnsupportedSchema { found: manifest.schema, current: schema });
+    }
+    if manifest.encrypted && options.keyring.is_none()
+    {
+        return Err(DatabaseError::MissingKey { id: None });
+    }
+
+    let encrypted = options.keyring.is_some();
+    if (!existed || manifest.schema != schema || manifest.encrypted != encrypted) && options.access == Access::Exclusive
+    {
+        let manifest = Manifest { schema, encrypted, ..manifest };
+        let data = serde_json::to_vec_pretty(&manifest)
+            .map_err(|e| DatabaseError::Encoding(e.into()))?;
+        write_atomic(&path, &data)?;
+    }
+
+    Ok(())
+}
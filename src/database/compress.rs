@@ -0,0 +1,75 @@
+// Compressing records, for those values which are worth it.
+
+use std::convert::TryFrom;
+
+use super::CodecError;
+
+/// Whether, and how, a `DiskCache` compresses its records. Each record
+/// notes how it was compressed, so records compressed any way (or not at
+/// all) can be read whatever's chosen now.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Compression
+{
+    #[default]
+    None,
+    /// Fast, but doesn't compress as well
+    Lz4,
+    /// At the given level, from 1 to 22 (see `zstd::DEFAULT_COMPRESSION_LEVEL`)
+    Zstd(i32),
+}
+
+/// How long `data` was before it was compressed, ahead of what it was
+/// compressed to.
+const LEN_PREFIX: usize = std::mem::size_of::<u32>();
+
+/// `data`, compressed as `compression` says, unless that doesn't make it
+/// any smaller.
+pub(super) fn compress(compression: Compression, data: &[u8]) -> Result<Option<Vec<u8>>, CodecError>
+{
+    let compressed = match compression
+    {
+        Compression::None =>
+            return Ok(None),
+        Compression::Lz4 =>
+            lz4_flex::compress(data),
+        Compression::Zstd(level) =>
+            zstd::bulk::compress(data, level)?,
+    };
+    if LEN_PREFIX + compressed.len() >= data.len()
+    {
+        return Ok(None);
+    }
+
+    let mut out = Vec::with_capacity(LEN_PREFIX + compressed.len());
+    out.extend_from_slice(&u32::try_from(data.len())?.to_le_bytes());
+    out.extend_from_slice(&compressed);
+
+    Ok(Some(out))
+}
+
+/// What `compress` was given, if it compressed it as `compression` says.
+pub(super) fn decompress(compression: Compression, data: &[u8]) -> Result<Vec<u8>, CodecError>
+{
+    if data.len() < LEN_PREFIX
+    {
+        return Err("Compressed record truncated".into());
+    }
+
+    let (len, compressed) = data.split_at(LEN_PREFIX);
+    let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    let data = match compression
+    {
+        Compression::None =>
+            return Err("Record isn't compressed".into()),
+        Compression::Lz4 =>
+            lz4_flex::decompress(compressed, len)?,
+        Compression::Zstd(_) =>
+            zstd::bulk::decompress(compressed, len)?,
+    };
+    if data.len() != len
+    {
+        return Err("Compressed record doesn't decompress to its length".into());
+    }
+
+    Ok(data)
+}
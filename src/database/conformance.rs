@@ -0,0 +1,368 @@
+// Tests which every `Table` implementation should pass, instantiated for
+// each one with `table_conformance!`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{ Path, PathBuf, };
+use std::sync::Arc;
+
+use proptest::prelude::*;
+use proptest::test_runner::{ Config, TestRunner, };
+
+use super::{ Batch, Table, };
+
+/// Generates a test for each of `Subject`'s checks, against the table
+/// opened by `$open`.
+macro_rules! table_conformance(
+    ($name:ident, $open:expr, durable: $durable:expr) =>
+    {
+        mod $name
+        {
+            use super::*;
+            use crate::database::conformance::Subject;
+
+            fn subject() -> Subject<impl Table<String, String> + Sync>
+            {
+                Subject { name: stringify!($name), open: $open, durable: $durable }
+            }
+
+            #[test]
+            fn add_contains_rm_sanity()
+            {
+                subject().add_contains_rm_sanity();
+            }
+
+            #[test]
+            fn records_survive_restart()
+            {
+                subject().records_survive_restart();
+            }
+
+            #[test]
+            fn keys_iter_and_scan_prefix()
+            {
+                subject().keys_iter_and_scan_prefix();
+            }
+
+            #[test]
+            fn batches_commit_together()
+            {
+                subject().batches_commit_together();
+            }
+
+            #[test]
+            fn concurrent_readers_and_writers()
+            {
+                subject().concurrent_readers_and_writers();
+            }
+
+            #[test]
+            fn behaves_like_a_hash_map()
+            {
+                subject().behaves_like_a_hash_map();
+            }
+        }
+    };
+);
+
+/// A `Table` implementation under test.
+pub struct Subject<T>
+{
+    pub name: &'static str,
+    /// Opens the table kept in a directory. Called again on the same
+    /// directory to simulate a restart.
+    pub open: fn(&Path) -> T,
+    /// Whether the table's records survive a restart
+    pub durable: bool,
+}
+
+/// One step of a model-checked sequence.
+#[derive(Clone, Debug)]
+enum Op
+{
+    Set(String, String),
+    Get(String),
+    ContainsKey(String),
+    Remove(String),
+    /// Sets (or, given None, removes) each key, as one batch
+    Commit(Vec<(String, Option<String>)>),
+    Keys,
+    Iter,
+    ScanPrefix(String),
+    Persist,
+    Restart,
+}
+
+/// A few keys, so that sequences revisit them, including some which
+/// need encoding to be stored as file names.
+fn key() -> impl Strategy<Value = String>
+{
+    prop::sample::select(vec!["cat", "Cat", "cats", "c", "", "..", "a/b", "ünï"])
+        .prop_map(String::from)
+}
+
+fn op() -> impl Strategy<Value = Op>
+{
+    prop_oneof![
+        4 => (key(), "[a-z]{0,8}").prop_map(|(k, v)| Op::Set(k, v)),
+        3 => key().prop_map(Op::Get),
+        1 => key().prop_map(Op::ContainsKey),
+        2 => key().prop_map(Op::Remove),
+        1 => prop::collection::vec((key(), prop::option::of("[a-z]{0,8}")), 0..4).prop_map(Op::Commit),
+        1 => Just(Op::Keys),
+        1 => Just(Op::Iter),
+        1 => prop::sample::select(vec!["", "c", "ca", "x"]).prop_map(|p| Op::ScanPrefix(String::from(p))),
+        1 => Just(Op::Persist),
+        1 => Just(Op::Restart),
+    ]
+}
+
+fn sorted<T: Ord>(mut v: Vec<T>) -> Vec<T>
+{
+    v.sort();
+    v
+}
+
+impl<T> Subject<T>
+    where T: Table<String, String> + Sync
+{
+    /// A fresh, empty directory for `test`
+    fn scratch(&self, test: &str) -> PathBuf
+    {
+        let mut path = std::env::temp_dir();
+        path.push("img-forest-test");
+        path.push("conformance");
+        path.push(format!("{}-{}", self.name, test));
+
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        path
+    }
+
+    /// Drop the table and open it again, if it's one that survives that.
+    fn restart(&self, t: T, path: &Path) -> T
+    {
+        if self.durable
+        {
+            drop(t);
+            (self.open)(path)
+        }
+        else
+        {
+            t
+        }
+    }
+
+    pub fn add_contains_rm_sanity(&self)
+    {
+        let t = (self.open)(&self.scratch("add_contains_rm_sanity"));
+        let (foo, bar) = (String::from("foo"), String::from("bar"));
+
+        assert!(!t.contains_key(&foo));
+        assert_eq!(None, t.set(foo.clone(), bar.clone()));
+        assert!(t.contains_key(&foo));
+        assert_eq!(Some(&bar), t.get(&foo).as_deref());
+        assert_eq!(Some(&bar), t.remove(&foo).as_deref());
+        assert!(!t.contains_key(&foo));
+        assert!(matches!(t.try_get(&foo), Ok(None)));
+    }
+
+    pub fn records_survive_restart(&self)
+    {
+        if !self.durable
+        {
+            return;
+        }
+
+        let path = self.scratch("records_survive_restart");
+        let t = (self.open)(&path);
+        for k in &["kept", "updated", "removed"]
+        {
+            t.set(String::from(*k), String::from("old"));
+        }
+        t.persist().unwrap();
+
+        t.set(String::from("updated"), String::from("new"));
+        t.remove(&String::from("removed"));
+        t.persist().unwrap();
+
+        let t = self.restart(t, &path);
+        assert_eq!(Some(&String::from("old")), t.get(&String::from("kept")).as_deref());
+        assert_eq!(Some(&String::from("new")), t.get(&String::from("updated")).as_deref());
+        assert!(!t.contains_key(&String::from("removed")));
+    }
+
+    pub fn keys_iter_and_scan_prefix(&self)
+    {
+        let path = self.scratch("keys_iter_and_scan_prefix");
+        let t = (self.open)(&path);
+        for k in &["nutty", "nutmeg", "chipper"]
+        {
+            t.set(String::from(*k), k.to_uppercase());
+        }
+        t.persist().unwrap();
+        t.remove(&String::from("nutmeg"));
+
+        assert_eq!(vec!["chipper", "nutty"], sorted(t.keys()));
+        assert_eq!(
+            vec![(String::from("chipper"), Arc::new(String::from("CHIPPER"))),
+                 (String::from("nutty"), Arc::new(String::from("NUTTY")))],
+            sorted(t.iter().collect()));
+        assert_eq!(vec!["nutty"], t.scan_prefix("nut"));
+        assert!(t.scan_prefix("blitz").is_empty());
+
+        t.persist().unwrap();
+        let t = self.restart(t, &path);
+        assert_eq!(vec!["chipper", "nutty"], sorted(t.keys()));
+    }
+
+    pub fn batches_commit_together(&self)
+    {
+        let path = self.scratch("batches_commit_together");
+        let t = (self.open)(&path);
+        t.set(String::from("old"), String::from("old"));
+
+        let mut batch = t.begin();
+        batch
+            .set(String::from("nutty"), String::from("1"))
+            .set(String::from("chipper"), String::from("2"))
+            .remove(String::from("old"))
+            .set(String::from("nutty"), String::from("3"));
+        assert_eq!(4, batch.len());
+        // Nothing's changed until it's committed
+        assert!(!t.contains_key(&String::from("nutty")));
+        t.commit(batch).unwrap();
+
+        let mut aborted = t.begin();
+        aborted.remove(String::from("nutty"));
+        aborted.abort();
+        t.commit(Batch::new()).unwrap();
+
+        let t = self.restart(t, &path);
+        assert_eq!(vec!["chipper", "nutty"], sorted(t.keys()));
+        assert_eq!(Some(&String::from("3")), t.get(&String::from("nutty")).as_deref());
+    }
+
+    pub fn concurrent_readers_and_writers(&self)
+    {
+        let path = self.scratch("concurrent_readers_and_writers");
+        let t = (self.open)(&path);
+
+        std::thread::scope(|scope| {
+            for w in 0..4
+            {
+                let t = &t;
+                scope.spawn(move || {
+                    for n in 0..50
+                    {
+                        t.set(format!("{}-{}", w, n % 10), n.to_string());
+                    }
+                });
+                scope.spawn(move || {
+                    for n in 0..50
+                    {
+                        if let Some(v) = t.get(&format!("{}-{}", w, n % 10))
+                        {
+                            assert!(v.parse::<u32>().unwrap() < 50);
+                        }
+                    }
+                });
+            }
+        });
+        t.persist().unwrap();
+
+        let t = self.restart(t, &path);
+        assert_eq!(40, t.keys().len());
+        assert_eq!(Some(&String::from("49")), t.get(&String::from("3-9")).as_deref());
+    }
+
+    /// Random sequences of operations give the same results as they would
+    /// on a `HashMap`.
+    pub fn behaves_like_a_hash_map(&self)
+    {
+        let config = Config { cases: 64, failure_persistence: None, ..Config::default() };
+        let mut runner = TestRunner::new(config);
+
+        let result = runner.run(&prop::collection::vec(op(), 1..40), |ops| {
+            let path = self.scratch("behaves_like_a_hash_map");
+            let mut t = (self.open)(&path);
+            let mut model: HashMap<String, String> = HashMap::new();
+
+            for op in ops
+            {
+                match op
+                {
+                    Op::Set(k, v) =>
+                    {
+                        let old = t.set(k.clone(), v.clone());
+                        let expected = model.insert(k, v);
+                        // Tables needn't look up the old value, but mustn't
+                        // return the wrong one
+                        prop_assert!(old.is_none() || old.as_deref() == expected.as_ref());
+                    },
+                    Op::Get(k) =>
+                        prop_assert_eq!(model.get(&k).cloned(), t.get(&k).map(|v| (*v).clone())),
+                    Op::ContainsKey(k) =>
+                        prop_assert_eq!(model.contains_key(&k), t.contains_key(&k)),
+                    Op::Remove(k) =>
+                    {
+                        let old = t.remove(&k);
+                        let expected = model.remove(&k);
+                        prop_assert!(old.is_none() || old.as_deref() == expected.as_ref());
+                    },
+                    Op::Commit(changes) =>
+                    {
+                        let mut batch = t.begin();
+                        for (k, v) in changes
+                        {
+                            match v
+                            {
+                                Some(v) =>
+                                {
+                                    batch.set(k.clone(), v.clone());
+                                    model.insert(k, v);
+                                },
+                                None =>
+                                {
+                                    batch.remove(k.clone());
+                                    model.remove(&k);
+                                },
+                            }
+                        }
+                        prop_assert!(t.commit(batch).is_ok());
+                    },
+                    Op::Keys =>
+                        prop_assert_eq!(sorted(model.keys().cloned().collect()), sorted(t.keys())),
+                    Op::Iter =>
+                        prop_assert_eq!(
+                            sorted(model.iter().map(|(k, v)| (k.clone(), v.clone())).collect()),
+                            sorted(t.iter().map(|(k, v)| (k, (*v).clone())).collect::<Vec<_>>())),
+                    Op::ScanPrefix(p) =>
+                        prop_assert_eq!(
+                            sorted(model.keys().filter(|k| k.starts_with(&p)).cloned().collect()),
+                            sorted(t.scan_prefix(&p))),
+                    Op::Persist =>
+                        prop_assert!(t.persist().is_ok()),
+                    Op::Restart =>
+                        t = self.restart(t, &path),
+                }
+            }
+
+            // Everything is still there after a restart, persisted or not
+            let t = self.restart(t, &path);
+            prop_assert_eq!(sorted(model.keys().cloned().collect()), sorted(t.keys()));
+            for (k, v) in &model
+            {
+                prop_assert_eq!(Some(v.clone()), t.get(k).map(|v| (*v).clone()));
+            }
+
+            Ok(())
+        });
+
+        if let Err(e) = result
+        {
+            panic!("{}", e);
+        }
+    }
+}
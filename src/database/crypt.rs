@@ -0,0 +1,185 @@
+// The keys records are encrypted with, when a store is encrypted at rest.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::{ Aead, AeadCore, KeyInit, OsRng, Payload, };
+use sha2::{ Digest, Sha256, };
+
+use super::CodecError;
+
+/// Names a key, without giving it away: the start of its SHA-256 hash.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct KeyId([u8; 4]);
+
+impl fmt::Display for KeyId
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        for b in &self.0
+        {
+            write!(f, "{:02x}", b)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for KeyId
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "KeyId({})", self)
+    }
+}
+
+const KEY_LEN: usize = 32;
+const ID_LEN: usize = 4;
+/// XChaCha20's, long enough that random nonces never repeat
+const NONCE_LEN: usize = 24;
+
+/// The keys a store is encrypted with. Data is sealed (with XChaCha20-
+/// Poly1305) under the current key, which is the last one given, and can
+/// be opened with any of them, so that a key can be rotated by adding a
+/// new one after it and re-encrypting.
+pub struct Keyring
+{
+    keys: Vec<(KeyId, XChaCha20Poly1305)>,
+}
+
+impl Keyring
+{
+    /// From raw 32 byte keys, the current one last. Fails if there are none.
+    pub fn new<I>(keys: I) -> io::Result<Self>
+        where I: IntoIterator<Item = [u8; KEY_LEN]>
+    {
+        let keys: Vec<_> = keys
+            .into_iter()
+            .map(|key| {
+                let mut id = [0; ID_LEN];
+                id.copy_from_slice(&Sha256::digest(key)[..ID_LEN]);
+                (KeyId(id), XChaCha20Poly1305::new(&key.into()))
+            })
+            .collect();
+
+        if keys.is_empty()
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "No keys given"));
+        }
+
+        Ok(Keyring { keys })
+    }
+
+    /// Reads a key file: a base64 encoded, 32 byte key per line, with the
+    /// current key last. Blank lines and those starting with `#` are
+    /// skipped.
+    pub fn load(path: &Path) -> io::Result<Self>
+    {
+        let mut keys = Vec::new();
+        for (n, line) in fs::read_to_string(path)?.lines().enumerate()
+        {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#')
+            {
+                continue;
+            }
+
+            let key = base64::decode(line)
+                .ok()
+                .and_then(|key| <[u8; KEY_LEN]>::try_from(key).ok())
+                .ok_or_else(|| io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}, line {}: Expected a base64 encoded, {} byte key", path.display(), n + 1, KEY_LEN)))?;
+            keys.push(key);
+        }
+
+        Self::new(keys)
+    }
+
+    pub fn current(&self) -> KeyId
+    {
+        self.keys[self.keys.len() - 1].0
+    }
+
+    /// `data`, encrypted under the current key and bound to `aad`, so that
+    /// it can't be passed off as something else.
+    pub(super) fn seal(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, CodecError>
+    {
+        let (id, cipher) = &self.keys[self.keys.len() - 1];
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = cipher.encrypt(&nonce, Payload { msg: data, aad })
+            .map_err(|_| "Unable to encrypt")?;
+
+        let mut out = Vec::with_capacity(ID_LEN + NONCE_LEN + sealed.len());
+        out.extend_from_slice(&id.0);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&sealed);
+
+        Ok(out)
+    }
+
+    /// What was sealed with `aad`, or an error if it was sealed with
+    /// something else or has been tampered with. Fails with
+    /// `Unsealed::MissingKey` if it was sealed under a key not given.
+    pub(super) fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Unsealed>
+    {
+        let id = sealed_with(sealed).ok_or(Unsealed::Corrupt("Truncated before the ciphertext".into()))?;
+        let (_, cipher) = self.keys
+            .iter()
+            .find(|(k, _)| *k == id)
+            .ok_or(Unsealed::MissingKey(id))?;
+
+        let nonce = &sealed[ID_LEN..ID_LEN + NONCE_LEN];
+        cipher.decrypt(nonce.into(), Payload { msg: &sealed[ID_LEN + NONCE_LEN..], aad })
+            .map_err(|_| Unsealed::Corrupt("Doesn't decrypt, it's been altered or isn't what it claims to be".into()))
+    }
+
+    /// Whether `sealed` was sealed with the current key.
+    pub(super) fn is_current(&self, sealed: &[u8]) -> bool
+    {
+        sealed_with(sealed) == Some(self.current())
+    }
+}
+
+/// A new random key, as a `Keyring` takes.
+pub(super) fn new_key() -> [u8; KEY_LEN]
+{
+    XChaCha20Poly1305::generate_key(&mut OsRng).into()
+}
+
+/// Never shows the keys themselves.
+impl fmt::Debug for Keyring
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.debug_struct("Keyring")
+            .field("keys", &self.keys.iter().map(|(id, _)| id).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// The id of the key `sealed` was sealed with.
+pub(super) fn sealed_with(sealed: &[u8]) -> Option<KeyId>
+{
+    if sealed.len() < ID_LEN + NONCE_LEN
+    {
+        return None;
+    }
+
+    let mut id = [0; ID_LEN];
+    id.copy_from_slice(&sealed[..ID_LEN]);
+
+    Some(KeyId(id))
+}
+
+/// Why sealed data couldn't be opened.
+#[derive(Debug)]
+pub(super) enum Unsealed
+{
+    MissingKey(KeyId),
+    Corrupt(CodecError),
+}
@@ -0,0 +1,405 @@
+// Secondary indexes over the values of a table.
+
+use std::collections::{ HashMap, HashSet, };
+use std::fmt::Display;
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{ Arc, RwLock, };
+
+use serde::{ Deserialize, Serialize, };
+use serde::de::DeserializeOwned;
+
+use super::{
+    read, record, sync_dir, write, write_atomic,
+    Access, Batch, BatchOp, Bincode, Codec, Compression, DatabaseError, DiskCacheOptions, Keyring, PersistError,
+    ScrubReport, Table, TableStats,
+};
+
+/// A secondary index: the records of a table, looked up by a field of their
+/// values rather than by key.
+pub struct Index<V>
+{
+    name: &'static str,
+    term: fn(&V) -> String,
+}
+
+impl<V> Index<V>
+{
+    /// Indexes each value under `term(value)`.
+    pub fn new(name: &'static str, term: fn(&V) -> String) -> Self
+    {
+        Index { name, term }
+    }
+}
+
+/// A table with secondary indexes over its values, kept up to date as the
+/// table is changed through it.
+///
+/// The indexes are saved to `path` by `persist`. The saved copy is removed
+/// before the next change is made, so if it's there on open, it's up to
+/// date. If it isn't, the indexes are rebuilt by reading every record.
+///
+/// `set` and `remove` go through `try_set` and `try_remove`, so that only
+/// changes which are made are indexed. Changes made to the table other than
+/// through this aren't indexed, until the next `rebuild`.
+pub struct IndexedTable<K,V,T>
+{
+    table: T,
+    indexes: Vec<Index<V>>,
+    path: PathBuf,
+    access: Access,
+    compression: Compression,
+    keyring: Option<Arc<Keyring>>,
+    /// Held for writing across each change, so the indexes always match
+    /// the table
+    state: RwLock<IndexState<K>>,
+}
+
+struct IndexState<K>
+{
+    /// For each index, the keys under each term
+    postings: Vec<HashMap<String, HashSet<K>>>,
+    /// The terms each key is indexed under, one for each index
+    terms: HashMap<K, Vec<String>>,
+    /// Whether the indexes are saved at `path` as they are now
+    saved: bool,
+}
+
+/// The indexes, as saved. The postings are rebuilt from the terms.
+#[derive(Deserialize, Serialize)]
+struct SavedIndexes<K>
+{
+    names: Vec<String>,
+    terms: Vec<(K, Vec<String>)>,
+}
+
+impl<K> IndexState<K>
+    where K: Clone + Eq + Hash
+{
+    fn new(indexes: usize) -> Self
+    {
+        IndexState
+        {
+            postings: vec![HashMap::new(); indexes],
+            terms: HashMap::new(),
+            saved: false,
+        }
+    }
+
+    /// Index `k` under `terms`, or, given None, not at all.
+    fn reindex(&mut self, k: K, terms: Option<Vec<String>>)
+    {
+        if let Some(old) = self.terms.remove(&k)
+        {
+            for (postings, term) in self.postings.iter_mut().zip(old)
+            {
+                if let Some(keys) = postings.get_mut(&term)
+                {
+                    keys.remove(&k);
+                    if keys.is_empty()
+                    {
+                        postings.remove(&term);
+                    }
+                }
+            }
+        }
+
+        if let Some(terms) = terms
+        {
+            for (postings, term) in self.postings.iter_mut().zip(&terms)
+            {
+                postings.entry(term.clone()).or_default().insert(k.clone());
+            }
+            self.terms.insert(k, terms);
+        }
+    }
+}
+
+impl<K,V,T> IndexedTable<K,V,T>
+    where K: Clone + Eq + Hash + DeserializeOwned + Serialize,
+          T: Table<K,V>
+{
+    /// Indexes `table` with `indexes`, saved at `path`, and compressed and
+    /// encrypted there as `options` say. The saved indexes are only written
+    /// (or removed) with `Access::Exclusive`.
+    pub fn open(table: T, path: PathBuf, indexes: Vec<Index<V>>, options: &DiskCacheOptions)
+        -> Result<Self, DatabaseError>
+    {
+        let indexed = IndexedTable
+        {
+            state: RwLock::new(IndexState::new(indexes.len())),
+            table,
+            indexes,
+            path,
+            access: options.access,
+            compression: options.compression,
+            keyring: options.keyring.clone(),
+        };
+
+        match indexed.load()
+        {
+            Ok(Some(state)) =>
+                *write(&indexed.state) = state,
+            Ok(None) =>
+            {
+                indexed.rebuild()?;
+            },
+            Err(e @ DatabaseError::MissingKey { .. }) =>
+                return Err(e),
+            Err(e) =>
+            {
+                eprintln!("Unable to load the indexes at {:?}, rebuilding them: {}", indexed.path, e);
+                indexed.rebuild()?;
+            },
+        }
+
+        Ok(indexed)
+    }
+
+    /// The keys of the records whose values are under `term` in the index
+    /// named `index`, in no particular order.
+    pub fn query(&self, index: &str, term: &str) -> Result<Vec<K>, DatabaseError>
+    {
+        let n = self.indexes
+            .iter()
+            .position(|i| i.name == index)
+            .ok_or_else(|| DatabaseError::NoSuchIndex(String::from(index)))?;
+        let state = read(&self.state);
+        let keys = state.postings[n]
+            .get(term)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default();
+
+        Ok(keys)
+    }
+
+    /// Rebuild the indexes from every record in the table, returning how
+    /// many records were indexed. Records which can't be read are left out.
+    pub fn rebuild(&self) -> Result<usize, DatabaseError>
+    {
+        let mut state = write(&self.state);
+        self.unsave(&mut state)?;
+
+        let mut rebuilt = IndexState::new(self.indexes.len());
+        for (k, v) in self.table.iter()
+        {
+            rebuilt.reindex(k, Some(self.terms(&v)));
+        }
+
+        let indexed = rebuilt.terms.len();
+        *state = rebuilt;
+
+        Ok(indexed)
+    }
+
+    /// The indexed table, for changes which needn't be indexed.
+    #[allow(dead_code)]
+    pub fn inner(&self) -> &T
+    {
+        &self.table
+    }
+
+    fn terms(&self, v: &V) -> Vec<String>
+    {
+        self.indexes.iter().map(|i| (i.term)(v)).collect()
+    }
+
+    /// The saved indexes, unless there aren't any, or they're of other
+    /// indexes.
+    fn load(&self) -> Result<Option<IndexState<K>>, DatabaseError>
+    {
+        let data = match fs::read(&self.path)
+        {
+            Ok(data) =>
+                data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound =>
+                return Ok(None),
+            Err(e) =>
+                return Err(DatabaseError::Io(e)),
+        };
+        let (_, body) = record::unpack(&self.key(), &data, self.keyring.as_deref())?;
+        let saved: SavedIndexes<K> = Bincode::decode(&body).map_err(DatabaseError::Corrupt)?;
+        if !saved.names.iter().eq(self.indexes.iter().map(|i| i.name))
+        {
+            return Ok(None);
+        }
+
+        let mut state = IndexState::new(self.indexes.len());
+        for (k, terms) in saved.terms
+        {
+            state.reindex(k, Some(terms));
+        }
+        state.saved = true;
+
+        Ok(Some(state))
+    }
+
+    /// Save the indexes, unless they're saved as they are already.
+    fn save(&self, state: &mut IndexState<K>) -> Result<(), DatabaseError>
+    {
+        if state.saved || self.access == Access::ReadOnly
+        {
+            return Ok(());
+        }
+
+        let saved = SavedIndexes
+        {
+            names: self.indexes.iter().map(|i| String::from(i.name)).collect(),
+            terms: state.terms.iter().map(|(k, terms)| (k.clone(), terms.clone())).collect(),
+        };
+        let body = Bincode::encode(&saved).map_err(DatabaseError::Encoding)?;
+        let data = record::pack(&self.key(), 0, &body, self.compression, self.keyring.as_deref())?;
+        write_atomic(&self.path, &data)?;
+        state.saved = true;
+
+        Ok(())
+    }
+
+    /// Remove the saved indexes, before a change makes them out of date.
+    fn unsave(&self, state: &mut IndexState<K>) -> Result<(), DatabaseError>
+    {
+        if !state.saved || self.access == Access::ReadOnly
+        {
+            return Ok(());
+        }
+
+        match fs::remove_file(&self.path)
+        {
+            Ok(()) =>
+                sync_dir(self.path.parent())?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound =>
+                {},
+            Err(e) =>
+                return Err(DatabaseError::Io(e)),
+        }
+        state.saved = false;
+
+        Ok(())
+    }
+
+    /// What the saved indexes are sealed as, like a record's key.
+    fn key(&self) -> String
+    {
+        self.path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+    }
+}
+
+impl<K,V,T> Table<K,V> for IndexedTable<K,V,T>
+    where K: Clone + Eq + Hash + DeserializeOwned + Serialize,
+          T: Table<K,V>
+{
+    fn try_set(&self, k: K, v: V) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        let terms = self.terms(&v);
+        let mut state = write(&self.state);
+        self.unsave(&mut state)?;
+        let old = self.table.try_set(k.clone(), v)?;
+        state.reindex(k, Some(terms));
+
+        Ok(old)
+    }
+
+    fn try_get(&self, k: &K) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        self.table.try_get(k)
+    }
+
+    fn try_contains_key(&self, k: &K) -> Result<bool, DatabaseError>
+    {
+        self.table.try_contains_key(k)
+    }
+
+    fn try_remove(&self, k: &K) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        let mut state = write(&self.state);
+        self.unsave(&mut state)?;
+        let old = self.table.try_remove(k)?;
+        state.reindex(k.clone(), None);
+
+        Ok(old)
+    }
+
+    fn begin(&self) -> Batch<K,V>
+    {
+        self.table.begin()
+    }
+
+    fn commit(&self, batch: Batch<K,V>) -> Result<(), DatabaseError>
+    {
+        let changes: Vec<_> = batch.ops()
+            .iter()
+            .map(|op| match op
+            {
+                BatchOp::Set(k, v) =>
+                    (k.clone(), Some(self.terms(v))),
+                BatchOp::Remove(k) =>
+                    (k.clone(), None),
+            })
+            .collect();
+
+        let mut state = write(&self.state);
+        self.unsave(&mut state)?;
+        self.table.commit(batch)?;
+        for (k, terms) in changes
+        {
+            state.reindex(k, terms);
+        }
+
+        Ok(())
+    }
+
+    fn keys(&self) -> Vec<K>
+    {
+        self.table.keys()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, Arc<V>)> + '_>
+    {
+        self.table.iter()
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Vec<K>
+        where K: Display
+    {
+        self.table.scan_prefix(prefix)
+    }
+
+    /// The indexes are only saved once the table's persisted.
+    fn persist(&self) -> Result<usize, PersistError<K>>
+    {
+        let persisted = self.table.persist()?;
+        self.save(&mut write(&self.state))
+            .map_err(|e| PersistError { failed: Vec::new(), journal: Some(e) })?;
+
+        Ok(persisted)
+    }
+
+    fn scrub(&self, quarantine: bool) -> io::Result<ScrubReport>
+    {
+        self.table.scrub(quarantine)
+    }
+
+    fn stats(&self) -> TableStats
+    {
+        self.table.stats()
+    }
+
+    /// The saved indexes are saved again, under the current key.
+    fn reencrypt(&self) -> Result<(usize, usize), DatabaseError>
+    {
+        let reencrypted = self.table.reencrypt()?;
+        if self.keyring.is_some()
+        {
+            let mut state = write(&self.state);
+            if state.saved
+            {
+                state.saved = false;
+                self.save(&mut state)?;
+            }
+        }
+
+        Ok(reencrypted)
+    }
+}
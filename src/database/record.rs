@@ -0,0 +1,281 @@
+// The header at the start of each record a `DiskCache` writes, and the
+// migrations which upgrade records written with older schemas.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::sync::Arc;
+
+use super::{ CodecError, DatabaseError, };
+use super::compress::{ self, Compression, };
+use super::crypt::{ self, Keyring, Unsealed, };
+
+/// Starts every record with a header. The last byte is the version of the
+/// header's own format: version 1 gave the schema, version 2 added a CRC-32
+/// of the body after it, and version 3 added flags between the two.
+const MAGIC_V1: [u8; 4] = *b"IFR\x01";
+const MAGIC_V2: [u8; 4] = *b"IFR\x02";
+const MAGIC: [u8; 4] = *b"IFR\x03";
+const HEADER_LEN: usize = MAGIC.len() + 3 * std::mem::size_of::<u32>();
+
+/// The body is sealed, under the key the `Keyring` it names
+pub(super) const SEALED: u32 = 1;
+/// The body (before it's sealed) is compressed with LZ4
+pub(super) const LZ4: u32 = 1 << 1;
+/// The body (before it's sealed) is compressed with zstd
+pub(super) const ZSTD: u32 = 1 << 2;
+/// Every flag this version knows what to do with
+const KNOWN_FLAGS: u32 = SEALED | LZ4 | ZSTD;
+
+/// What a record's header says about its body.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) struct Header
+{
+    pub schema: u32,
+    pub flags: u32,
+}
+
+/// `body` behind a header saying what it is.
+pub(super) fn encode(header: Header, body: &[u8]) -> Vec<u8>
+{
+    let mut data = Vec::with_capacity(HEADER_LEN + body.len());
+    data.extend_from_slice(&MAGIC);
+    data.extend_from_slice(&header.schema.to_le_bytes());
+    data.extend_from_slice(&header.flags.to_le_bytes());
+    data.extend_from_slice(&crc32fast::hash(body).to_le_bytes());
+    data.extend_from_slice(body);
+
+    data
+}
+
+/// The header and body of a record, once its checksum has been verified.
+/// Records written before there were headers have none, and are schema 0.
+/// Neither they nor those with version 1 headers have checksums to verify.
+/// Only version 3 headers have flags.
+pub(super) fn decode(data: &[u8]) -> Result<(Header, &[u8]), CodecError>
+{
+    if let Some(rest) = data.strip_prefix(&MAGIC[..])
+    {
+        if rest.len() < HEADER_LEN - MAGIC.len()
+        {
+            return Err("Record truncated within its header".into());
+        }
+
+        let (schema, rest) = rest.split_at(4);
+        let (flags, rest) = rest.split_at(4);
+        let body = checked(rest)?;
+        let header = Header { schema: le_u32(schema), flags: le_u32(flags) };
+        if header.flags & !KNOWN_FLAGS != 0
+        {
+            return Err(format!("Record has flags {:#x}, which are unknown to this version", header.flags).into());
+        }
+        if header.flags & (LZ4 | ZSTD) == LZ4 | ZSTD
+        {
+            return Err("Record claims to be compressed two ways".into());
+        }
+
+        Ok((header, body))
+    }
+    else if let Some(rest) = data.strip_prefix(&MAGIC_V2[..])
+    {
+        if rest.len() < 2 * 4
+        {
+            return Err("Record truncated within its header".into());
+        }
+
+        let (schema, rest) = rest.split_at(4);
+        Ok((Header { schema: le_u32(schema), flags: 0 }, checked(rest)?))
+    }
+    else if let Some(rest) = data.strip_prefix(&MAGIC_V1[..]).filter(|rest| rest.len() >= 4)
+    {
+        let (schema, body) = rest.split_at(4);
+        Ok((Header { schema: le_u32(schema), flags: 0 }, body))
+    }
+    else
+    {
+        Ok((Header { schema: 0, flags: 0 }, data))
+    }
+}
+
+/// Whether `data` starts as a record written now does.
+pub(super) fn has_header(data: &[u8]) -> bool
+{
+    data.starts_with(&MAGIC)
+}
+
+/// The body after a checksum, if it matches.
+fn checked(data: &[u8]) -> Result<&[u8], CodecError>
+{
+    let (checksum, body) = data.split_at(4);
+    if crc32fast::hash(body) != le_u32(checksum)
+    {
+        // Bit rot, or a write cut short
+        return Err("Record doesn't match its checksum".into());
+    }
+
+    Ok(body)
+}
+
+/// The record for `key`, given its encoded value at `schema`: compressed as
+/// `compression` says (if that makes it smaller), then sealed if there's a
+/// `keyring`, bound to the key and schema so that it can't be passed off as
+/// another record.
+pub(super) fn pack(key: &str, schema: u32, body: &[u8], compression: Compression, keyring: Option<&Keyring>)
+    -> Result<Vec<u8>, DatabaseError>
+{
+    let mut flags = 0;
+    let mut body = Cow::Borrowed(body);
+    if let Some(compressed) = compress::compress(compression, &body).map_err(DatabaseError::Encoding)?
+    {
+        flags |= match compression
+        {
+            Compression::None =>
+                0,
+            Compression::Lz4 =>
+                LZ4,
+            Compression::Zstd(_) =>
+                ZSTD,
+        };
+        body = Cow::Owned(compressed);
+    }
+
+    if let Some(keyring) = keyring
+    {
+        flags |= SEALED;
+        body = Cow::Owned(keyring.seal(&aad(key, schema), &body).map_err(DatabaseError::Encoding)?);
+    }
+
+    Ok(encode(Header { schema, flags }, &body))
+}
+
+/// The schema and encoded value of `data`, the record for `key`, opened with
+/// `keyring` if it was sealed.
+pub(super) fn unpack<'a>(key: &str, data: &'a [u8], keyring: Option<&Keyring>) -> Result<(u32, Cow<'a, [u8]>), DatabaseError>
+{
+    let (header, body) = decode(data).map_err(DatabaseError::Corrupt)?;
+    let mut body = Cow::Borrowed(body);
+    if header.flags & SEALED != 0
+    {
+        let keyring = keyring.ok_or(DatabaseError::MissingKey { id: crypt::sealed_with(&body) })?;
+        body = match keyring.open(&aad(key, header.schema), &body)
+        {
+            Ok(body) =>
+                Cow::Owned(body),
+            Err(Unsealed::MissingKey(id)) =>
+                return Err(DatabaseError::MissingKey { id: Some(id) }),
+            Err(Unsealed::Corrupt(e)) =>
+                return Err(DatabaseError::Corrupt(e)),
+        };
+    }
+
+    let compression = if header.flags & LZ4 != 0
+    {
+        Compression::Lz4
+    }
+    else if header.flags & ZSTD != 0
+    {
+        Compression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+    else
+    {
+        return Ok((header.schema, body));
+    };
+
+    let body = compress::decompress(compression, &body).map_err(DatabaseError::Corrupt)?;
+    Ok((header.schema, Cow::Owned(body)))
+}
+
+/// Whether `data` has to be rewritten for it to be sealed under the current
+/// key of `keyring`.
+pub(super) fn needs_sealing(data: &[u8], keyring: &Keyring) -> bool
+{
+    match decode(data)
+    {
+        Ok((header, body)) =>
+            header.flags & SEALED == 0 || !keyring.is_current(body),
+        Err(_) =>
+            false,
+    }
+}
+
+fn aad(key: &str, schema: u32) -> Vec<u8>
+{
+    let mut aad = schema.to_le_bytes().to_vec();
+    aad.extend_from_slice(key.as_bytes());
+    aad
+}
+
+fn le_u32(bytes: &[u8]) -> u32
+{
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+type Step = dyn Fn(&str, &[u8]) -> Result<Vec<u8>, CodecError> + Send + Sync;
+
+/// Upgrades the records of a store whose values have changed shape, so
+/// that those written before a change can still be read. Each step takes
+/// a record (given its key and encoded value) from one schema version to
+/// the next.
+#[derive(Clone, Default)]
+pub struct Migrations
+{
+    current: u32,
+    steps: BTreeMap<u32, Arc<Step>>,
+}
+
+impl Migrations
+{
+    /// For values now at schema version `current`, before any steps are added.
+    /// Values which have never changed are at version 0.
+    pub fn new(current: u32) -> Self
+    {
+        Migrations { current, steps: BTreeMap::new() }
+    }
+
+    /// Adds `step`, which upgrades a record from schema version `from` to
+    /// the one after.
+    pub fn step<F>(mut self, from: u32, step: F) -> Self
+        where F: Fn(&str, &[u8]) -> Result<Vec<u8>, CodecError> + Send + Sync + 'static
+    {
+        self.steps.insert(from, Arc::new(step));
+        self
+    }
+
+    pub fn current(&self) -> u32
+    {
+        self.current
+    }
+
+    /// `body`, the record for `key` at schema version `schema`, upgraded to
+    /// the current version.
+    pub fn upgrade(&self, key: &str, schema: u32, body: &[u8]) -> Result<Vec<u8>, DatabaseError>
+    {
+        let mut body = body.to_vec();
+        for from in schema..self.current
+        {
+            let step = self.steps.get(&from)
+                .ok_or(DatabaseError::UnsupportedSchema { found: schema, current: self.current })?;
+            body = step(key, &body).map_err(DatabaseError::Corrupt)?;
+        }
+
+        if schema > self.current
+        {
+            // Written by something newer
+            return Err(DatabaseError::UnsupportedSchema { found: schema, current: self.current });
+        }
+
+        Ok(body)
+    }
+}
+
+impl fmt::Debug for Migrations
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.debug_struct("Migrations")
+            .field("current", &self.current)
+            .field("steps", &self.steps.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
@@ -0,0 +1,285 @@
+// Checking the files of a `DiskCache` for damage, and moving aside those it
+// can't use.
+
+use std::fmt::{ self, Display, };
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::path::{ Path, PathBuf, };
+use std::str::FromStr;
+use std::time::{ SystemTime, UNIX_EPOCH, };
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::{
+    decode_key, encode_key, is_shard_name, lock, record, record_path, write,
+    Codec, DatabaseError, DiskCache, Layout, RESERVED_PREFIX,
+};
+
+/// Where `scrub` moves what it quarantines, under a directory per scrub
+const QUARANTINE_DIR: &str = ".quarantine";
+
+/// What's wrong with a file found by `scrub`.
+#[derive(Debug)]
+pub enum Damage
+{
+    /// A record which doesn't match its checksum, or doesn't decode
+    Corrupt(String),
+    /// A file the store never reads: left by an interrupted write, not
+    /// named for a key, or not where its key's record is kept
+    Orphaned,
+    /// A record which couldn't be read, perhaps only for now
+    Unreadable(String),
+}
+
+/// What `scrub` did about a damaged file.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Remedy
+{
+    /// Nothing, it's only reported
+    None,
+    /// Left for the next persist, which replaces it with what's in memory
+    Persist,
+    /// Moved to the given path
+    Quarantined(PathBuf),
+}
+
+#[derive(Debug)]
+pub struct Finding
+{
+    pub path: PathBuf,
+    pub damage: Damage,
+    pub remedy: Remedy,
+}
+
+#[derive(Debug, Default)]
+pub struct ScrubReport
+{
+    /// How many records were checked
+    pub checked: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl Display for Damage
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            Damage::Corrupt(why) =>
+                write!(f, "corrupt ({})", why),
+            Damage::Orphaned =>
+                write!(f, "orphaned"),
+            Damage::Unreadable(why) =>
+                write!(f, "unreadable ({})", why),
+        }
+    }
+}
+
+impl Display for Finding
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "{}: {}", self.path.display(), self.damage)?;
+        match &self.remedy
+        {
+            Remedy::None =>
+                Ok(()),
+            Remedy::Persist =>
+                write!(f, ", to be rewritten from memory"),
+            Remedy::Quarantined(to) =>
+                write!(f, ", moved to {}", to.display()),
+        }
+    }
+}
+
+impl<K,V,C> DiskCache<K,V,C>
+    where K: Clone + Display + Eq + FromStr + Hash + DeserializeOwned + Serialize,
+          V: DeserializeOwned + Serialize,
+          C: Codec
+{
+    /// Check every file under `base_path`, reporting records which are
+    /// corrupt or unreadable and files which are orphaned.
+    ///
+    /// With `quarantine`, damaged records which are still in memory are
+    /// left for the next persist to replace, and the rest (and orphaned
+    /// files) are moved under `.quarantine/`, so that the store no longer
+    /// sees them. Unreadable records are only reported, as they may not
+    /// be damaged. Records in older schemas are only checked against
+    /// their checksums, as upgrading them may have side effects. A
+    /// read-only store can't quarantine anything.
+    pub fn scrub(&self, quarantine: bool) -> io::Result<ScrubReport>
+    {
+        if quarantine
+        {
+            self.writable().map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+        }
+
+        let mut report = ScrubReport::default();
+        let mut damaged = Vec::new();
+        for (path, key) in self.files()?
+        {
+            if key.is_some()
+            {
+                report.checked += 1;
+            }
+            if let Some(damage) = self.inspect(&path, key.as_ref())
+            {
+                damaged.push((path, key, damage));
+            }
+        }
+
+        if !quarantine
+        {
+            report.findings = damaged
+                .into_iter()
+                .map(|(path, _, damage)| Finding { path, damage, remedy: Remedy::None })
+                .collect();
+
+            return Ok(report);
+        }
+
+        // No record is written or removed while this is held, so none is
+        // moved aside as it's being replaced
+        let _persisting = lock(&self.persisting);
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let to_dir = self.base_path.join(QUARANTINE_DIR).join(since_epoch.as_secs().to_string());
+
+        for (path, key, _) in damaged
+        {
+            // Again, in case it's changed since
+            let damage = match self.inspect(&path, key.as_ref())
+            {
+                Some(damage) =>
+                    damage,
+                None =>
+                    continue,
+            };
+
+            let remedy = match (&damage, key)
+            {
+                (Damage::Unreadable(_), _) =>
+                    Remedy::None,
+                (Damage::Corrupt(_), Some(k)) if self.persist_replaces(&k) =>
+                    Remedy::Persist,
+                _ =>
+                {
+                    let to = to_dir.join(path.strip_prefix(&self.base_path).unwrap_or(&path));
+                    if let Some(dir) = to.parent()
+                    {
+                        fs::create_dir_all(dir)?;
+                    }
+                    fs::rename(&path, &to)?;
+                    Remedy::Quarantined(to)
+                },
+            };
+
+            report.findings.push(Finding { path, damage, remedy });
+        }
+
+        Ok(report)
+    }
+
+    /// Every file under `base_path` but the store's own, each with its key
+    /// if it's a record, or None if it's orphaned.
+    fn files(&self) -> io::Result<Vec<(PathBuf, Option<K>)>>
+    {
+        let mut files = Vec::new();
+        let mut dirs = vec![(self.base_path.clone(), 0)];
+        while let Some((dir, depth)) = dirs.pop()
+        {
+            for entry in fs::read_dir(&dir)?
+            {
+                let entry = entry?;
+                let path = entry.path();
+                let name = entry.file_name();
+                let name = name.to_str();
+
+                if entry.file_type()?.is_dir()
+                {
+                    // Anything else is none of the store's business
+                    if self.layout == Layout::Sharded && depth < 2 && name.is_some_and(is_shard_name)
+                    {
+                        dirs.push((path, depth + 1));
+                    }
+                    continue;
+                }
+
+                let reserved = name.is_some_and(|name| name.starts_with(RESERVED_PREFIX));
+                let temporary = name.is_some_and(|name| name.ends_with(".tmp"));
+                if depth == 0 && reserved && !temporary
+                {
+                    // Such as the journal and manifest
+                    continue;
+                }
+
+                let key = name
+                    .and_then(decode_key)
+                    .filter(|key| record_path(&self.base_path, self.layout, &encode_key(key)) == path)
+                    .and_then(|key| key.parse().ok());
+                files.push((path, key));
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// What's wrong with the file at `path`, the record for `key` or an
+    /// orphan, if anything, or if it's gone.
+    fn inspect(&self, path: &Path, key: Option<&K>) -> Option<Damage>
+    {
+        let key = match key
+        {
+            Some(key) =>
+                key.to_string(),
+            None =>
+                return path.exists().then_some(Damage::Orphaned),
+        };
+
+        let data = match fs::read(path)
+        {
+            Ok(data) =>
+                data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound =>
+                return None,
+            Err(e) =>
+                return Some(Damage::Unreadable(e.to_string())),
+        };
+
+        let current = self.migrations.current();
+        match record::unpack(&key, &data, self.keyring.as_deref())
+        {
+            Err(DatabaseError::Corrupt(e)) =>
+                Some(Damage::Corrupt(e.to_string())),
+            // Such as one encrypted with a key that wasn't given
+            Err(e) =>
+                Some(Damage::Unreadable(e.to_string())),
+            Ok((schema, body)) if schema == current =>
+                C::decode::<V>(&body).err().map(|e| Damage::Corrupt(e.to_string())),
+            Ok((schema, _)) if schema > current =>
+                Some(Damage::Unreadable(DatabaseError::UnsupportedSchema { found: schema, current }.to_string())),
+            Ok(_) =>
+                None,
+        }
+    }
+
+    /// Whether the next persist will replace the record for `k`, marking it
+    /// to be if it's held in memory.
+    fn persist_replaces(&self, k: &K) -> bool
+    {
+        let mut state = write(&self.state);
+        if state.disk_update_required.contains_key(k)
+        {
+            return true;
+        }
+        else if !state.cache.contains_key(k)
+        {
+            return false;
+        }
+
+        Self::mark_dirty(&mut state, k.clone());
+
+        true
+    }
+}
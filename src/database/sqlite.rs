@@ -0,0 +1,262 @@
+use std::fmt::Display;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex, };
+
+use rusqlite::{ params, params_from_iter, Connection, OptionalExtension, };
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::{ lock, Batch, BatchOp, Bincode, Codec, DatabaseError, Table, };
+
+/// A table kept in a single SQLite database file, with each value stored as
+/// a bincode blob under its key's string form.
+///
+/// Every change is written straight through, so there is nothing to persist.
+pub struct SqliteTable<K,V>
+{
+    conn: Mutex<Connection>,
+    // Keys and values are only stored encoded, never held
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K,V> SqliteTable<K,V>
+{
+    /// Opens the database at `path`, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> Result<Self, DatabaseError>
+    {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// A table which is lost when dropped.
+    #[allow(dead_code)]
+    pub fn in_memory() -> Result<Self, DatabaseError>
+    {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self, DatabaseError>
+    {
+        // WAL lets readers in other processes carry on during a write
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS records (
+                key   TEXT PRIMARY KEY NOT NULL,
+                value BLOB NOT NULL
+            )",
+            [])?;
+
+        Ok(SqliteTable { conn: Mutex::new(conn), types: PhantomData })
+    }
+}
+
+impl<K,V> SqliteTable<K,V>
+    where K: Display,
+          V: DeserializeOwned
+{
+    fn select(conn: &Connection, k: &K) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        let blob: Option<Vec<u8>> = conn
+            .query_row(
+                "SELECT value FROM records WHERE key = ?1",
+                params![k.to_string()],
+                |row| row.get(0))
+            .optional()?;
+
+        match blob
+        {
+            Some(blob) =>
+                Bincode::decode(&blob)
+                    .map(|v| Some(Arc::new(v)))
+                    .map_err(DatabaseError::Corrupt),
+            None =>
+                Ok(None),
+        }
+    }
+}
+
+impl<K,V> Table<K,V> for SqliteTable<K,V>
+    where K: Display + FromStr,
+          V: DeserializeOwned + Serialize
+{
+    fn try_set(&self, k: K, v: V) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        let blob = Bincode::encode(&v).map_err(DatabaseError::Encoding)?;
+
+        let mut conn = lock(&self.conn);
+        let tx = conn.transaction()?;
+        // A corrupt old value is still replaced
+        let old = match Self::select(&tx, &k)
+        {
+            Err(DatabaseError::Corrupt(_)) =>
+                None,
+            old =>
+                old?,
+        };
+        tx.execute(
+            "INSERT OR REPLACE INTO records (key, value) VALUES (?1, ?2)",
+            params![k.to_string(), blob])?;
+        tx.commit()?;
+
+        Ok(old)
+    }
+
+    fn try_get(&self, k: &K) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        Self::select(&lock(&self.conn), k)
+    }
+
+    fn try_contains_key(&self, k: &K) -> Result<bool, DatabaseError>
+    {
+        let found = lock(&self.conn)
+            .query_row(
+                "SELECT 1 FROM records WHERE key = ?1",
+                params![k.to_string()],
+                |_| Ok(()))
+            .optional()?;
+
+        Ok(found.is_some())
+    }
+
+    fn try_remove(&self, k: &K) -> Result<Option<Arc<V>>, DatabaseError>
+    {
+        let mut conn = lock(&self.conn);
+        let tx = conn.transaction()?;
+        // A corrupt old value is still removed
+        let old = match Self::select(&tx, k)
+        {
+            Err(DatabaseError::Corrupt(_)) =>
+                None,
+            old =>
+                old?,
+        };
+        tx.execute("DELETE FROM records WHERE key = ?1", params![k.to_string()])?;
+        tx.commit()?;
+
+        Ok(old)
+    }
+
+    /// In one SQLite transaction.
+    fn commit(&self, batch: Batch<K,V>) -> Result<(), DatabaseError>
+    {
+        let mut conn = lock(&self.conn);
+        let tx = conn.transaction()?;
+        for op in batch.ops()
+        {
+            match op
+            {
+                BatchOp::Set(k, v) =>
+                {
+                    let blob = Bincode::encode(v).map_err(DatabaseError::Encoding)?;
+                    tx.execute(
+                        "INSERT OR REPLACE INTO records (key, value) VALUES (?1, ?2)",
+                        params![k.to_string(), blob])?;
+                },
+                BatchOp::Remove(k) =>
+                {
+                    tx.execute("DELETE FROM records WHERE key = ?1", params![k.to_string()])?;
+                },
+            }
+        }
+        // Dropped without committing, the transaction is rolled back
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn keys(&self) -> Vec<K>
+        where K: Clone
+    {
+        self.try_keys().unwrap_or_else(|e| {
+            eprintln!("Unable to list the keys: {}", e);
+            Vec::new()
+        })
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (K, Arc<V>)> + '_>
+        where K: Clone
+    {
+        match self.try_iter()
+        {
+            Ok(records) =>
+                Box::new(records.into_iter()),
+            Err(e) =>
+            {
+                eprintln!("Unable to list the records: {}", e);
+                Box::new(std::iter::empty())
+            },
+        }
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Vec<K>
+        where K: Clone + Display
+    {
+        self.try_scan_prefix(prefix).unwrap_or_else(|e| {
+            eprintln!("Unable to list the keys starting {:?}: {}", prefix, e);
+            Vec::new()
+        })
+    }
+}
+
+impl<K,V> SqliteTable<K,V>
+    where K: FromStr,
+          V: DeserializeOwned
+{
+    /// Every key in the table, or why they couldn't be listed.
+    pub fn try_keys(&self) -> Result<Vec<K>, DatabaseError>
+    {
+        self.keys_where("SELECT key FROM records", &[])
+    }
+
+    /// The keys whose string form starts with `prefix`, or why they
+    /// couldn't be listed.
+    pub fn try_scan_prefix(&self, prefix: &str) -> Result<Vec<K>, DatabaseError>
+    {
+        self.keys_where(
+            "SELECT key FROM records WHERE substr(key, 1, length(?1)) = ?1",
+            &[prefix])
+    }
+
+    /// Every record in the table which can be read, or why they couldn't
+    /// be listed.
+    pub fn try_iter(&self) -> Result<Vec<(K, Arc<V>)>, DatabaseError>
+    {
+        let rows: Vec<(String, Vec<u8>)> = {
+            let conn = lock(&self.conn);
+            let mut stmt = conn.prepare("SELECT key, value FROM records")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<Result<_, _>>()?
+        };
+
+        // Like the other tables, skip records which can't be read
+        Ok(rows.into_iter()
+            .filter_map(|(k, blob)| {
+                let k = k.parse().ok()?;
+                let v = Bincode::decode(&blob).ok()?;
+
+                Some((k, Arc::new(v)))
+            })
+            .collect())
+    }
+
+    /// The keys selected by `query`, given `args`.
+    fn keys_where(&self, query: &str, args: &[&str]) -> Result<Vec<K>, DatabaseError>
+    {
+        let conn = lock(&self.conn);
+        let mut stmt = conn.prepare(query)?;
+        let keys = stmt.query_map(params_from_iter(args), |row| row.get::<_, String>(0))?;
+
+        let mut parsed = Vec::new();
+        for k in keys
+        {
+            // Skipped like unreadable records, as they can't be named
+            if let Ok(k) = k?.parse()
+            {
+                parsed.push(k);
+            }
+        }
+
+        Ok(parsed)
+    }
+}
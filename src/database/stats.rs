@@ -0,0 +1,119 @@
+// Counting what a table does, to tell how well its cache is working.
+
+use std::convert::TryFrom;
+use std::sync::atomic::{ AtomicU64, Ordering, };
+use std::time::Duration;
+
+use serde::Serialize;
+
+use super::EvictionStats;
+
+/// What a table has done since it was opened, and what it holds now, as
+/// `Table::stats` gives it. Tables count what applies to them, and leave
+/// the rest at zero.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct TableStats
+{
+    /// Reads answered from memory, including those of a record known to
+    /// have been removed
+    pub hits: u64,
+    /// Reads which had to go to storage, whether or not the record was there
+    pub misses: u64,
+    /// Records read from storage
+    pub loads: u64,
+    /// Bytes of records read from storage, as stored
+    pub bytes_read: u64,
+    /// Bytes of records written to storage, as stored
+    pub bytes_written: u64,
+    pub persists: u64,
+    /// How long all the persists took, in microseconds
+    pub persist_micros: u64,
+    /// How long the slowest persist took, in microseconds
+    pub max_persist_micros: u64,
+    /// Changes held in memory, still to be persisted
+    pub dirty: u64,
+    /// Records held in memory, and their serialized size
+    pub cached: u64,
+    pub cached_bytes: u64,
+    pub evictions: EvictionStats,
+}
+
+impl TableStats
+{
+    /// The share of reads answered from memory, if there have been any.
+    #[allow(dead_code)]
+    pub fn hit_rate(&self) -> Option<f64>
+    {
+        match self.hits + self.misses
+        {
+            0 =>
+                None,
+            reads =>
+                Some(self.hits as f64 / reads as f64),
+        }
+    }
+}
+
+/// The counts behind `TableStats`, which are bumped without taking a lock.
+#[derive(Default)]
+pub(super) struct Counters
+{
+    hits: AtomicU64,
+    misses: AtomicU64,
+    loads: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    persists: AtomicU64,
+    persist_micros: AtomicU64,
+    max_persist_micros: AtomicU64,
+}
+
+impl Counters
+{
+    pub(super) fn hit(&self)
+    {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn miss(&self)
+    {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A record of `bytes` was read from storage.
+    pub(super) fn loaded(&self, bytes: usize)
+    {
+        self.loads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn wrote(&self, bytes: usize)
+    {
+        self.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn persisted(&self, took: Duration)
+    {
+        let micros = u64::try_from(took.as_micros()).unwrap_or(u64::MAX);
+        self.persists.fetch_add(1, Ordering::Relaxed);
+        self.persist_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_persist_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// The counts so far, with the rest of `TableStats` left at zero.
+    pub(super) fn stats(&self) -> TableStats
+    {
+        TableStats
+        {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            loads: self.loads.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            persists: self.persists.load(Ordering::Relaxed),
+            persist_micros: self.persist_micros.load(Ordering::Relaxed),
+            max_persist_micros: self.max_persist_micros.load(Ordering::Relaxed),
+            ..TableStats::default()
+        }
+    }
+}
@@ -1,16 +1,23 @@
-use std::io;
+use std::collections::HashSet;
+use std::io::{ self, Read, };
 use std::fs;
-use std::sync::Mutex;
+use std::path::{ Path, PathBuf, };
+use std::sync::Arc;
+use std::time::Duration;
 
 use actix_cors::Cors;
 use actix_session::{
     CookieSession, Session
 };
+use futures::lock::Mutex;
+use futures::stream::{ self, Stream, };
 use actix_web::{
     App, http, HttpResponse, HttpServer,
-    web,
+    body::SizedStream, rt, web,
 };
+#[allow(clippy::single_component_path_imports)]
 use base64;
+use bincode::Options;
 use serde::{
     Deserialize, Serialize,
 };
@@ -18,19 +25,31 @@ use serde::{
 mod auth;
 mod database;
 use crate::database::{
-    DiskCache, MemCache,
-    Table,
+    Access, AsyncTable, Bincode, BlobId, BlobStore, Budget, Cbor, Codec, CodecError, Compression, DatabaseError,
+    DiskCache, DiskCacheOptions, Index, IndexedTable, Json, Keyring, Layout, LogTable, MemCache, MessagePack, Migrations,
+    Remedy, SqliteTable, Table,
 };
 #[cfg(test)]
 mod server_test;
 
 const SERV_PRIVATE_KEY: [u8; 32] = [0; 32];
+/// Image records held in memory before the least recently viewed are dropped
+const ICACHE_BUDGET: Budget = Budget::Bytes(64*1024*1024);
+/// How often changes to the images are persisted, unless overridden by
+/// the FLUSH_INTERVAL_SECS environment variable
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// The version of `Image` stored in the `files` image store. Bump it, and
+/// add a step to `image_migrations`, whenever `Image` changes.
+const IMAGE_SCHEMA: u32 = 1;
+/// Where the image indexes are saved, in the `files` and `log` stores. It
+/// isn't a valid record name.
+const INDEX_FILE: &str = ".indexes";
 
 #[macro_export]
 macro_rules! default_user_table(
     () =>
     {{
-        let mut utable = MemCache::new();
+        let utable = MemCache::new();
 
         utable.set(String::from("chipper"),
                    User{ hpass: String::from("5f4dcc3b5aa765d61d8327deb882cf99") });
@@ -55,10 +74,35 @@ pub struct User
 }
 
 pub type ImageKey = String;
-type ImageTable = DiskCache<ImageKey, Image>;
+/// Chosen at startup, see `open_image_table`
+type ImageTable = Box<dyn Table<ImageKey, Image> + Send + Sync>;
 
+/// What's known about an image. Its data is kept apart, in `Database.blobs`,
+/// so that checking who may see an image doesn't involve reading it, and
+/// images with the same data share it.
 #[derive(Deserialize, Serialize)]
 pub struct Image
+{
+    public: bool,
+    owner: UserKey,
+    blob: BlobId,
+}
+
+/// The image records, indexed by `image_indexes`
+type IndexedImages = IndexedTable<ImageKey, Image, ImageTable>;
+
+/// Who owns each image, and whether it's public (as "true" or "false").
+fn image_indexes() -> Vec<Index<Image>>
+{
+    vec![
+        Index::new("owner", |img| img.owner.clone()),
+        Index::new("public", |img| img.public.to_string()),
+    ]
+}
+
+/// How images were stored before their data was split out of the record.
+#[derive(Deserialize, Serialize)]
+struct InlineImage
 {
     public: bool,
     owner: UserKey,
@@ -68,7 +112,11 @@ pub struct Image
 struct Database
 {
     utable: UserTable,
-    icache: ImageTable,
+    icache: AsyncTable<IndexedImages>,
+    blobs: AsyncTable<BlobStore>,
+    /// Held by handlers which check, then change, the images, so that their
+    /// changes don't interleave. Views don't need it.
+    writes: Mutex<()>,
 }
 
 #[derive(Deserialize)]
@@ -94,16 +142,18 @@ pub struct LogonRequest
 
 // ---- User Procedures ----
 
-fn add_img(db: &mut Database, sess: &Session, req: &AddRequest) -> HttpResponse
+async fn add_img(db: &Database, sess: &Session, req: AddRequest) -> HttpResponse
 {
-    match (auth::get_auth_user(sess), db.icache.contains_key(&req.id))
+    match (auth::get_auth_user(sess), db.icache.try_contains_key(req.id.clone()).await)
     {
-        (_, true) =>
+        (_, Ok(true)) =>
             HttpResponse::Conflict()
             .body(format!("{} is already present in the database. Please use another id, or remove the existing value.", req.id)),
         (None, _) =>
             HttpResponse::Unauthorized().finish(),
-        (Some(auth_user), false) =>
+        (_, Err(e)) =>
+            HttpResponse::InternalServerError().body(format!("{:?}", e)),
+        (Some(auth_user), Ok(false)) =>
         {
             let img_data: Vec<u8> = match base64::decode(&req.img)
             {
@@ -113,40 +163,142 @@ fn add_img(db: &mut Database, sess: &Session, req: &AddRequest) -> HttpResponse
                     return HttpResponse::InternalServerError().body(format!("{:?}", e)),
             };
 
-            db.icache.set(req.id.clone(), Image {
+            // The data goes first, so a record never refers to missing data
+            let blob = match db.blobs.run(move |blobs| blobs.put(&img_data)).await.and_then(|r| r)
+            {
+                Ok(blob) =>
+                    blob,
+                Err(e) =>
+                    return HttpResponse::InternalServerError().body(format!("{:?}", e)),
+            };
+
+            let img = Image {
                 public: req.public.unwrap_or(false),
                 owner: auth_user,
-                data: img_data,
-            });
+                blob,
+            };
 
-            HttpResponse::Ok().body(format!("Added {} to the database.", req.id))
+            match db.icache.try_set(req.id.clone(), img).await
+            {
+                Ok(_) =>
+                    HttpResponse::Ok().body(format!("Added {} to the database.", req.id)),
+                Err(e) =>
+                {
+                    let _ = db.blobs.run(move |blobs| blobs.release(&blob)).await;
+                    HttpResponse::InternalServerError().body(format!("{:?}", e))
+                },
+            }
         },
     }
 }
 
-fn add_img_dispatch(db: web::Data<Mutex<Database>>, sess: Session, req: web::Json<AddRequest>) -> HttpResponse
+async fn add_img_dispatch(db: web::Data<Database>, sess: Session, req: web::Json<AddRequest>) -> HttpResponse
 {
-    match db.lock()
+    let _writes = db.writes.lock().await;
+    add_img(&db, &sess, req.into_inner()).await
+}
+
+/// Add several images, all or none of them.
+async fn add_imgs(db: &Database, sess: &Session, reqs: Vec<AddRequest>) -> HttpResponse
+{
+    let auth_user = match auth::get_auth_user(sess)
+    {
+        Some(auth_user) =>
+            auth_user,
+        None =>
+            return HttpResponse::Unauthorized().finish(),
+    };
+
+    let mut ids = HashSet::new();
+    for req in &reqs
+    {
+        match db.icache.try_contains_key(req.id.clone()).await
+        {
+            Ok(false) if ids.insert(req.id.clone()) =>
+                {},
+            Ok(_) =>
+                return HttpResponse::Conflict()
+                    .body(format!("{} is already present in the database, or given twice. Please use another id, or remove the existing value.", req.id)),
+            Err(e) =>
+                return HttpResponse::InternalServerError().body(format!("{:?}", e)),
+        }
+    }
+
+    let mut imgs = Vec::with_capacity(reqs.len());
+    for req in &reqs
+    {
+        match base64::decode(&req.img)
+        {
+            Ok(data) =>
+                imgs.push(data),
+            Err(e) =>
+                return HttpResponse::InternalServerError().body(format!("{:?}", e)),
+        }
+    }
+
+    // The data goes first, as for a single image
+    let blobs = match db.blobs.run(move |blobs| put_all(blobs, &imgs)).await.and_then(|r| r)
     {
-        Ok(mut db) =>
-            add_img(&mut db, &sess, &req.into_inner()),
+        Ok(blobs) =>
+            blobs,
         Err(e) =>
-            HttpResponse::InternalServerError().body(format!("{:?}", e)),
+            return HttpResponse::InternalServerError().body(format!("{:?}", e)),
+    };
+
+    let mut batch = db.icache.inner().begin();
+    for (req, blob) in reqs.iter().zip(&blobs)
+    {
+        batch.set(req.id.clone(), Image {
+            public: req.public.unwrap_or(false),
+            owner: auth_user.clone(),
+            blob: *blob,
+        });
     }
+
+    match db.icache.commit(batch).await
+    {
+        Ok(()) =>
+            HttpResponse::Ok().body(format!("Added {} images to the database.", reqs.len())),
+        Err(e) =>
+        {
+            let _ = db.blobs.run(move |store| release_all(store, &blobs)).await;
+            HttpResponse::InternalServerError().body(format!("{:?}", e))
+        },
+    }
+}
+
+async fn add_imgs_dispatch(db: web::Data<Database>, sess: Session, req: web::Json<Vec<AddRequest>>) -> HttpResponse
+{
+    let _writes = db.writes.lock().await;
+    add_imgs(&db, &sess, req.into_inner()).await
 }
 
-fn remove_img(db: &mut Database, sess: &Session, req: &RmRequest) -> HttpResponse
+async fn remove_img(db: &Database, sess: &Session, req: RmRequest) -> HttpResponse
 {
-    match (auth::get_auth_user(sess), db.icache.get(&req.id))
+    match (auth::get_auth_user(sess), db.icache.try_get(req.id.clone()).await)
     {
         (None, _) =>
             HttpResponse::Unauthorized().finish(),
-        (_, None) =>
+        (_, Ok(None)) =>
             HttpResponse::NotFound().body(format!("We couldn't find {}", req.id)),
-        (Some(auth_user), Some(img)) =>
+        (_, Err(e)) =>
+            HttpResponse::InternalServerError().body(format!("{:?}", e)),
+        (Some(auth_user), Ok(Some(img))) =>
             if auth_user == img.owner
             {
-                db.icache.remove(&req.id);
+                if let Err(e) = db.icache.try_remove(req.id.clone()).await
+                {
+                    return HttpResponse::InternalServerError().body(format!("{:?}", e));
+                }
+
+                // The image is gone once its record is. Data left behind is
+                // only wasted space, until the next `collect_garbage`.
+                let blob = img.blob;
+                if let Err(e) = db.blobs.run(move |blobs| blobs.release(&blob)).await.and_then(|r| r)
+                {
+                    eprintln!("Unable to remove the data for {}: {}", req.id, e);
+                }
+
                 HttpResponse::Ok().body(format!("Removed {} from the database.", req.id))
             }
             else
@@ -156,40 +308,33 @@ fn remove_img(db: &mut Database, sess: &Session, req: &RmRequest) -> HttpRespons
     }
 }
 
-fn remove_img_dispatch(db: web::Data<Mutex<Database>>, sess: Session, req: web::Json<RmRequest>) -> HttpResponse
+async fn remove_img_dispatch(db: web::Data<Database>, sess: Session, req: web::Json<RmRequest>) -> HttpResponse
 {
-    match db.lock()
-    {
-        Ok(mut db) =>
-            remove_img(&mut db, &sess, &req.into_inner()),
-        Err(e) =>
-            HttpResponse::InternalServerError().body(format!("{:?}", e)),
-    }
+    let _writes = db.writes.lock().await;
+    remove_img(&db, &sess, req.into_inner()).await
 }
 
-fn view_img(db: &mut Database, sess: &Session, img_id: &String) -> HttpResponse
+async fn view_img(db: &Database, sess: &Session, img_id: String) -> HttpResponse
 {
-    match (auth::get_auth_user(sess), db.icache.get(img_id))
+    match (auth::get_auth_user(sess), db.icache.try_get(img_id.clone()).await)
     {
-        (_, None) =>
+        (_, Ok(None)) =>
             HttpResponse::NotFound().body(format!("We couldn't find {}", img_id)),
-        (None, Some(img)) =>
+        (_, Err(e)) =>
+            HttpResponse::InternalServerError().body(format!("{:?}", e)),
+        (None, Ok(Some(img))) =>
             if img.public
             {
-                HttpResponse::Ok()
-                    .header(http::header::CONTENT_TYPE, "image/jpeg")
-                    .body(img.data.clone())
+                serve_img(db, &img_id, img.blob).await
             }
             else
             {
                 HttpResponse::Unauthorized().finish()
             },
-        (Some(auth_user), Some(img)) =>
+        (Some(auth_user), Ok(Some(img))) =>
             if img.public || (auth_user == img.owner)
             {
-                HttpResponse::Ok()
-                    .header(http::header::CONTENT_TYPE, "image/jpeg")
-                    .body(img.data.clone())
+                serve_img(db, &img_id, img.blob).await
             }
             else
             {
@@ -198,18 +343,62 @@ fn view_img(db: &mut Database, sess: &Session, img_id: &String) -> HttpResponse
     }
 }
 
-fn view_img_dispatch(db: web::Data<Mutex<Database>>, sess: Session, req: web::Path<String>) -> HttpResponse
+/// The ids of the images the session may view: the public ones, and those
+/// of its user. Answered from the image indexes, without reading any images.
+async fn list_imgs(db: &Database, sess: &Session) -> HttpResponse
 {
-    match db.lock()
+    let auth_user = auth::get_auth_user(sess);
+    let ids = db.icache.run(move |icache| {
+        let mut ids: HashSet<ImageKey> = icache.query("public", "true")?.into_iter().collect();
+        if let Some(auth_user) = auth_user
+        {
+            ids.extend(icache.query("owner", &auth_user)?);
+        }
+
+        Ok(ids)
+    }).await.and_then(|r| r);
+
+    match ids
     {
-        Ok(mut db) =>
-            view_img(&mut db, &sess, &req.into_inner()),
+        Ok(ids) =>
+        {
+            let mut ids: Vec<_> = ids.into_iter().collect();
+            ids.sort();
+            HttpResponse::Ok().json(ids)
+        },
         Err(e) =>
             HttpResponse::InternalServerError().body(format!("{:?}", e)),
     }
 }
 
-fn logon(db: &mut Database, sess: &mut Session, req: &LogonRequest) -> HttpResponse
+async fn list_imgs_dispatch(db: web::Data<Database>, sess: Session) -> HttpResponse
+{
+    list_imgs(&db, &sess).await
+}
+
+/// Stream an image's data from disk, a chunk at a time. The data may have
+/// gone since the record was read, if the image was removed meanwhile.
+async fn serve_img(db: &Database, img_id: &str, blob: BlobId) -> HttpResponse
+{
+    match db.blobs.run(move |blobs| blobs.open(&blob)).await.and_then(|r| r)
+    {
+        Ok((f, len)) =>
+            HttpResponse::Ok()
+                .header(http::header::CONTENT_TYPE, "image/jpeg")
+                .body(SizedStream::new(len, Box::pin(read_chunks(f)))),
+        Err(DatabaseError::NotFound) =>
+            HttpResponse::NotFound().body(format!("We couldn't find {}", img_id)),
+        Err(e) =>
+            HttpResponse::InternalServerError().body(format!("{:?}", e)),
+    }
+}
+
+async fn view_img_dispatch(db: web::Data<Database>, sess: Session, req: web::Path<String>) -> HttpResponse
+{
+    view_img(&db, &sess, req.into_inner()).await
+}
+
+fn logon(db: &Database, sess: &mut Session, req: &LogonRequest) -> HttpResponse
 {
     if let Some(db_user) = db.utable.get(&req.uname)
     {
@@ -234,38 +423,520 @@ fn logon(db: &mut Database, sess: &mut Session, req: &LogonRequest) -> HttpRespo
     }
 }
 
-fn logon_dispatch(db: web::Data<Mutex<Database>>, mut sess: Session, req: web::Json<LogonRequest>) -> HttpResponse
+/// How the image cache is doing, as JSON, to help size it. Only for users
+/// who are logged on.
+async fn stats(db: &Database, sess: &Session) -> HttpResponse
 {
-    match db.lock()
+    if auth::get_auth_user(sess).is_none()
+    {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match db.icache.run(|icache| icache.stats()).await
     {
-        Ok(mut db) =>
-            logon(&mut db, &mut sess, &req.into_inner()),
+        Ok(stats) =>
+            HttpResponse::Ok().json(stats),
         Err(e) =>
             HttpResponse::InternalServerError().body(format!("{:?}", e)),
     }
 }
 
-fn logoff(db: &mut Database, sess: &mut Session) -> HttpResponse
+async fn stats_dispatch(db: web::Data<Database>, sess: Session) -> HttpResponse
+{
+    stats(&db, &sess).await
+}
+
+async fn logon_dispatch(db: web::Data<Database>, mut sess: Session, req: web::Json<LogonRequest>) -> HttpResponse
+{
+    logon(&db, &mut sess, &req.into_inner())
+}
+
+async fn logoff(db: &Database, sess: &mut Session) -> HttpResponse
 {
     sess.remove("auth-user");
 
-    match db.icache.persist()
+    match flush(db).await
     {
-        Ok(_) =>
+        Ok(()) =>
             HttpResponse::Ok().body("Goodbye friend."),
         Err(e) =>
-            HttpResponse::InternalServerError().body(format!("{:?}", e)),
+            HttpResponse::InternalServerError().body(e),
     }
 }
 
-fn logoff_dispatch(db: web::Data<Mutex<Database>>, mut sess: Session) -> HttpResponse
+async fn logoff_dispatch(db: web::Data<Database>, mut sess: Session) -> HttpResponse
+{
+    logoff(&db, &mut sess).await
+}
+
+// ---- Persistence ----
+
+/// Bytes read from disk at a time when serving an image
+const CHUNK_SIZE: usize = 64*1024;
+
+/// The contents of `f`, read on the blocking thread pool.
+fn read_chunks(f: Box<dyn Read + Send>) -> impl Stream<Item = Result<web::Bytes, actix_web::Error>>
 {
-    match db.lock()
+    stream::unfold(Some(f), |f| async move {
+        let mut f = f?;
+        let chunk = web::block(move || {
+            let mut buf = vec![0; CHUNK_SIZE];
+            let n = f.read(&mut buf)?;
+            buf.truncate(n);
+
+            Ok::<_, io::Error>((f, buf))
+        })
+        .await;
+
+        match chunk
+        {
+            Ok((_, buf)) if buf.is_empty() =>
+                None,
+            Ok((f, buf)) =>
+                Some((Ok(web::Bytes::from(buf)), Some(f))),
+            // Nothing more can be sent after an error
+            Err(e) =>
+                Some((Err(actix_web::error::ErrorInternalServerError(format!("{:?}", e))), None)),
+        }
+    })
+}
+
+/// How to upgrade the records of the `files` image store, encoded with `C`,
+/// moving image data into `blobs` as need be.
+fn image_migrations<C: Codec>(blobs: Arc<BlobStore>) -> Migrations
+{
+    Migrations::new(IMAGE_SCHEMA)
+        // Before records had headers, images were stored with their data
+        // inline. Each time it runs on a record its data is counted again,
+        // until `collect_garbage`.
+        .step(0, move |_key, record| -> Result<Vec<u8>, CodecError> {
+            // Unlike the records themselves, which tolerate trailing bytes,
+            // the old format must account for the whole record. Only
+            // `Bincode` stores predate headers.
+            let old: InlineImage = bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .deserialize(record)
+                .map_err(|_| "Not an image record")?;
+            let blob = blobs.put(&old.data)?;
+
+            C::encode(&Image { public: old.public, owner: old.owner, blob })
+        })
+}
+
+/// Store each of `imgs`, or, if one can't be, none of them.
+fn put_all(blobs: &BlobStore, imgs: &[Vec<u8>]) -> Result<Vec<BlobId>, DatabaseError>
+{
+    let mut put = Vec::with_capacity(imgs.len());
+    for data in imgs
+    {
+        match blobs.put(data)
+        {
+            Ok(blob) =>
+                put.push(blob),
+            Err(e) =>
+            {
+                release_all(blobs, &put);
+                return Err(e);
+            },
+        }
+    }
+
+    Ok(put)
+}
+
+/// Release each of `put`. Any left behind are only wasted space, until the
+/// next `collect_garbage`.
+fn release_all(blobs: &BlobStore, put: &[BlobId])
+{
+    for blob in put
+    {
+        let _ = blobs.release(blob);
+    }
+}
+
+/// Recount the references to each image's data from the records, removing
+/// data nothing refers to. Must run before anything changes the images.
+/// Fails, removing nothing, if any record can't be read. Returns how many
+/// were removed.
+fn collect_garbage(icache: &IndexedImages, blobs: &BlobStore) -> io::Result<usize>
+{
+    let mut live = Vec::new();
+    for k in icache.keys()
+    {
+        // Data is only known to be unused if every record can be read
+        if let Some(img) = icache.try_get(&k).map_err(io::Error::other)?
+        {
+            live.push(img.blob);
+        }
+    }
+
+    blobs.gc(live).map_err(io::Error::other)
+}
+
+/// Check the image records for damage, as `Table::scrub` does, reporting
+/// what's found. Returns how many damaged files were left as they were.
+fn scrub_images(icache: &IndexedImages, quarantine: bool) -> io::Result<usize>
+{
+    let report = icache.scrub(quarantine)?;
+    for finding in &report.findings
     {
-        Ok(mut db) =>
-            logoff(&mut db, &mut sess),
+        eprintln!("{}", finding);
+    }
+    println!("Scrubbed {} image records, and found {} damaged files", report.checked, report.findings.len());
+
+    Ok(report.findings.iter().filter(|finding| finding.remedy == Remedy::None).count())
+}
+
+/// Persist the images, and the counts of references to their data, off of
+/// the async workers.
+async fn flush(db: &Database) -> Result<(), String>
+{
+    match db.icache.run(|icache| icache.persist()).await
+    {
+        Ok(Ok(_)) =>
+            {},
+        Ok(Err(e)) =>
+            return Err(e.to_string()),
         Err(e) =>
-            HttpResponse::InternalServerError().body(format!("{:?}", e)),
+            return Err(e.to_string()),
+    }
+
+    match db.blobs.run(|blobs| blobs.persist()).await
+    {
+        Ok(Ok(_)) =>
+            Ok(()),
+        Ok(Err(e)) =>
+            Err(e.to_string()),
+        Err(e) =>
+            Err(e.to_string()),
+    }
+}
+
+/// Persist the images every `period`, so they're saved even if nobody logs off.
+fn spawn_flusher(db: web::Data<Database>, period: Duration)
+{
+    rt::spawn(async move {
+        let mut ticks = rt::time::interval(period);
+        // The first tick completes immediately
+        ticks.tick().await;
+
+        loop
+        {
+            ticks.tick().await;
+
+            if let Err(e) = flush(&db).await
+            {
+                eprintln!("Periodic flush failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Scrub the images every `period`, quarantining what's damaged if asked to.
+fn spawn_scrubber(db: web::Data<Database>, period: Duration, quarantine: bool)
+{
+    rt::spawn(async move {
+        let mut ticks = rt::time::interval(period);
+        // The first tick completes immediately
+        ticks.tick().await;
+
+        loop
+        {
+            ticks.tick().await;
+
+            match db.icache.run(move |icache| scrub_images(icache, quarantine)).await
+            {
+                Ok(Ok(_)) =>
+                    {},
+                Ok(Err(e)) =>
+                    eprintln!("Periodic scrub failed: {}", e),
+                Err(e) =>
+                    eprintln!("Periodic scrub failed: {}", e),
+            }
+        }
+    });
+}
+
+/// How often the images are scrubbed in the background, if at all, from
+/// the SCRUB_INTERVAL_SECS environment variable.
+fn scrub_interval() -> Option<Duration>
+{
+    match std::env::var("SCRUB_INTERVAL_SECS").map(|secs| secs.parse())
+    {
+        Ok(Ok(secs)) if secs > 0 =>
+            Some(Duration::from_secs(secs)),
+        Ok(_) =>
+        {
+            eprintln!("Ignoring invalid SCRUB_INTERVAL_SECS");
+            None
+        },
+        Err(_) =>
+            None,
+    }
+}
+
+/// Re-encrypt the images, and their data, under the current key, once. Once
+/// it's reported done, the keys before it can be dropped from the key file.
+fn spawn_reencrypter(db: web::Data<Database>)
+{
+    rt::spawn(async move {
+        match db.icache.run(|icache| icache.reencrypt()).await.map_err(|e| e.to_string())
+        {
+            Ok(Ok((0, 0))) =>
+                {},
+            Ok(Ok((n, 0))) =>
+                println!("Re-encrypted the records for {} images", n),
+            Ok(Ok((_, failed))) =>
+                eprintln!("Unable to re-encrypt the records for {} images", failed),
+            Ok(Err(e)) =>
+                eprintln!("Unable to re-encrypt the image records: {}", e),
+            Err(e) =>
+                eprintln!("Unable to re-encrypt the image records: {}", e),
+        }
+
+        match db.blobs.run(|blobs| blobs.reencrypt()).await.map_err(|e| e.to_string())
+        {
+            Ok(Ok((0, 0))) =>
+                {},
+            Ok(Ok((n, 0))) =>
+                println!("Re-encrypted the data for {} images", n),
+            Ok(Ok((_, failed))) =>
+                eprintln!("Unable to re-encrypt the data for {} images", failed),
+            Ok(Err(e)) =>
+                eprintln!("Unable to re-encrypt the image data: {}", e),
+            Err(e) =>
+                eprintln!("Unable to re-encrypt the image data: {}", e),
+        }
+    });
+}
+
+/// The keys the images are encrypted with, from the file IMG_KEY_FILE names,
+/// if it's set. See `Keyring::load` for its format.
+fn keyring() -> io::Result<Option<Arc<Keyring>>>
+{
+    match std::env::var_os("IMG_KEY_FILE")
+    {
+        Some(path) =>
+            Keyring::load(Path::new(&path)).map(|keyring| Some(Arc::new(keyring))),
+        None =>
+            Ok(None),
+    }
+}
+
+/// How the images, and their data, are compressed as they're written, from
+/// the IMG_COMPRESSION environment variable: `none` (the default), `lz4`, or
+/// `zstd`, optionally with a level (as in `zstd:19`).
+fn compression() -> io::Result<Compression>
+{
+    let compression = match std::env::var("IMG_COMPRESSION")
+    {
+        Err(_) =>
+            return Ok(Compression::None),
+        Ok(compression) =>
+            compression,
+    };
+
+    let parsed = match compression.split_once(':')
+    {
+        None if compression == "none" =>
+            Some(Compression::None),
+        None if compression == "lz4" =>
+            Some(Compression::Lz4),
+        None if compression == "zstd" =>
+            Some(Compression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)),
+        Some(("zstd", level)) =>
+            level.parse().ok().filter(|level| (1..=22).contains(level)).map(Compression::Zstd),
+        _ =>
+            None,
+    };
+
+    parsed.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                                        format!("Unknown IMG_COMPRESSION {:?}, expected none, lz4, zstd or zstd:<1-22>", compression)))
+}
+
+/// What to do, as given on the command line
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Command
+{
+    Serve,
+    /// Check the images for damage, and exit
+    Scrub { quarantine: bool },
+}
+
+fn parse_command(args: &[String]) -> io::Result<Command>
+{
+    match args
+    {
+        [] =>
+            Ok(Command::Serve),
+        [scrub] if scrub == "scrub" =>
+            Ok(Command::Scrub { quarantine: false }),
+        [scrub, flag] if scrub == "scrub" && flag == "--quarantine" =>
+            Ok(Command::Scrub { quarantine: true }),
+        _ =>
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "Usage: img-forest [scrub [--quarantine]]")),
+    }
+}
+
+/// Runs `scrub`, given on the command line in place of starting the server.
+fn scrub_command(icache: &IndexedImages, quarantine: bool) -> io::Result<()>
+{
+    let unresolved = scrub_images(icache, quarantine)?;
+    // Records damaged on disk, but intact in the journal, are rewritten
+    icache.persist().map_err(|e| io::Error::other(e.to_string()))?;
+
+    match unresolved
+    {
+        0 =>
+            Ok(()),
+        n =>
+            Err(io::Error::other(format!("{} damaged files were left as they were", n))),
+    }
+}
+
+fn flush_interval() -> Duration
+{
+    match std::env::var("FLUSH_INTERVAL_SECS").map(|secs| secs.parse())
+    {
+        Ok(Ok(secs)) if secs > 0 =>
+            Duration::from_secs(secs),
+        Ok(_) =>
+        {
+            eprintln!("Ignoring invalid FLUSH_INTERVAL_SECS");
+            DEFAULT_FLUSH_INTERVAL
+        },
+        Err(_) =>
+            DEFAULT_FLUSH_INTERVAL,
+    }
+}
+
+/// The `files` image store under `base_path`, with records encoded by `codec`
+/// (and stored as `storage` says) and brought up to date.
+fn open_image_files<C: Codec + 'static>(base_path: PathBuf, codec: C, blobs: Arc<BlobStore>, storage: DiskCacheOptions)
+    -> io::Result<ImageTable>
+{
+    let options = DiskCacheOptions {
+        budget: ICACHE_BUDGET,
+        layout: Layout::Sharded,
+        migrations: image_migrations::<C>(blobs.clone()),
+        ..storage
+    };
+    // Opened first, so a store encoded otherwise (or in use) is refused
+    // before any of it is rewritten
+    let icache = DiskCache::with_codec(base_path.clone(), options.clone(), codec)
+        .map_err(io::Error::other)?;
+
+    if options.access == Access::Exclusive
+    {
+        match database::upgrade_records(&base_path, &options)?
+        {
+            (0, _) =>
+                {},
+            (n, _) =>
+                println!("Upgraded the records for {} images", n),
+        }
+    }
+
+    Ok(Box::new(icache))
+}
+
+/// The `files` image store under `base_path`, with records encoded by the
+/// codec IMG_CODEC names.
+fn open_image_files_with(base_path: PathBuf, blobs: &Arc<BlobStore>, storage: DiskCacheOptions) -> io::Result<ImageTable>
+{
+    match std::env::var("IMG_CODEC").as_deref()
+    {
+        Ok("bincode") | Err(_) =>
+            open_image_files(base_path, Bincode, blobs.clone(), storage),
+        Ok("json") =>
+            open_image_files(base_path, Json, blobs.clone(), storage),
+        Ok("cbor") =>
+            open_image_files(base_path, Cbor, blobs.clone(), storage),
+        Ok("msgpack") =>
+            open_image_files(base_path, MessagePack, blobs.clone(), storage),
+        Ok(other) =>
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                               format!("Unknown IMG_CODEC {:?}, expected bincode, json, cbor or msgpack", other))),
+    }
+}
+
+/// Opens the image store named by the IMG_STORE environment variable:
+/// `files` (the default) for a record per image in subdirectories of `live-db/`,
+/// `sqlite` for a single `live-db.sqlite3` database, or `log` for an
+/// append-only log under `live-db.log/`. The records in `files` are encoded
+/// as IMG_CODEC says: `bincode` (the default), `json`, `cbor` or `msgpack`.
+/// Image data is kept in `blobs`. Only `files` can be opened read-only, and
+/// then it's used as it is, without being brought up to date. Likewise,
+/// only `files` can be compressed or encrypted. `storage` says how.
+///
+/// The images are indexed by `image_indexes`, which are saved alongside
+/// the store.
+fn open_image_table(blobs: &Arc<BlobStore>, storage: DiskCacheOptions) -> io::Result<IndexedImages>
+{
+    let (icache, index_path) = open_image_store(blobs, storage.clone())?;
+
+    IndexedTable::open(icache, index_path, image_indexes(), &storage).map_err(io::Error::other)
+}
+
+/// The image store `open_image_table` opens, and where its indexes are
+/// saved.
+fn open_image_store(blobs: &Arc<BlobStore>, storage: DiskCacheOptions) -> io::Result<(ImageTable, PathBuf)>
+{
+    let mut base_path = std::env::current_dir()?;
+
+    match std::env::var("IMG_STORE").as_deref()
+    {
+        Ok(store @ ("sqlite" | "log")) if storage.access == Access::ReadOnly =>
+            Err(io::Error::new(io::ErrorKind::Unsupported,
+                               format!("IMG_STORE={} can't be opened read-only", store))),
+        Ok(store @ ("sqlite" | "log")) if storage.keyring.is_some() || storage.compression != Compression::None =>
+            Err(io::Error::new(io::ErrorKind::Unsupported,
+                               format!("IMG_STORE={} can't be compressed or encrypted", store))),
+        Ok("sqlite") =>
+        {
+            base_path.push("live-db.sqlite3");
+            SqliteTable::open(&base_path)
+                .map(|t| (Box::new(t) as ImageTable, base_path.with_extension("indexes")))
+                .map_err(io::Error::other)
+        },
+        Ok("log") =>
+        {
+            base_path.push("live-db.log");
+            LogTable::open(&base_path)
+                .map(|t| (Box::new(t) as ImageTable, base_path.join(INDEX_FILE)))
+                .map_err(io::Error::other)
+        },
+        Ok("files") | Err(_) if storage.access == Access::ReadOnly =>
+        {
+            base_path.push("live-db");
+            let index_path = base_path.join(INDEX_FILE);
+            open_image_files_with(base_path, blobs, storage).map(|t| (t, index_path))
+        },
+        Ok("files") | Err(_) =>
+        {
+            base_path.push("live-db");
+            fs::create_dir_all(&base_path)?;
+            match database::migrate_key_encoding(&base_path)?
+            {
+                0 =>
+                    {},
+                n =>
+                    println!("Renamed {} records to their encoded names", n),
+            }
+            match database::migrate_to_sharded(&base_path)?
+            {
+                0 =>
+                    {},
+                n =>
+                    println!("Moved {} records into subdirectories", n),
+            }
+
+            let index_path = base_path.join(INDEX_FILE);
+            open_image_files_with(base_path, blobs, storage).map(|t| (t, index_path))
+        },
+        Ok(other) =>
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                               format!("Unknown IMG_STORE {:?}, expected files, sqlite or log", other))),
     }
 }
 
@@ -282,12 +953,63 @@ fn file(f_name: &str) -> HttpResponse {
 
 // ---- Main ----
 
+fn routes(cfg: &mut web::ServiceConfig)
+{
+    cfg
+        .app_data(
+            web::JsonConfig::default()
+                .limit(1024*1024)
+        )
+        // User Endpoints
+        .route("/logon",           web::post().to(logon_dispatch))
+        .route("/logoff",          web::post().to(logoff_dispatch))
+        .route("/add",             web::post().to(add_img_dispatch))
+        .route("/add_batch",       web::post().to(add_imgs_dispatch))
+        .route("/remove",          web::delete().to(remove_img_dispatch))
+        .route("/view/{image_id}", web::get().to(view_img_dispatch))
+        .route("/list",            web::get().to(list_imgs_dispatch))
+        .route("/stats",           web::get().to(stats_dispatch))
+        // Web Endpoints
+        .route("/",                web::get().to(|| {file("public/index.html")}))
+        .route("/style.css",       web::get().to(|| {file("public/style.css")}))
+        .route("*",                web::get().to(|| {file("public/404.html")}));
+}
+
 #[actix_web::main]
 async fn main() -> io::Result<()>
 {
-    let mut db_base_path = std::env::current_dir()?;
-    db_base_path.push("live-db");
+    let command = parse_command(&std::env::args().skip(1).collect::<Vec<_>>())?;
+    // Only a scrub which changes nothing can run alongside the server
+    let access = match command
+    {
+        Command::Scrub { quarantine: false } =>
+            Access::ReadOnly,
+        _ =>
+            Access::Exclusive,
+    };
+
+    let storage = DiskCacheOptions {
+        access,
+        compression: compression()?,
+        keyring: keyring()?,
+        ..DiskCacheOptions::default()
+    };
+    let blobs = Arc::new(BlobStore::with_options(std::env::current_dir()?.join("live-blobs"), storage.clone())?);
+    let icache = open_image_table(&blobs, storage.clone())?;
+    if let Command::Scrub { quarantine } = command
+    {
+        return scrub_command(&icache, quarantine);
+    }
 
+    match collect_garbage(&icache, &blobs)
+    {
+        Ok(0) =>
+            {},
+        Ok(n) =>
+            println!("Removed the data for {} images no longer stored", n),
+        Err(e) =>
+            eprintln!("Not removing unused image data: {}", e),
+    }
 
     println!("🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲🌲");
     println!("🥜🥜🥜🥜🥜🥜 Starting Img-Forest Server 🥜🥜🥜🥜🥜🥜");
@@ -296,38 +1018,42 @@ async fn main() -> io::Result<()>
 
     let img_store =
         web::Data::new(
-            Mutex::new(
-                Database {
-                    utable: default_user_table!(),
-                    icache: ImageTable::new(db_base_path),
-                }));
+            Database {
+                utable: default_user_table!(),
+                icache: AsyncTable::new(icache),
+                blobs: AsyncTable::shared(blobs),
+                writes: Mutex::new(()),
+            });
+
+    spawn_flusher(img_store.clone(), flush_interval());
+    if storage.keyring.is_some()
+    {
+        spawn_reencrypter(img_store.clone());
+    }
+    if let Some(period) = scrub_interval()
+    {
+        let quarantine = std::env::var("SCRUB_QUARANTINE").is_ok_and(|v| v == "1");
+        spawn_scrubber(img_store.clone(), period, quarantine);
+    }
 
+    let server_store = img_store.clone();
     HttpServer::new(move || {
         App::new()
             .wrap(
                 CookieSession::private(&SERV_PRIVATE_KEY)
                     .secure(false),
             )
-           .app_data(
-               web::JsonConfig::default()
-                   .limit(1024*1024)
-           )
-            .app_data(img_store.clone())
-            // User Endpoints
-            .route("/logon",           web::post().to(logon_dispatch))
-            .route("/logoff",          web::post().to(logoff_dispatch))
-            .route("/add",             web::post().to(add_img_dispatch))
-            .route("/remove",          web::delete().to(remove_img_dispatch))
-            .route("/view/{image_id}", web::get().to(view_img_dispatch))
-            // Web Endpoints
-            .route("/",                web::get().to(|| {file("public/index.html")}))
-            .route("/style.css",       web::get().to(|| {file("public/style.css")}))
-            .route("*",                web::get().to(|| {file("public/404.html")}))
+            .app_data(server_store.clone())
+            .configure(routes)
             .wrap(
                 Cors::default()
             )
     })
     .bind("127.0.0.1:8080")?
     .run()
-    .await
+    .await?;
+
+    // The server has stopped (Ctrl-c), save anything not yet persisted
+    println!("🥜🥜🥜🥜🥜🥜 Squirreling away images 🥜🥜🥜🥜🥜🥜");
+    flush(&img_store).await.map_err(io::Error::other)
 }
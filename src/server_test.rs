@@ -1,11 +1,531 @@
+use actix_web::{
+    test, http::Cookie, http::StatusCode,
+};
+use std::path::{ Path, PathBuf, };
+use std::time::Instant;
 
-// #[ignore]
-// #[test]
-// fn server_tests()
-// {
-//     // I'd like to test *all * the things, but getting a valid Session object has
-//     // proven to be really challenging. So I'm leaving automated unit-testing for
-//     // the internal server procedures out of scope for this project. Instead,
-//     // this testing was performed with Postman.
-//     unimplemented!()
-// }
+use super::*;
+
+#[derive(Serialize)]
+struct TestLogon<'a>
+{
+    uname: &'a str,
+    hpass: &'a str,
+}
+
+#[derive(Serialize)]
+struct TestRm<'a>
+{
+    id: &'a str,
+}
+
+#[derive(Serialize)]
+struct TestAdd<'a>
+{
+    id: &'a str,
+    img: &'a str,
+    public: bool,
+}
+
+const TEST_IMG: &[u8] = b"\xff\xd8\xff\xe0 not really a jpeg";
+
+/// A fresh, empty image store for the test `name`
+fn scratch_db(name: &str) -> PathBuf
+{
+    let mut path = std::env::temp_dir();
+    path.push("img-forest-server-test");
+    path.push(name);
+
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+
+    path
+}
+
+/// Where `test_data` keeps the image data for the store at `base_path`
+fn blob_path(base_path: &Path) -> PathBuf
+{
+    base_path.with_extension("blobs")
+}
+
+fn test_data(base_path: PathBuf) -> web::Data<Database>
+{
+    let blobs = blob_path(&base_path);
+    let _ = fs::remove_dir_all(&blobs);
+    let index_path = base_path.join(INDEX_FILE);
+    let icache: ImageTable = Box::new(DiskCache::new(base_path).unwrap());
+
+    web::Data::new(
+        Database {
+            utable: default_user_table!(),
+            icache: AsyncTable::new(IndexedTable::open(icache, index_path, image_indexes(), &DiskCacheOptions::default()).unwrap()),
+            blobs: AsyncTable::new(BlobStore::new(blobs).unwrap()),
+            writes: Mutex::new(()),
+        })
+}
+
+macro_rules! test_app(
+    ($data:expr) =>
+    {
+        test::init_service(
+            App::new()
+                .wrap(
+                    CookieSession::private(&SERV_PRIVATE_KEY)
+                        .secure(false),
+                )
+                .app_data($data.clone())
+                .configure(routes)
+        ).await
+    };
+);
+
+macro_rules! logon(
+    ($app:expr) =>
+    {{
+        let req = test::TestRequest::post()
+            .uri("/logon")
+            .set_json(&TestLogon { uname: "chipper", hpass: "5f4dcc3b5aa765d61d8327deb882cf99" })
+            .to_request();
+        let resp = test::call_service(&mut $app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+
+        let cookie: Cookie = resp.response().cookies().next().unwrap().into_owned();
+        cookie
+    }};
+);
+
+macro_rules! add(
+    ($app:expr, $cookie:expr, $id:expr) =>
+    {{
+        let req = test::TestRequest::post()
+            .uri("/add")
+            .cookie($cookie.clone())
+            .set_json(&TestAdd { id: $id, img: &base64::encode(TEST_IMG), public: false })
+            .to_request();
+
+        test::call_service(&mut $app, req).await.status()
+    }};
+);
+
+#[actix_rt::test]
+async fn add_traversal_id_stays_in_store()
+{
+    let base_path = scratch_db("add_traversal_id_stays_in_store");
+    let data = test_data(base_path.clone());
+    let mut app = test_app!(data);
+    let cookie = logon!(app);
+
+    for id in &["../escaped", "../../escaped", "a/b", "/abs"]
+    {
+        assert_eq!(StatusCode::OK, add!(app, cookie, id));
+    }
+    data.icache.inner().persist().unwrap();
+
+    // Nothing written outside of the store, or into a subdirectory of it
+    assert!(!base_path.parent().unwrap().join("escaped").exists());
+    for entry in fs::read_dir(&base_path).unwrap()
+    {
+        assert!(entry.unwrap().file_type().unwrap().is_file());
+    }
+
+    assert!(base_path.join("%2E%2E%2Fescaped").exists());
+    assert!(base_path.join("a%2Fb").exists());
+}
+
+#[actix_rt::test]
+async fn view_traversal_id_serves_only_its_own_record()
+{
+    let base_path = scratch_db("view_traversal_id_serves_only_its_own_record");
+    fs::write(base_path.join("secret"), b"not a record").unwrap();
+
+    let mut store = base_path.clone();
+    store.push("store");
+    fs::create_dir(&store).unwrap();
+
+    let data = test_data(store.clone());
+    let mut app = test_app!(data);
+    let cookie = logon!(app);
+
+    for uri in &["/view/..%2Fsecret", "/view/%2E%2E%2Fsecret", "/view/%2E%2E"]
+    {
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .cookie(cookie.clone())
+            .to_request();
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&mut app, req).await.status());
+    }
+
+    // The id ".." is a record like any other
+    assert_eq!(StatusCode::OK, add!(app, cookie, ".."));
+    data.icache.inner().persist().unwrap();
+    assert!(store.join("%2E%2E").is_file());
+
+    let req = test::TestRequest::get()
+        .uri("/view/%2E%2E")
+        .cookie(cookie.clone())
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, resp.status());
+    assert_eq!(TEST_IMG, &test::read_body(resp).await[..]);
+}
+
+#[actix_rt::test]
+async fn ids_differing_by_case_dont_collide()
+{
+    let base_path = scratch_db("ids_differing_by_case_dont_collide");
+    let data = test_data(base_path.clone());
+    let mut app = test_app!(data);
+    let cookie = logon!(app);
+
+    assert_eq!(StatusCode::OK, add!(app, cookie, "cat"));
+    assert_eq!(StatusCode::OK, add!(app, cookie, "Cat"));
+    assert_eq!(StatusCode::CONFLICT, add!(app, cookie, "Cat"));
+    data.icache.inner().persist().unwrap();
+
+    assert!(base_path.join("cat").exists());
+    assert!(base_path.join("%43at").exists());
+}
+
+#[actix_rt::test]
+async fn batch_adds_all_images_or_none()
+{
+    let base_path = scratch_db("batch_adds_all_images_or_none");
+    let data = test_data(base_path);
+    let mut app = test_app!(data);
+    let cookie = logon!(app);
+    let img = base64::encode(TEST_IMG);
+
+    let add_batch = |ids: &[&'static str]| {
+        test::TestRequest::post()
+            .uri("/add_batch")
+            .cookie(cookie.clone())
+            .set_json(&ids.iter().map(|id| TestAdd { id, img: &img, public: false }).collect::<Vec<_>>())
+            .to_request()
+    };
+
+    let resp = test::call_service(&mut app, add_batch(&["nutmeg", "acorn"])).await;
+    assert_eq!(StatusCode::OK, resp.status());
+    assert!(data.icache.inner().contains_key(&String::from("acorn")));
+
+    // One already there, so none are added
+    let resp = test::call_service(&mut app, add_batch(&["walnut", "acorn"])).await;
+    assert_eq!(StatusCode::CONFLICT, resp.status());
+    assert!(!data.icache.inner().contains_key(&String::from("walnut")));
+
+    let resp = test::call_service(&mut app, add_batch(&["walnut", "walnut"])).await;
+    assert_eq!(StatusCode::CONFLICT, resp.status());
+    assert!(!data.icache.inner().contains_key(&String::from("walnut")));
+}
+
+#[actix_rt::test]
+async fn list_shows_public_images_and_the_users_own()
+{
+    let base_path = scratch_db("list_shows_public_images_and_the_users_own");
+    let data = test_data(base_path);
+    let mut app = test_app!(data);
+    let cookie = logon!(app);
+
+    assert_eq!(StatusCode::OK, add!(app, cookie, "mine"));
+    for (id, owner, public) in &[("public", "nutty", true), ("private", "nutty", false)]
+    {
+        data.icache.inner().set(String::from(*id), Image {
+            public: *public,
+            owner: String::from(*owner),
+            blob: BlobId::of(TEST_IMG),
+        });
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/list")
+        .cookie(cookie.clone())
+        .to_request();
+    let ids: Vec<String> = test::read_response_json(&mut app, req).await;
+    assert_eq!(vec!["mine", "public"], ids);
+
+    let req = test::TestRequest::get().uri("/list").to_request();
+    let ids: Vec<String> = test::read_response_json(&mut app, req).await;
+    assert_eq!(vec!["public"], ids);
+}
+
+#[actix_rt::test]
+async fn stats_reports_the_image_cache()
+{
+    let base_path = scratch_db("stats_reports_the_image_cache");
+    let data = test_data(base_path);
+    let mut app = test_app!(data);
+    let cookie = logon!(app);
+
+    assert_eq!(StatusCode::OK, add!(app, cookie, "a-normal-cat"));
+    let req = test::TestRequest::get()
+        .uri("/view/a-normal-cat")
+        .cookie(cookie.clone())
+        .to_request();
+    assert_eq!(StatusCode::OK, test::call_service(&mut app, req).await.status());
+
+    let req = test::TestRequest::get().uri("/stats").to_request();
+    assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&mut app, req).await.status());
+
+    let req = test::TestRequest::get()
+        .uri("/stats")
+        .cookie(cookie.clone())
+        .to_request();
+    let stats: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(1, stats["hits"]);
+    assert_eq!(1, stats["dirty"]);
+    assert_eq!(1, stats["cached"]);
+    assert_eq!(0, stats["evictions"]["evictions"]);
+}
+
+#[actix_rt::test]
+async fn flusher_persists_without_logoff()
+{
+    let base_path = scratch_db("flusher_persists_without_logoff");
+    let data = test_data(base_path.clone());
+    let record = base_path.join("a-normal-cat");
+
+    let period = Duration::from_millis(20);
+    spawn_flusher(data.clone(), period);
+
+    data.icache.inner().set(String::from("a-normal-cat"), Image {
+        public: true,
+        owner: String::from("chipper"),
+        blob: BlobId::of(TEST_IMG),
+    });
+    assert!(!record.exists());
+
+    // However long the flusher takes to get to it, it does eventually
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !record.exists() && Instant::now() < deadline
+    {
+        rt::time::delay_for(period).await;
+    }
+    assert!(record.exists());
+}
+
+#[actix_rt::test]
+async fn flush_persists_images()
+{
+    let base_path = scratch_db("flush_persists_images");
+    let data = test_data(base_path.clone());
+    let mut app = test_app!(data);
+    let cookie = logon!(app);
+
+    assert_eq!(StatusCode::OK, add!(app, cookie, "a-normal-cat"));
+    assert!(!base_path.join("a-normal-cat").exists());
+
+    flush(&data).await.unwrap();
+    assert!(base_path.join("a-normal-cat").exists());
+}
+
+#[actix_rt::test]
+async fn corrupt_record_is_a_server_error()
+{
+    let base_path = scratch_db("corrupt_record_is_a_server_error");
+    fs::write(base_path.join("corrupt"), b"\xff\xff\xff\xff\xff\xff\xff\xff").unwrap();
+
+    let data = test_data(base_path);
+    let mut app = test_app!(data);
+    let cookie = logon!(app);
+
+    let req = test::TestRequest::get()
+        .uri("/view/corrupt")
+        .cookie(cookie.clone())
+        .to_request();
+    assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, test::call_service(&mut app, req).await.status());
+
+    let req = test::TestRequest::get()
+        .uri("/view/missing")
+        .cookie(cookie.clone())
+        .to_request();
+    assert_eq!(StatusCode::NOT_FOUND, test::call_service(&mut app, req).await.status());
+}
+
+#[actix_rt::test]
+async fn images_are_stored_apart_from_their_records()
+{
+    let base_path = scratch_db("images_are_stored_apart_from_their_records");
+    let data = test_data(base_path.clone());
+    let mut app = test_app!(data);
+    let cookie = logon!(app);
+
+    assert_eq!(StatusCode::OK, add!(app, cookie, "a-normal-cat"));
+    flush(&data).await.unwrap();
+
+    let blob = data.icache.inner().get(&String::from("a-normal-cat")).unwrap().blob;
+    assert_eq!(TEST_IMG, &data.blobs.inner().read(&blob).unwrap()[..]);
+    let record = fs::read(base_path.join("a-normal-cat")).unwrap();
+    assert!(!record.windows(TEST_IMG.len()).any(|w| w == TEST_IMG));
+
+    let req = test::TestRequest::get()
+        .uri("/view/a-normal-cat")
+        .cookie(cookie.clone())
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, resp.status());
+    assert_eq!(TEST_IMG, &test::read_body(resp).await[..]);
+
+    let req = test::TestRequest::delete()
+        .uri("/remove")
+        .cookie(cookie.clone())
+        .set_json(&TestRm { id: "a-normal-cat" })
+        .to_request();
+    assert_eq!(StatusCode::OK, test::call_service(&mut app, req).await.status());
+    assert!(!data.blobs.inner().contains(&blob));
+}
+
+#[actix_rt::test]
+async fn permission_checks_dont_read_the_image()
+{
+    let base_path = scratch_db("permission_checks_dont_read_the_image");
+    let data = test_data(base_path.clone());
+    let mut app = test_app!(data);
+
+    data.icache.inner().set(String::from("no-data"), Image {
+        public: false,
+        owner: String::from("nutty"),
+        blob: BlobId::of(b"no data"),
+    });
+
+    // Turned away on the strength of the record alone
+    let req = test::TestRequest::get()
+        .uri("/view/no-data")
+        .to_request();
+    assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&mut app, req).await.status());
+
+    // But the owner finds the data missing, as if the image had been
+    // removed since its record was read
+    data.icache.inner().set(String::from("no-data"), Image {
+        public: true,
+        owner: String::from("nutty"),
+        blob: BlobId::of(b"no data"),
+    });
+    let req = test::TestRequest::get()
+        .uri("/view/no-data")
+        .to_request();
+    assert_eq!(StatusCode::NOT_FOUND, test::call_service(&mut app, req).await.status());
+}
+
+#[actix_rt::test]
+async fn images_with_the_same_data_share_it()
+{
+    let base_path = scratch_db("images_with_the_same_data_share_it");
+    let data = test_data(base_path.clone());
+    let mut app = test_app!(data);
+    let cookie = logon!(app);
+
+    assert_eq!(StatusCode::OK, add!(app, cookie, "cat"));
+    assert_eq!(StatusCode::OK, add!(app, cookie, "the-same-cat"));
+
+    let blob = data.icache.inner().get(&String::from("cat")).unwrap().blob;
+    assert_eq!(blob, data.icache.inner().get(&String::from("the-same-cat")).unwrap().blob);
+    assert_eq!(2, data.blobs.inner().refs(&blob).unwrap());
+
+    let req = test::TestRequest::delete()
+        .uri("/remove")
+        .cookie(cookie.clone())
+        .set_json(&TestRm { id: "cat" })
+        .to_request();
+    assert_eq!(StatusCode::OK, test::call_service(&mut app, req).await.status());
+
+    // Still there for the other image
+    let req = test::TestRequest::get()
+        .uri("/view/the-same-cat")
+        .cookie(cookie.clone())
+        .to_request();
+    let resp = test::call_service(&mut app, req).await;
+    assert_eq!(StatusCode::OK, resp.status());
+    assert_eq!(TEST_IMG, &test::read_body(resp).await[..]);
+
+    let req = test::TestRequest::delete()
+        .uri("/remove")
+        .cookie(cookie.clone())
+        .set_json(&TestRm { id: "the-same-cat" })
+        .to_request();
+    assert_eq!(StatusCode::OK, test::call_service(&mut app, req).await.status());
+    assert!(!data.blobs.inner().contains(&blob));
+}
+
+#[test]
+fn garbage_collection_keeps_only_data_in_use()
+{
+    let base_path = scratch_db("garbage_collection_keeps_only_data_in_use");
+    let data = test_data(base_path.clone());
+    let (icache, blobs) = (data.icache.inner(), data.blobs.inner());
+
+    // As if the server stopped between storing the data and the record
+    let kept = blobs.put(TEST_IMG).unwrap();
+    let orphan = blobs.put(b"never recorded").unwrap();
+    icache.set(String::from("a-normal-cat"), Image {
+        public: true,
+        owner: String::from("chipper"),
+        blob: kept,
+    });
+
+    assert_eq!(1, collect_garbage(icache, blobs).unwrap());
+    assert!(blobs.contains(&kept));
+    assert!(!blobs.contains(&orphan));
+
+    // An unreadable record might be using anything
+    let orphan = blobs.put(b"never recorded").unwrap();
+    fs::write(base_path.join("corrupt"), b"\xff\xff\xff\xff\xff\xff\xff\xff").unwrap();
+    assert!(collect_garbage(icache, blobs).is_err());
+    assert!(blobs.contains(&orphan));
+}
+
+#[test]
+fn old_image_records_are_upgraded()
+{
+    let base_path = scratch_db("old_image_records_are_upgraded");
+    let _ = fs::remove_dir_all(blob_path(&base_path));
+    let blobs = Arc::new(BlobStore::new(blob_path(&base_path)).unwrap());
+    let options = DiskCacheOptions { migrations: image_migrations::<Bincode>(blobs.clone()), ..Default::default() };
+
+    // Data inline, as the fixtures were written
+    for name in &["a-normal-cat", "out-on-the-town", "secret-bounty"]
+    {
+        fs::copy(Path::new("live-db").join(name), base_path.join(name)).unwrap();
+    }
+    // Images were never stored apart from their data without a header
+    let headerless = Image { public: false, owner: String::from("nutty"), blob: BlobId::of(TEST_IMG) };
+    fs::write(base_path.join("headerless"), bincode::serialize(&headerless).unwrap()).unwrap();
+
+    assert_eq!((3, 1), database::upgrade_records(&base_path, &options).unwrap());
+    fs::remove_file(base_path.join("headerless")).unwrap();
+    // Already upgraded
+    assert_eq!((0, 0), database::upgrade_records(&base_path, &options).unwrap());
+    assert!(fs::read(base_path.join("a-normal-cat")).unwrap().starts_with(b"IFR\x03\x01\0\0\0\0\0\0\0"));
+
+    let icache = DiskCache::<ImageKey, Image>::with_options(base_path, options).unwrap();
+    let cat = icache.get(&String::from("a-normal-cat")).unwrap();
+    assert!(cat.public);
+    assert_eq!("chipper", cat.owner);
+    assert!(blobs.read(&cat.blob).unwrap().starts_with(b"\xff\xd8"));
+}
+
+#[test]
+fn old_image_records_are_upgraded_as_they_are_read()
+{
+    let base_path = scratch_db("old_image_records_are_upgraded_as_they_are_read");
+    let _ = fs::remove_dir_all(blob_path(&base_path));
+    let blobs = Arc::new(BlobStore::new(blob_path(&base_path)).unwrap());
+    let options = DiskCacheOptions {
+        migrations: image_migrations::<Bincode>(blobs.clone()),
+        ..Default::default()
+    };
+
+    let cat_path = base_path.join("a-normal-cat");
+    fs::copy(Path::new("live-db").join("a-normal-cat"), &cat_path).unwrap();
+
+    let icache = DiskCache::<ImageKey, Image>::with_options(base_path.clone(), options.clone()).unwrap();
+    let cat = icache.get(&String::from("a-normal-cat")).unwrap();
+    assert_eq!("chipper", cat.owner);
+    assert!(blobs.read(&cat.blob).unwrap().starts_with(b"\xff\xd8"));
+
+    // Written back in the current schema
+    assert_eq!(1, icache.persist().unwrap());
+    assert!(fs::read(&cat_path).unwrap().starts_with(b"IFR\x03\x01\0\0\0\0\0\0\0"));
+    drop(icache);
+    let icache = DiskCache::<ImageKey, Image>::with_options(base_path, options).unwrap();
+    assert_eq!(cat.blob, icache.get(&String::from("a-normal-cat")).unwrap().blob);
+    assert_eq!(0, icache.persist().unwrap());
+}